@@ -4,19 +4,24 @@ extern crate error_chain;
 extern crate log;
 extern crate env_logger;
 extern crate clap;
+#[macro_use]
+extern crate serde_json;
 
 extern crate rupert;
 
 use std::env;
+use std::path::PathBuf;
 use std::sync::mpsc::{Receiver, channel, Sender};
 use std::thread;
 
 use log::LogLevelFilter;
 use env_logger::LogBuilder;
 
-use rupert::{BuildStatus, BuildRequest};
+use rupert::{BuildResult, BuildStatus};
+use rupert::history::{BuildRecord, History};
 use rupert::errors::*;
 use rupert::utils::{BuildUpdates, Config, RepoConfig};
+use rupert::utils::git::GitRef;
 
 
 fn run() -> Result<()> {
@@ -24,7 +29,10 @@ fn run() -> Result<()> {
 
     // Load config and program arguments
     let conf = rupert::utils::load_config(None)?;
-    let pargs = parse_args()?;
+    let pargs = match parse_args()? {
+        Action::Build(pargs) => pargs,
+        Action::Serve { listen } => return rupert::server::serve(conf, &listen),
+    };
 
     let key = (pargs.owner.clone(), pargs.reponame.clone());
     let repo_conf: RepoConfig = conf.repos
@@ -35,43 +43,82 @@ fn run() -> Result<()> {
         pargs.reponame.clone(),
     ))?
         .clone();
-    let integration = repo_conf.integration.clone();
-    let api_token = repo_conf.api_token.clone();
-
-    let build_request = rupert::BuildRequest::new(
-        integration,
-        pargs.owner.clone(),
-        pargs.reponame.clone(),
-        pargs.commit.clone(),
-    )?;
 
-    info!("Received a new build-request: \"{:?}\"", build_request);
+    let format = pargs.format;
     let (sender, receiver) = channel();
-    let handle = thread::spawn(move || run_runner(conf, build_request, repo_conf, sender));
+    let handle = thread::spawn(move || run_runner(conf, pargs, repo_conf, sender));
 
-    listen(receiver);
+    listen(receiver, format)?;
 
-    match handle.join() {
+    // Printed once all updates are, after the worker-thread is done
+    let results = match handle.join() {
         Ok(res) => res?,
         Err(_) => bail!("Worker-thread panicked"),
-    }
-    Ok(())
+    };
+    print_results(&key, &results, format)
 }
 
 fn run_runner(
     conf: Config,
-    build_request: BuildRequest,
+    pargs: ProgramArgs,
     repo_conf: RepoConfig,
     sender: Sender<BuildUpdates>,
-) -> Result<()> {
-    let runner = rupert::Runner::new(&conf.meta.build_root, &build_request, Some(sender))
-        .chain_err(|| {
-            format!("Failed checking out code from {:?}", build_request)
-        })?;
-
-    let results = runner.execute(&repo_conf.build_instruction).chain_err(
-        || "Failed execution of build",
-    )?;
+) -> Result<BuildResult> {
+    let history = History::new(&conf.meta.build_root, &pargs.owner, &pargs.reponame);
+    let results = match pargs.source {
+        Source::Remote(ref gitref) => {
+            let build_request = rupert::BuildRequest::resolve(&conf, &repo_conf, gitref)?;
+            info!("Received a new build-request: \"{:?}\"", build_request);
+            let record = history.queue(&build_request)?;
+            follow_build(&conf, &repo_conf, &build_request, record, sender)?
+        }
+        Source::Approved(id) => {
+            let (build_request, record) = history.approve(id)?;
+            info!("Approved build {} of {:?}", id, build_request);
+            follow_build(&conf, &repo_conf, &build_request, record, sender)?
+        }
+        Source::Local {
+            ref path,
+            include_untracked,
+        } => {
+            let mut runner = rupert::Runner::local(
+                &conf.meta.build_root,
+                &repo_conf,
+                path,
+                include_untracked,
+                Some(sender),
+            )?;
+            runner.limit(&repo_conf.limits, conf.meta.cgroup.as_ref());
+            runner.hide(&conf.private_files);
+            if let Some(user) = repo_conf.user(&conf.meta)? {
+                runner.run_as(user);
+            }
+            runner.execute(&repo_conf.build_instruction).chain_err(
+                || "Failed execution of build",
+            )?
+        }
+    };
+
+    Ok(results)
+}
+
+/// Print the summary of the finished build of the repository `key`
+fn print_results(key: &(String, String), results: &BuildResult, format: Format) -> Result<()> {
+    if format != Format::Text {
+        let summary = json!({
+            "owner": key.0,
+            "reponame": key.1,
+            "successful": results.successful(),
+            "result": results,
+        });
+        let summary = if format == Format::Json {
+            serde_json::to_string_pretty(&summary)
+        } else {
+            serde_json::to_string(&summary)
+        }.chain_err(|| "Failed serializing build-result")?;
+        println!("{}", summary);
+        return Ok(());
+    }
     if !results.successful() {
         for (i, step) in results.steps.iter().enumerate() {
             println!("Step {} resulted in {:?}", i, step.status);
@@ -79,77 +126,260 @@ fn run_runner(
             if step.status != BuildStatus::Successful {}
         }
     }
-    println!("Build-result: {:?}", results.successful());
+    println!("Build-result: {}", results.description());
+    println!("Summary written to {:?}", results.reports.markdown);
     Ok(())
 }
 
-fn listen(receiver: Receiver<BuildUpdates>) {
-    loop {
-        match receiver.recv() {
-            Ok(out) => println!("{}", out),
-            Err(_) => break,
+/// Run the queued build `record` of `req`, sending its updates to `sender`
+fn follow_build(
+    conf: &Config,
+    repo_conf: &RepoConfig,
+    req: &rupert::BuildRequest,
+    record: BuildRecord,
+    sender: Sender<BuildUpdates>,
+) -> Result<BuildResult> {
+    // Follow the build once started, the watch ends if it never is
+    let hub = rupert::hub::Hub::new();
+    let started = hub.watch();
+    thread::spawn(move || if let Ok(broadcast) = started.recv() {
+        for update in broadcast.subscribe() {
+            if sender.send(update).is_err() {
+                break;
+            }
+        }
+    });
+    // Statuses of commits are only posted by builds of the server
+    let record = rupert::run_queued_build(conf, repo_conf, req, record, &hub, false)?;
+    match record.result {
+        Some(results) => Ok(results),
+        None => bail!("Failed build of {:?}: {}", req, record.error.unwrap_or_default()),
+    }
+}
+
+fn listen(receiver: Receiver<BuildUpdates>, format: Format) -> Result<()> {
+    while let Ok(out) = receiver.recv() {
+        match format {
+            Format::Text => println!("{}", out),
+            Format::JsonLines => {
+                println!(
+                    "{}",
+                    serde_json::to_string(&out).chain_err(
+                        || "Failed serializing update",
+                    )?
+                )
+            }
+            // Only the final summary is printed
+            Format::Json => {}
         }
     }
+    Ok(())
+}
+
+/// What to build
+enum Source {
+    /// A ref in the remote repository
+    Remote(GitRef),
+    /// A build awaiting approval, by id
+    Approved(u64),
+    /// The working tree of a local repository
+    Local {
+        path: PathBuf,
+        include_untracked: bool,
+    },
+}
+
+/// How results and updates are printed
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Format {
+    /// Colored text for humans
+    Text,
+    /// A single JSON summary once the build is finished
+    Json,
+    /// Every update as a line of JSON followed by a summary-line
+    JsonLines,
+}
+
+/// What the program was asked to do
+enum Action {
+    /// Build once and print the result
+    Build(ProgramArgs),
+    /// Run as a server until stopped
+    Serve { listen: String },
 }
 
 struct ProgramArgs {
     owner: String,
     reponame: String,
-    commit: String,
+    source: Source,
+    format: Format,
+}
+
+fn repo_args<'a, 'b>() -> Vec<clap::Arg<'a, 'b>> {
+    vec![
+        clap::Arg::with_name("owner")
+            .short("o")
+            .long("owner")
+            .value_name("OWNER")
+            .help("Specify owner of repository")
+            .required(true)
+            .takes_value(true),
+        clap::Arg::with_name("repo")
+            .short("r")
+            .long("repo")
+            .value_name("REPO")
+            .help("Name of repository")
+            .required(true)
+            .takes_value(true),
+    ]
 }
 
-fn parse_args() -> Result<ProgramArgs> {
+fn parse_args() -> Result<Action> {
     let matches = clap::App::new("rupert-cli")
         .version("0.1")
         .about("CLI for rupert build-server")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
         .arg(
-            clap::Arg::with_name("owner")
-                .short("o")
-                .long("owner")
-                .value_name("OWNER")
-                .help("Specify owner of repository")
-                .required(true)
+            clap::Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Output format, text, json (summary only) or jsonl (updates and summary)")
+                .possible_values(&["text", "json", "jsonl"])
+                .default_value("text")
+                .global(true)
                 .takes_value(true),
         )
-        .arg(
-            clap::Arg::with_name("repo")
-                .short("r")
-                .long("repo")
-                .value_name("REPO")
-                .help("Name of repository")
-                .required(true)
-                .takes_value(true),
+        .subcommand(
+            clap::SubCommand::with_name("build")
+                .about("Build a commit, branch or tag of a configured repository")
+                .args(&repo_args())
+                .arg(
+                    clap::Arg::with_name("commit")
+                        .short("c")
+                        .long("commit")
+                        .value_name("COMMIT")
+                        .help("Which commit to build, full or abbreviated")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("branch")
+                        .short("b")
+                        .long("branch")
+                        .value_name("BRANCH")
+                        .help("Build the latest commit on branch")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("tag")
+                        .short("t")
+                        .long("tag")
+                        .value_name("TAG")
+                        .help("Build the commit pointed to by tag")
+                        .takes_value(true),
+                )
+                .group(
+                    clap::ArgGroup::with_name("ref")
+                        .args(&["commit", "branch", "tag"])
+                        .required(true),
+                ),
         )
-        .arg(
-            clap::Arg::with_name("commit")
-                .short("c")
-                .long("commit")
-                .value_name("COMMIT")
-                .help("Which commit to build")
-                .required(true)
-                .takes_value(true),
+        .subcommand(
+            clap::SubCommand::with_name("run")
+                .about(
+                    "Run the build-steps of a configured repository on a local working tree",
+                )
+                .args(&repo_args())
+                .arg(
+                    clap::Arg::with_name("local")
+                        .short("l")
+                        .long("local")
+                        .value_name("PATH")
+                        .help("Build the working tree at PATH, defaults to current directory")
+                        .required(true)
+                        .takes_value(true)
+                        .min_values(0),
+                )
+                .arg(
+                    clap::Arg::with_name("untracked")
+                        .short("u")
+                        .long("untracked")
+                        .help("Include untracked files which are not ignored"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("approve")
+                .about("Approve and run a build awaiting approval")
+                .args(&repo_args())
+                .arg(
+                    clap::Arg::with_name("id")
+                        .short("i")
+                        .long("id")
+                        .value_name("ID")
+                        .help("Id of the build, numbered per repository")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("serve")
+                .about("Receive webhooks, run the queued builds and serve the dashboard")
+                .arg(
+                    clap::Arg::with_name("listen")
+                        .long("listen")
+                        .value_name("ADDR")
+                        .help("Address to listen on")
+                        .default_value("127.0.0.1:8080")
+                        .takes_value(true),
+                ),
         )
         .get_matches();
 
+    let (subcommand, matches) = match matches.subcommand() {
+        (name, Some(matches)) => (name, matches),
+        _ => bail!("No subcommand given"),
+    };
+    if subcommand == "serve" {
+        let listen = matches.value_of("listen").unwrap_or("127.0.0.1:8080");
+        return Ok(Action::Serve { listen: listen.to_owned() });
+    }
     let get_arg = |arg: &str| {
         let res: Result<String> = Ok(
             matches
                 .value_of_lossy(arg)
                 .ok_or(format!("\"{}\" argument missing", arg))?
-                .to_owned()
-                .into_owned()
-                .to_lowercase(),
+                .into_owned(),
         );
         res
     };
-    let owner = get_arg("owner")?;
-    let reponame = get_arg("repo")?;
-    let commit = get_arg("commit")?;
-    Ok(ProgramArgs {
+    let owner = get_arg("owner")?.to_lowercase();
+    let reponame = get_arg("repo")?.to_lowercase();
+    let source = match subcommand {
+        "run" => {
+            Source::Local {
+                path: matches.value_of_os("local").unwrap_or(".".as_ref()).into(),
+                include_untracked: matches.is_present("untracked"),
+            }
+        }
+        "approve" => {
+            Source::Approved(get_arg("id")?.parse().chain_err(|| "Invalid build-id")?)
+        }
+        // Refs are case-sensitive, only owner and repo are normalized
+        _ if matches.is_present("branch") => Source::Remote(GitRef::Branch(get_arg("branch")?)),
+        _ if matches.is_present("tag") => Source::Remote(GitRef::Tag(get_arg("tag")?)),
+        _ => Source::Remote(GitRef::Commit(get_arg("commit")?)),
+    };
+    let format = match matches.value_of("format") {
+        Some("json") => Format::Json,
+        Some("jsonl") => Format::JsonLines,
+        _ => Format::Text,
+    };
+    Ok(Action::Build(ProgramArgs {
         owner,
         reponame,
-        commit,
-    })
+        source,
+        format,
+    }))
 }
 
 fn init_logger() -> Result<()> {
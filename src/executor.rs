@@ -0,0 +1,413 @@
+//! Executors running build-steps, directly on the host or isolated from it
+//!
+//! Every repository selects its executor by `executor.type` in its configuration:
+//!
+//! * `host` runs steps as child-processes of rupert, as they always did
+//! * `sandbox` runs steps with bubblewrap in unprivileged namespaces, seeing the host's
+//!   root read-only with only the build-, cache- and artifact-dirs writable and rupert's
+//!   configuration and secrets hidden
+//! * `container` runs steps in an image through an OCI-runtime's CLI, podman or docker
+//!
+//! Steps see their directories at the same paths with every executor. They do not inherit
+//! rupert's environment, only the variables in `INHERITED_ENV` and those of the step.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+
+use limits::Limits;
+use users::{self, User};
+
+/// Variables of rupert's environment passed on to steps, along with `LC_*`. `HOME` is
+/// not, steps get one of their own from the runner.
+const INHERITED_ENV: &[&str] = &[
+    "PATH",
+    "USER",
+    "LOGNAME",
+    "LANG",
+    "LANGUAGE",
+    "TERM",
+    "TZ",
+    "TMPDIR",
+];
+
+/// Runs the commands of build-steps
+pub trait Executor: Send + Sync {
+    /// Command running `step`, to be spawned by the runner
+    fn command(&self, step: &Step) -> Command;
+
+    /// Stop `step` after its command was killed, for what outlives it
+    fn kill(&self, _step: &Step) {}
+
+    /// Whether steps are put into a cgroup of their own for their `memory` and `cpus`,
+    /// rather than limited by the executor
+    fn cgroup(&self) -> bool {
+        true
+    }
+}
+
+/// A build-step to be run by an `Executor`
+#[derive(Debug, Clone)]
+pub struct Step {
+    /// Unique among the running steps of this rupert
+    pub id: String,
+    /// Shell-command of the step, run by bash
+    pub cmd: String,
+    pub path_build: PathBuf,
+    pub path_cache: PathBuf,
+    pub path_artifacts: PathBuf,
+    /// Mirror the build-dir is a worktree of, read by git in the build-dir
+    pub path_mirror: Option<PathBuf>,
+    /// Environment of the step, on top of `PATH_BUILD`, `PATH_CACHE` and
+    /// `PATH_ARTIFACTS`
+    pub env: BTreeMap<String, String>,
+    pub limits: Limits,
+    /// Cgroup the step is run in
+    pub cgroup: Option<PathBuf>,
+    /// User the step runs as, rather than as rupert
+    pub user: Option<User>,
+    /// Files of the host the step must not read, hidden where the executor can
+    pub hidden: Vec<PathBuf>,
+}
+
+impl Step {
+    /// Environment of the step, with the paths of its directories
+    fn env(&self) -> BTreeMap<String, OsString> {
+        let mut env: BTreeMap<_, _> = self.env
+            .iter()
+            .map(|(k, v)| (k.clone(), OsString::from(v)))
+            .collect();
+        env.insert("PATH_BUILD".into(), self.path_build.clone().into());
+        env.insert("PATH_CACHE".into(), self.path_cache.clone().into());
+        env.insert("PATH_ARTIFACTS".into(), self.path_artifacts.clone().into());
+        env
+    }
+
+    /// Environment of the step along with the variables it inherits from rupert
+    fn full_env(&self) -> BTreeMap<String, OsString> {
+        let mut env: BTreeMap<_, _> = env::vars_os()
+            .filter_map(|(k, v)| k.into_string().ok().map(|k| (k, v)))
+            .filter(|(k, _)| {
+                INHERITED_ENV.contains(&k.as_str()) || k.starts_with("LC_")
+            })
+            // Those of rupert's user are wrong for another
+            .filter(|(k, _)| self.user.is_none() || k != "USER" && k != "LOGNAME")
+            .collect();
+        env.extend(self.env());
+        env
+    }
+
+    /// Directories written by the step
+    fn writable(&self) -> Vec<&PathBuf> {
+        vec![&self.path_build, &self.path_cache, &self.path_artifacts]
+    }
+
+    /// Line-buffered bash running `cmd`
+    fn shell(&self) -> Vec<OsString> {
+        ["stdbuf", "-oL", "bash", "-c", &self.cmd]
+            .iter()
+            .map(OsString::from)
+            .collect()
+    }
+}
+
+/// Executor of a repository, as configured
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ExecutorConfig {
+    #[default]
+    Host,
+    Sandbox {
+        /// Whether steps can reach the network
+        #[serde(default = "default_network")]
+        network: bool,
+        /// Bubblewrap-binary
+        #[serde(default = "default_bwrap")]
+        bwrap: String,
+    },
+    Container {
+        /// Image steps are run in
+        image: String,
+        /// CLI of the OCI-runtime, `podman` or `docker`
+        #[serde(default = "default_runtime")]
+        runtime: String,
+        /// Whether steps can reach the network
+        #[serde(default = "default_network")]
+        network: bool,
+        /// Further options of `run`, like `--userns=keep-id` or `--memory=2g`
+        #[serde(default)]
+        options: Vec<String>,
+    },
+}
+
+fn default_network() -> bool {
+    true
+}
+
+fn default_bwrap() -> String {
+    "bwrap".into()
+}
+
+fn default_runtime() -> String {
+    "podman".into()
+}
+
+impl ExecutorConfig {
+    /// The configured executor
+    pub fn executor(&self) -> Arc<dyn Executor> {
+        match *self {
+            ExecutorConfig::Host => Arc::new(Host),
+            ExecutorConfig::Sandbox {
+                network,
+                ref bwrap,
+            } => Arc::new(Sandbox {
+                network,
+                bwrap: bwrap.clone(),
+            }),
+            ExecutorConfig::Container {
+                ref image,
+                ref runtime,
+                network,
+                ref options,
+            } => Arc::new(Container {
+                image: image.clone(),
+                runtime: runtime.clone(),
+                network,
+                options: options.clone(),
+            }),
+        }
+    }
+}
+
+/// Runs steps as child-processes of rupert
+pub struct Host;
+
+impl Executor for Host {
+    fn command(&self, step: &Step) -> Command {
+        let shell = step.shell();
+        let mut command = Command::new(&shell[0]);
+        command
+            .args(&shell[1..])
+            .current_dir(&step.path_build)
+            .env_clear()
+            .envs(step.full_env());
+        step.limits.confine(&mut command, step.cgroup.as_ref());
+        if let Some(ref user) = step.user {
+            users::run_as(&mut command, user);
+        }
+        command
+    }
+}
+
+/// Runs steps with bubblewrap, on a read-only view of the host
+pub struct Sandbox {
+    network: bool,
+    bwrap: String,
+}
+
+impl Executor for Sandbox {
+    fn command(&self, step: &Step) -> Command {
+        let mut command = Command::new(&self.bwrap);
+        command.args(["--ro-bind", "/", "/"]).args([
+            "--dev",
+            "/dev",
+            "--proc",
+            "/proc",
+            "--tmpfs",
+            "/tmp",
+            "--unshare-pid",
+            "--unshare-ipc",
+            "--die-with-parent",
+        ]);
+        if !self.network {
+            command.arg("--unshare-net");
+        }
+        for path in step.writable() {
+            command.arg("--bind").arg(path).arg(path);
+        }
+        // Readable through the root otherwise, whatever the user of the step
+        for path in &step.hidden {
+            command.arg("--ro-bind").arg("/dev/null").arg(path);
+        }
+        command
+            .arg("--chdir")
+            .arg(&step.path_build)
+            .arg("--")
+            .args(step.shell())
+            .env_clear()
+            .envs(step.full_env());
+        step.limits.confine(&mut command, step.cgroup.as_ref());
+        if let Some(ref user) = step.user {
+            users::run_as(&mut command, user);
+        }
+        command
+    }
+}
+
+/// Runs steps in containers through the CLI of an OCI-runtime
+pub struct Container {
+    image: String,
+    runtime: String,
+    network: bool,
+    options: Vec<String>,
+}
+
+impl Executor for Container {
+    fn command(&self, step: &Step) -> Command {
+        let mut command = Command::new(&self.runtime);
+        command
+            .args(["run", "--rm", "--init", "--name", &step.id])
+            .arg("--workdir")
+            .arg(&step.path_build);
+        if !self.network {
+            command.arg("--network=none");
+        }
+        if let Some(ref user) = step.user {
+            command.arg(format!("--user={}:{}", user.uid, user.gid));
+        }
+        for path in step.writable() {
+            command.arg("--volume").arg(volume(path, ""));
+        }
+        if let Some(ref path) = step.path_mirror {
+            command.arg("--volume").arg(volume(path, ":ro"));
+        }
+        // Only the names are given, values are taken from the runtime's environment
+        let env = step.env();
+        for name in env.keys() {
+            command.args(["--env", name]);
+        }
+        command
+            .args(step.limits.container_args())
+            .args(&self.options)
+            .arg(&self.image)
+            .args(step.shell())
+            .envs(env);
+        command
+    }
+
+    fn kill(&self, step: &Step) {
+        let res = Command::new(&self.runtime)
+            .args(["kill", &step.id])
+            .output();
+        match res {
+            Ok(ref output) if output.status.success() => {}
+            Ok(output) => {
+                warn!(
+                    "Failed killing container {}: {}",
+                    step.id,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )
+            }
+            Err(e) => warn!("Failed killing container {}: {}", step.id, e),
+        }
+    }
+
+    fn cgroup(&self) -> bool {
+        false
+    }
+}
+
+/// `--volume` mounting `path` at the same path
+fn volume(path: &PathBuf, options: &str) -> OsString {
+    let mut volume = path.clone().into_os_string();
+    volume.push(":");
+    volume.push(path);
+    volume.push(options);
+    volume
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::BTreeMap;
+    use std::ffi::OsStr;
+    use std::path::PathBuf;
+
+    use toml;
+
+    use executor::{ExecutorConfig, Step};
+    use limits::{Limits, Size};
+
+    fn step() -> Step {
+        let mut env = BTreeMap::new();
+        env.insert("TOKEN".into(), "hunter2".into());
+        Step {
+            id: "rupert-1-0".into(),
+            cmd: "make test".into(),
+            path_build: PathBuf::from("/r/builds/common"),
+            path_cache: PathBuf::from("/r/cache"),
+            path_artifacts: PathBuf::from("/r/artifacts/abc"),
+            path_mirror: Some(PathBuf::from("/r/mirror")),
+            env,
+            limits: Limits {
+                open_files: Some(64),
+                memory: Some(Size(1 << 30)),
+                ..Default::default()
+            },
+            cgroup: None,
+            user: None,
+            hidden: vec!["/etc/rupert/rupert-conf.toml".into()],
+        }
+    }
+
+    fn args(conf: &str) -> Vec<String> {
+        #[derive(Deserialize)]
+        struct Conf {
+            #[serde(default)]
+            executor: ExecutorConfig,
+        }
+        let conf: Conf = toml::from_str(conf).unwrap();
+        let command = conf.executor.executor().command(&step());
+        let mut args = vec![command.get_program()];
+        args.extend(command.get_args());
+        args.iter().map(|a| a.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn test_executor_commands() {
+        let shell = ["stdbuf", "-oL", "bash", "-c", "make test"];
+        assert_eq!(args(""), shell);
+
+        let sandbox = args("executor = { type = \"sandbox\", network = false }");
+        assert_eq!(sandbox[0], "bwrap");
+        assert!(sandbox.windows(3).any(|a| a == ["--ro-bind", "/", "/"]));
+        assert!(sandbox.windows(3).any(|a| {
+            a == ["--bind", "/r/builds/common", "/r/builds/common"]
+        }));
+        assert!(sandbox.windows(3).any(|a| {
+            a == ["--ro-bind", "/dev/null", "/etc/rupert/rupert-conf.toml"]
+        }));
+        assert!(sandbox.contains(&"--unshare-net".into()));
+        assert!(sandbox[sandbox.len() - shell.len()..] == shell);
+
+        let container = args(
+            "[executor]\ntype = \"container\"\nruntime = \"docker\"\nimage = \"rust:1\"",
+        );
+        assert_eq!(container[..3], ["docker", "run", "--rm"]);
+        assert!(container.windows(2).any(|a| a == ["--name", "rupert-1-0"]));
+        assert!(container.windows(2).any(|a| {
+            a == ["--volume", "/r/mirror:/r/mirror:ro"]
+        }));
+        // Secrets are not on the command-line
+        assert!(container.windows(2).any(|a| a == ["--env", "TOKEN"]));
+        assert!(!container.iter().any(|a| a.contains("hunter2")));
+        assert!(!container.contains(&"--network=none".into()));
+        assert!(container.contains(&"--ulimit=nofile=64:64".into()));
+        assert!(container.contains(&"--memory=1073741824".into()));
+        assert!(container[container.len() - shell.len()..] == shell);
+        assert!(container[container.len() - shell.len() - 1] == "rust:1");
+
+        let command = ExecutorConfig::Host.executor().command(&step());
+        assert!(command.get_envs().any(|(k, v)| {
+            k == OsStr::new("PATH_CACHE") && v == Some(OsStr::new("/r/cache"))
+        }));
+        // Only allowed variables of rupert's environment are passed on
+        assert!(command.get_envs().any(|(k, _)| k == OsStr::new("PATH")));
+        assert!(!command.get_envs().any(|(k, _)| {
+            k == OsStr::new("CARGO_MANIFEST_DIR") || k == OsStr::new("HOME")
+        }));
+    }
+}
@@ -0,0 +1,336 @@
+//! Build-history of each repository
+//!
+//! Every build is kept as a JSON-file, `<build_root>/<owner>/<repo>/history/<id>.json`,
+//! with ids increasing for each new build of the repository. Its log, the updates as
+//! rendered in a terminal, is kept next to it in `<id>.log`. Builds awaiting approval
+//! keep their request in `<id>.request.json` until approved.
+
+use std::fs::{File, OpenOptions, create_dir_all, read_dir, remove_file, rename};
+use std::io::{ErrorKind as IoErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json;
+
+use errors::*;
+use utils;
+use {BuildRequest, BuildResult, BuildStatus, Runner};
+
+/// Author of the commit being built
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Author {
+    pub name: String,
+    pub email: String,
+}
+
+/// A build, finished or in progress, of a repository
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BuildRecord {
+    pub id: u64,
+    pub owner: String,
+    pub reponame: String,
+    pub commit: String,
+    pub branch: Option<String>,
+    /// Id of the pull-request the build is for, if any
+    #[serde(default)]
+    pub pull_request: Option<u64>,
+    pub author: Option<Author>,
+    pub status: BuildStatus,
+    /// Seconds since the unix epoch
+    pub started: u64,
+    pub finished: Option<u64>,
+    pub result: Option<BuildResult>,
+    /// Why the build could not be executed, e.g. a failed checkout
+    pub error: Option<String>,
+}
+
+impl BuildRecord {
+    /// Record the outcome of executing the build
+    pub fn finish(&mut self, result: Result<BuildResult>) {
+        self.finished = Some(utils::timestamp());
+        match result {
+            Ok(result) => {
+                self.status = if result.successful() {
+                    BuildStatus::Successful
+                } else {
+                    result
+                        .steps
+                        .iter()
+                        .map(|step| step.status.clone())
+                        .find(|status| *status != BuildStatus::Successful)
+                        .unwrap_or(BuildStatus::Failed)
+                };
+                self.result = Some(result);
+            }
+            Err(e) => self.fail(&e),
+        }
+    }
+
+    /// Record the build as failed with `e`, without a result
+    pub fn fail(&mut self, e: &Error) {
+        self.finished = Some(utils::timestamp());
+        self.status = BuildStatus::Failed;
+        let causes: Vec<String> = e.iter().map(|e| e.to_string()).collect();
+        self.error = Some(causes.join(": "));
+    }
+
+    pub fn short_commit(&self) -> &str {
+        &self.commit[..self.commit.len().min(7)]
+    }
+
+    /// Where the build is shown on the dashboard served at `public_url`
+    pub fn url(&self, public_url: &str) -> String {
+        format!(
+            "{}/repos/{}/{}/builds/{}",
+            public_url.trim_end_matches('/'),
+            self.owner,
+            self.reponame,
+            self.id
+        )
+    }
+
+    /// Short description of the outcome, e.g. for build-statuses
+    pub fn description(&self) -> String {
+        match (self.result.as_ref(), self.error.as_ref()) {
+            (Some(result), _) => result.description(),
+            (_, Some(error)) => format!("Error: {}", error),
+            _ => format!("{:?}", self.status),
+        }
+    }
+}
+
+/// The build-history of a single repository
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new(rupert_root: &Path, owner: &str, reponame: &str) -> History {
+        History { path: Runner::subdir(&Runner::path_root(rupert_root, owner, reponame), "history") }
+    }
+
+    /// Record a new build of `req` as in progress
+    pub fn start(&self, req: &BuildRequest) -> Result<BuildRecord> {
+        self.create(req, BuildStatus::InProgress)
+    }
+
+    /// Record a new build of `req` waiting for its turn
+    pub fn queue(&self, req: &BuildRequest) -> Result<BuildRecord> {
+        self.create(req, BuildStatus::Queued)
+    }
+
+    /// Record a new build of `req` awaiting approval, keeping `req` for `approve`
+    pub fn hold(&self, req: &BuildRequest) -> Result<BuildRecord> {
+        let record = self.create(req, BuildStatus::AwaitingApproval)?;
+        let path = self.path_request(record.id);
+        let json = serde_json::to_string(req).chain_err(
+            || "Failed serializing build-request",
+        )?;
+        File::create(&path)
+            .and_then(|mut f| f.write_all(json.as_bytes()))
+            .chain_err(|| format!("Failed writing {:?}", path))?;
+        Ok(record)
+    }
+
+    /// Queue build `id` awaiting approval, returning its request and record
+    pub fn approve(&self, id: u64) -> Result<(BuildRequest, BuildRecord)> {
+        let mut record = self.get(id)?;
+        if record.status != BuildStatus::AwaitingApproval {
+            bail!("Build {} is not awaiting approval but {:?}", id, record.status);
+        }
+        let path = self.path_request(id);
+        let mut contents = String::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .chain_err(|| format!("Failed reading build-request {:?}", path))?;
+        let req = serde_json::from_str(&contents).chain_err(|| {
+            format!("Bad format of build-request {:?}", path)
+        })?;
+        record.status = BuildStatus::Queued;
+        self.save(&record)?;
+        remove_file(&path).chain_err(|| format!("Failed removing {:?}", path))?;
+        Ok((req, record))
+    }
+
+    fn create(&self, req: &BuildRequest, status: BuildStatus) -> Result<BuildRecord> {
+        create_dir_all(&self.path).chain_err(|| {
+            format!("Failed creating history-dir {:?}", self.path)
+        })?;
+        let mut id = self.ids()?.into_iter().max().unwrap_or(0) + 1;
+        // Claim the id by creating its file, someone else may be starting a build too
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(
+                self.path_record(id),
+            ) {
+                Ok(_) => break,
+                Err(ref e) if e.kind() == IoErrorKind::AlreadyExists => id += 1,
+                Err(e) => return Err(e).chain_err(|| "Failed creating build-record"),
+            }
+        }
+        let record = BuildRecord {
+            id,
+            owner: req.owner.clone(),
+            reponame: req.reponame.clone(),
+            commit: req.commit.clone(),
+            branch: req.branch.clone(),
+            pull_request: req.pull_request.as_ref().map(|pr| pr.id),
+            author: None,
+            status,
+            started: utils::timestamp(),
+            finished: None,
+            result: None,
+            error: None,
+        };
+        self.save(&record)?;
+        Ok(record)
+    }
+
+    pub fn save(&self, record: &BuildRecord) -> Result<()> {
+        let path = self.path_record(record.id);
+        let tmp = path.with_extension("json.tmp");
+        let json = serde_json::to_string(record).chain_err(
+            || "Failed serializing build-record",
+        )?;
+        File::create(&tmp)
+            .and_then(|mut f| f.write_all(json.as_bytes()))
+            .chain_err(|| format!("Failed writing {:?}", tmp))?;
+        rename(&tmp, &path).chain_err(|| format!("Failed writing {:?}", path))
+    }
+
+    pub fn get(&self, id: u64) -> Result<BuildRecord> {
+        let path = self.path_record(id);
+        let mut contents = String::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .chain_err(|| format!("Failed reading build-record {:?}", path))?;
+        serde_json::from_str(&contents).chain_err(|| {
+            ErrorKind::ParseError(format!("Bad build-record {:?}", path))
+        })
+    }
+
+    /// All builds, newest first
+    pub fn list(&self) -> Result<Vec<BuildRecord>> {
+        let mut ids = self.ids()?;
+        ids.sort_by(|a, b| b.cmp(a));
+        let mut records = Vec::new();
+        for id in ids {
+            match self.get(id) {
+                Ok(record) => records.push(record),
+                // Claimed but not yet written
+                Err(e) => debug!("Skipping build {}: {}", id, e),
+            }
+        }
+        Ok(records)
+    }
+
+    /// The latest finished build of the same branch, and pull-request, before `record`.
+    /// Builds of a commit or tag without a branch have no previous build.
+    pub fn previous(&self, record: &BuildRecord) -> Result<Option<BuildRecord>> {
+        if record.branch.is_none() {
+            return Ok(None);
+        }
+        Ok(self.list()?.into_iter().find(|r| {
+            r.id < record.id && r.branch == record.branch &&
+                r.pull_request == record.pull_request && r.finished.is_some()
+        }))
+    }
+
+    fn ids(&self) -> Result<Vec<u64>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let mut ids = Vec::new();
+        for entry in read_dir(&self.path).chain_err(|| {
+            format!("Failed read_dir of {:?}", self.path)
+        })?
+        {
+            let path = entry.chain_err(|| "Failed reading entry")?.path();
+            if path.extension().map(|e| e == "json").unwrap_or(false) {
+                if let Some(id) = path.file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                {
+                    ids.push(id);
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Where the rendered updates of build `id` are logged
+    pub fn path_log(&self, id: u64) -> PathBuf {
+        self.path.join(format!("{}.log", id))
+    }
+
+    fn path_record(&self, id: u64) -> PathBuf {
+        self.path.join(format!("{}.json", id))
+    }
+
+    fn path_request(&self, id: u64) -> PathBuf {
+        self.path.join(format!("{}.request.json", id))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::remove_dir_all;
+
+    use {BuildRequest, BuildStatus};
+    use history::History;
+    use integrations::Integrations;
+    use utils::tests::TEST_DIR;
+
+    fn request(branch: &str) -> BuildRequest {
+        BuildRequest::new(
+            Integrations::Bitbucket,
+            "purew".into(),
+            "foobar".into(),
+            "709d658dc5b6d6afcd46049c2f332ee3f515a67d".into(),
+            Some(branch.into()),
+        ).unwrap()
+    }
+
+    #[test]
+    fn test_history() {
+        let root = TEST_DIR.join("test_history");
+        let _ = remove_dir_all(&root);
+        let history = History::new(&root, "purew", "foobar");
+
+        let mut first = history.start(&request("main")).unwrap();
+        first.finish(Err("checkout failed".into()));
+        history.save(&first).unwrap();
+        let other = history.start(&request("feature")).unwrap();
+        let unfinished = history.start(&request("main")).unwrap();
+        let latest = history.start(&request("main")).unwrap();
+        assert_eq!((first.id, other.id, latest.id), (1, 2, 4));
+
+        let ids: Vec<u64> = history.list().unwrap().iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![4, 3, 2, 1]);
+        assert_eq!(history.get(3).unwrap().status, BuildStatus::InProgress);
+        assert!(history.previous(&unfinished).unwrap().is_some());
+
+        let previous = history.previous(&latest).unwrap().unwrap();
+        assert_eq!(previous.id, 1);
+        assert_eq!(previous.status, BuildStatus::Failed);
+        assert_eq!(previous.description(), "Error: checkout failed");
+        assert!(history.previous(&other).unwrap().is_none());
+
+        let mut tag = request("main");
+        tag.branch = None;
+        let mut tag_record = history.start(&tag).unwrap();
+        tag_record.finish(Err("tag failed".into()));
+        history.save(&tag_record).unwrap();
+        let commit = history.start(&tag).unwrap();
+        assert!(history.previous(&commit).unwrap().is_none());
+
+        let held = history.hold(&request("feature")).unwrap();
+        assert_eq!(held.status, BuildStatus::AwaitingApproval);
+        assert!(history.approve(latest.id).is_err());
+        let (req, approved) = history.approve(held.id).unwrap();
+        assert_eq!(req.branch, Some("feature".into()));
+        assert_eq!(history.get(held.id).unwrap().status, BuildStatus::Queued);
+        assert_eq!(approved.status, BuildStatus::Queued);
+        assert!(history.approve(held.id).is_err());
+    }
+}
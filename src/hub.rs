@@ -0,0 +1,211 @@
+//! Fan-out of build-updates to any number of subscribers
+//!
+//! Each build has a `Broadcast` keeping the last `BACKLOG` updates published so far, a
+//! subscriber joining late first receives that backlog and then continues live. The
+//! complete updates are in the build-log. Queued and running
+//! builds are registered in the `Hub` so they can be found, and cancelled, by their id.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel};
+
+use history::BuildRecord;
+use utils::BuildUpdates;
+
+/// How many updates are kept for late subscribers
+pub const BACKLOG: usize = 10_000;
+
+/// Identifies a build, `(owner, reponame, id)`
+pub type BuildKey = (String, String, u64);
+
+/// Updates of a single build
+#[derive(Default)]
+pub struct Broadcast {
+    inner: Mutex<BroadcastInner>,
+}
+
+#[derive(Default)]
+struct BroadcastInner {
+    backlog: VecDeque<BuildUpdates>,
+    /// Updates published before the first of the backlog
+    dropped: usize,
+    subscribers: Vec<Sender<BuildUpdates>>,
+    closed: bool,
+}
+
+impl Broadcast {
+    pub fn new() -> Broadcast {
+        Broadcast::default()
+    }
+
+    /// Receive every update of the build, starting with the backlog of those already
+    /// published.
+    ///
+    /// The receiver is disconnected once the broadcast is closed.
+    pub fn subscribe(&self) -> Receiver<BuildUpdates> {
+        self.subscribe_indexed().1
+    }
+
+    /// Like `subscribe`, along with the index of the first update received
+    pub fn subscribe_indexed(&self) -> (usize, Receiver<BuildUpdates>) {
+        let (tx, rx) = channel();
+        let mut inner = self.inner.lock().expect("Broadcast poisoned");
+        for update in &inner.backlog {
+            let _ = tx.send(update.clone());
+        }
+        if !inner.closed {
+            inner.subscribers.push(tx);
+        }
+        (inner.dropped, rx)
+    }
+
+    /// Send `update` to all subscribers, forgetting those who hung up
+    pub fn publish(&self, update: BuildUpdates) {
+        let mut inner = self.inner.lock().expect("Broadcast poisoned");
+        inner.subscribers.retain(|tx| tx.send(update.clone()).is_ok());
+        if inner.backlog.len() == BACKLOG {
+            inner.backlog.pop_front();
+            inner.dropped += 1;
+        }
+        inner.backlog.push_back(update);
+    }
+
+    /// No more updates will be published, disconnects all subscribers
+    pub fn close(&self) {
+        let mut inner = self.inner.lock().expect("Broadcast poisoned");
+        inner.closed = true;
+        inner.subscribers.clear();
+    }
+}
+
+/// A queued or running build
+#[derive(Clone)]
+pub struct LiveBuild {
+    pub broadcast: Arc<Broadcast>,
+    /// Set when the build should stop as soon as possible
+    pub cancelled: Arc<AtomicBool>,
+}
+
+/// Registry of queued and running builds
+#[derive(Default)]
+pub struct Hub {
+    inner: Mutex<HubInner>,
+}
+
+#[derive(Default)]
+struct HubInner {
+    live: HashMap<BuildKey, LiveBuild>,
+    watchers: Vec<Sender<Arc<Broadcast>>>,
+}
+
+impl Hub {
+    pub fn new() -> Hub {
+        Hub::default()
+    }
+
+    /// Register the build of `record`, if not already registered
+    pub fn register(&self, record: &BuildRecord) -> LiveBuild {
+        let mut inner = self.inner.lock().expect("Hub poisoned");
+        if let Some(build) = inner.live.get(&key(record)) {
+            return build.clone();
+        }
+        let build = LiveBuild {
+            broadcast: Arc::new(Broadcast::new()),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        };
+        let broadcast = build.broadcast.clone();
+        inner.watchers.retain(|tx| tx.send(broadcast.clone()).is_ok());
+        inner.live.insert(key(record), build.clone());
+        build
+    }
+
+    /// Forget the build of `record`, closing its broadcast
+    pub fn finish(&self, record: &BuildRecord) {
+        let mut inner = self.inner.lock().expect("Hub poisoned");
+        if let Some(build) = inner.live.remove(&key(record)) {
+            build.broadcast.close();
+        }
+    }
+
+    /// A queued or running build
+    pub fn get(&self, owner: &str, reponame: &str, id: u64) -> Option<LiveBuild> {
+        let inner = self.inner.lock().expect("Hub poisoned");
+        inner
+            .live
+            .get(&(owner.to_owned(), reponame.to_owned(), id))
+            .cloned()
+    }
+
+    /// Ask a queued or running build to stop, false if it is not live
+    pub fn cancel(&self, owner: &str, reponame: &str, id: u64) -> bool {
+        match self.get(owner, reponame, id) {
+            Some(build) => {
+                build.cancelled.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Receive the broadcast of every build registered from now on.
+    ///
+    /// The receiver is disconnected when the hub is dropped.
+    pub fn watch(&self) -> Receiver<Arc<Broadcast>> {
+        let (tx, rx) = channel();
+        self.inner.lock().expect("Hub poisoned").watchers.push(tx);
+        rx
+    }
+}
+
+fn key(record: &BuildRecord) -> BuildKey {
+    (record.owner.clone(), record.reponame.clone(), record.id)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use hub::{BACKLOG, Broadcast};
+    use utils::BuildUpdates;
+
+    #[test]
+    fn test_late_subscriber() {
+        let broadcast = Broadcast::new();
+        let early = broadcast.subscribe();
+        broadcast.publish(BuildUpdates::Started);
+        broadcast.publish(BuildUpdates::StepStarted("make".into()));
+
+        let late = broadcast.subscribe();
+        broadcast.publish(BuildUpdates::Finished);
+        broadcast.close();
+
+        let types = |rx: ::std::sync::mpsc::Receiver<BuildUpdates>| -> Vec<String> {
+            rx.iter()
+                .map(|u| match u {
+                    BuildUpdates::Started => "started",
+                    BuildUpdates::StepStarted(_) => "step",
+                    BuildUpdates::Finished => "finished",
+                    _ => "other",
+                })
+                .map(String::from)
+                .collect()
+        };
+        assert_eq!(types(early), vec!["started", "step", "finished"]);
+        assert_eq!(types(late), vec!["started", "step", "finished"]);
+        assert_eq!(types(broadcast.subscribe()), vec!["started", "step", "finished"]);
+    }
+
+    #[test]
+    fn test_backlog_capped() {
+        let broadcast = Broadcast::new();
+        let early = broadcast.subscribe();
+        for _ in 0..BACKLOG + 2 {
+            broadcast.publish(BuildUpdates::Started);
+        }
+        broadcast.close();
+        assert_eq!(early.iter().count(), BACKLOG + 2);
+        let (first, late) = broadcast.subscribe_indexed();
+        assert_eq!(first, 2);
+        assert_eq!(late.iter().count(), BACKLOG);
+    }
+}
@@ -1,49 +1,188 @@
-/// Integration for Bitbucket
-///
-/// API for updating build-status:
-/// https://developer.atlassian.com/bitbucket/api/2/reference/resource/repositories/%7Busername%7D/%7Brepo_slug%7D/commit/%7Bnode%7D/statuses/build
-///
-/// Documentation on web-hooks:
-/// https://confluence.atlassian.com/bitbucket/manage-webhooks-735643732.html
-///
+//! Integration for Bitbucket
+//!
+//! API for updating build-status:
+//! https://developer.atlassian.com/bitbucket/api/2/reference/resource/repositories/%7Busername%7D/%7Brepo_slug%7D/commit/%7Bnode%7D/statuses/build
+//!
+//! Documentation on web-hooks:
+//! https://confluence.atlassian.com/bitbucket/manage-webhooks-735643732.html
 
-use serde_json::{value, Value};
+use serde_json::Value;
 
 use errors::*;
-use BuildRequest;
+use history::BuildRecord;
+use utils;
+use {BuildRequest, BuildStatus, PullRequest};
 use integrations::{Hookable, Integrations};
 
+/// Longest description accepted in build-statuses
+const MAX_DESCRIPTION_LEN: usize = 255;
+
 
 impl Hookable for BuildRequest {
     fn parse_push_request(val: Value) -> Result<BuildRequest> {
         let integration = Integrations::Bitbucket;
-        let owner = json_val_as_str(&val, "actor")?;
-        let reponame = json_val_as_str(&val, "repository")?;
+        let (owner, reponame) = split_full_name(
+            &json_val_as_str(json_val_as_val(&val, "repository")?, "full_name")?,
+        )?;
+        let actor = actor_name(json_val_as_val(&val, "actor")?)?;
         let pushval = json_val_as_val(&val, "push")?;
-        let changesval = json_val_as_val(&pushval, "changes")?;
-        let firstrefval = json_arr_as_val(&changesval, 0)?;
-        let newval = json_val_as_val(&firstrefval, "new")?;
-        let targetval = json_val_as_val(&newval, "target")?;
-        let commit = json_val_as_str(&targetval, "hash")?;
+        let changesval = json_val_as_val(pushval, "changes")?;
+        let firstrefval = json_arr_as_val(changesval, 0)?;
+        let newval = json_val_as_val(firstrefval, "new")?;
+        let targetval = json_val_as_val(newval, "target")?;
+        let commit = json_val_as_str(targetval, "hash")?;
+        let branch = match json_val_as_str(newval, "type")?.as_str() {
+            "branch" => Some(json_val_as_str(newval, "name")?),
+            _ => None,
+        };
 
         Ok(BuildRequest {
             reponame,
             commit,
+            branch,
             integration,
             owner,
+            env: Default::default(),
+            pull_request: None,
+            actor: Some(actor),
+        })
+    }
+
+    fn parse_pull_request(val: Value) -> Result<BuildRequest> {
+        let prval = json_val_as_val(&val, "pullrequest")?;
+        let id = json_val_as_val(prval, "id")?.as_u64().ok_or(
+            ErrorKind::ParseError("\"id\" is not a number".into()),
+        )?;
+        let sourceval = json_val_as_val(prval, "source")?;
+        let destval = json_val_as_val(prval, "destination")?;
+        let branch_name = |val: &Value| json_val_as_str(json_val_as_val(val, "branch")?, "name");
+        let full_name = |val: &Value| {
+            json_val_as_str(json_val_as_val(val, "repository")?, "full_name")
+        };
+        let source_repo = full_name(sourceval)?;
+        let dest_repo = full_name(destval)?;
+        let (owner, reponame) = split_full_name(&dest_repo)?;
+        let actor = actor_name(json_val_as_val(&val, "actor")?)?;
+        let source_branch = branch_name(sourceval)?;
+        // Status is reported on the source-commit, whatever is built
+        let commit = json_val_as_str(json_val_as_val(sourceval, "commit")?, "hash")?;
+
+        Ok(BuildRequest {
+            integration: Integrations::Bitbucket,
+            owner,
+            reponame,
+            commit,
+            branch: Some(source_branch.clone()),
+            env: Default::default(),
+            pull_request: Some(PullRequest {
+                id,
+                source_branch,
+                target_branch: branch_name(destval)?,
+                fork: if source_repo != dest_repo {
+                    Some(source_repo)
+                } else {
+                    None
+                },
+            }),
+            actor: Some(actor),
         })
     }
-    fn build_clone_url(&self) -> String {
-        format!("git@bitbucket.org:{}/{}.git", self.owner, self.reponame)
+
+    fn build_clone_url(&self, https: bool) -> String {
+        clone_url(&format!("{}/{}", self.owner, self.reponame), https)
+    }
+
+    fn build_fork_url(&self, fork: &str, https: bool) -> String {
+        clone_url(fork, https)
+    }
+
+    fn report_status(
+        &self,
+        api_token: &str,
+        record: &BuildRecord,
+        target_url: Option<&str>,
+    ) -> Result<()> {
+        let state = match record.status {
+            BuildStatus::Successful => "SUCCESSFUL",
+            BuildStatus::Failed => "FAILED",
+            BuildStatus::Queued |
+            BuildStatus::AwaitingApproval |
+            BuildStatus::InProgress => "INPROGRESS",
+            BuildStatus::Stopped => "STOPPED",
+        };
+        // A link is mandatory, fall back to the commit itself
+        let url = match target_url {
+            Some(url) => url.to_owned(),
+            None => {
+                format!(
+                    "https://bitbucket.org/{}/{}/commits/{}",
+                    self.owner,
+                    self.reponame,
+                    self.commit
+                )
+            }
+        };
+        let description: String = record.description().chars().take(MAX_DESCRIPTION_LEN).collect();
+        let body = json!({
+            "state": state,
+            "key": "rupert",
+            "name": format!("rupert #{}", record.id),
+            "url": url,
+            "description": description,
+        });
+        let api_url = format!(
+            "https://api.bitbucket.org/2.0/repositories/{}/{}/commit/{}/statuses/build",
+            self.owner,
+            self.reponame,
+            self.commit
+        );
+        utils::http::post(
+            &api_url,
+            &[
+                ("Content-Type", "application/json"),
+                ("Authorization", &format!("Bearer {}", api_token)),
+            ],
+            body.to_string().as_bytes(),
+        ).map(|_| ())
+    }
+}
+
+/// Url to clone `full_name`, as `owner/reponame`, from
+fn clone_url(full_name: &str, https: bool) -> String {
+    if https {
+        format!("https://bitbucket.org/{}.git", full_name)
+    } else {
+        format!("git@bitbucket.org:{}.git", full_name)
+    }
+}
+
+/// Owner and name of a repository given by its `full_name`, `owner/reponame`
+fn split_full_name(full_name: &str) -> Result<(String, String)> {
+    let mut parts = full_name.splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(owner), Some(reponame)) => Ok((owner.to_owned(), reponame.to_owned())),
+        _ => {
+            bail!(ErrorKind::ParseError(
+                format!("\"{}\" is not owner/reponame", full_name),
+            ))
+        }
+    }
+}
+
+/// Name of the user who triggered an event, given as a name or an account
+fn actor_name(val: &Value) -> Result<String> {
+    match val.as_str() {
+        Some(name) => Ok(name.to_owned()),
+        None => json_val_as_str(val, "nickname").or_else(|_| json_val_as_str(val, "username")),
     }
 }
 
 fn json_val_as_str(val: &Value, key: &str) -> Result<String> {
     Ok(
-        json_val_as_val(&val, key)?
+        json_val_as_val(val, key)?
             .as_str()
             .ok_or(ErrorKind::ParseError(
-                format!("\"{}\" is not string", key).into(),
+                format!("\"{}\" is not string", key),
             ))?
             .to_owned(),
     )
@@ -51,13 +190,13 @@ fn json_val_as_str(val: &Value, key: &str) -> Result<String> {
 
 fn json_val_as_val<'a>(val: &'a Value, key: &str) -> Result<&'a Value> {
     val.get(key).ok_or(
-        ErrorKind::ParseError(format!("No \"{}\" in json", key).into()).into(),
+        ErrorKind::ParseError(format!("No \"{}\" in json", key)).into(),
     )
 }
 
-fn json_arr_as_val<'a>(val: &'a Value, index: usize) -> Result<&'a Value> {
+fn json_arr_as_val(val: &Value, index: usize) -> Result<&Value> {
     val.get(index).ok_or(
-        ErrorKind::ParseError(format!("No \"{}\" in json", index).into()).into(),
+        ErrorKind::ParseError(format!("No \"{}\" in json", index)).into(),
     )
 }
 
@@ -73,10 +212,29 @@ mod tests {
 
     lazy_static!{
         static ref PUSH_EXAMPLE: Value = serde_json::from_str(include_str!("bitbucket-push-example.json")).unwrap();
+        static ref PULLREQUEST_EXAMPLE: Value = serde_json::from_str(include_str!("bitbucket-pullrequest-example.json")).unwrap();
     }
 
     #[test]
     fn parse_example_build_request() {
-        BuildRequest::parse_push_request(PUSH_EXAMPLE.clone()).unwrap();
+        let req = BuildRequest::parse_push_request(PUSH_EXAMPLE.clone()).unwrap();
+        // Pushed by a collaborator, not the owner of the repository
+        assert_eq!((req.owner.as_str(), req.reponame.as_str()), ("superman", "linux"));
+        assert_eq!(req.actor, Some("lex".into()));
+        assert_eq!(req.commit, "709d658dc5b6d6afcd46049c2f332ee3f515a67d");
+        assert_eq!(req.branch, Some("name-of-branch".into()));
     }
-}
+
+    #[test]
+    fn parse_example_pull_request() {
+        let req = BuildRequest::parse_pull_request(PULLREQUEST_EXAMPLE.clone()).unwrap();
+        assert_eq!((req.owner.as_str(), req.reponame.as_str()), ("superman", "linux"));
+        assert_eq!(req.commit, "d3adb33f1234");
+        assert_eq!(req.branch, Some("feature/faster".into()));
+        let pr = req.pull_request.unwrap();
+        assert_eq!(pr.id, 42);
+        assert_eq!(pr.target_branch, "master");
+        assert_eq!(pr.fork, Some("lex/linux".into()));
+        assert_eq!(req.actor, Some("lex".into()));
+    }
+}
\ No newline at end of file
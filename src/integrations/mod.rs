@@ -4,12 +4,26 @@ use serde_json::Value;
 
 
 use errors::*;
+use history::BuildRecord;
 use BuildRequest;
 mod bitbucket;
 
 pub trait Hookable {
     fn parse_push_request(val: Value) -> Result<BuildRequest>;
-    fn build_clone_url(&self) -> String;
+    /// Request building the source-commit of a created or updated pull-request
+    fn parse_pull_request(val: Value) -> Result<BuildRequest>;
+    /// Url to clone the repository from, over https or else ssh
+    fn build_clone_url(&self, https: bool) -> String;
+    /// Url to clone `fork`, as `owner/reponame`, of the repository from
+    fn build_fork_url(&self, fork: &str, https: bool) -> String;
+    /// Report the status of `record` on the built commit, linking to `target_url` if
+    /// rupert is publicly reachable
+    fn report_status(
+        &self,
+        api_token: &str,
+        record: &BuildRecord,
+        target_url: Option<&str>,
+    ) -> Result<()>;
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -1,27 +1,37 @@
 #[macro_use]
 extern crate error_chain;
+#[macro_use]
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
 extern crate toml;
 extern crate git2;
-#[macro_use]
+#[cfg_attr(test, macro_use)]
 extern crate lazy_static;
 extern crate yansi;
+extern crate xml;
+extern crate curl;
+extern crate hmac;
+extern crate sha2;
+extern crate tiny_http;
+extern crate libc;
 
 #[macro_use]
 extern crate log;
 
-use std::error::Error;
-use std::fs::{create_dir_all, remove_dir_all};
-use std::hash::Hasher;
-use std::io::{Bytes, Read};
-use std::sync::mpsc::Sender;
+use std::collections::BTreeMap;
+use std::fs::{File, create_dir_all, read_dir, remove_dir_all, remove_file};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, channel};
+use std::thread;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio, Child, ChildStdout};
+use std::process::Stdio;
 
 use git2::Repository;
 
@@ -34,15 +44,30 @@ pub mod errors {
             description("Parse-error")
             display("Parse-error: {}", v)
         }
+        RefNotFound(v: String) {
+            description("Ref not found")
+            display("Ref not found: {}", v)
+        }
     }}
 }
 use errors::*;
 
+pub mod executor;
+pub mod history;
+pub mod hub;
+pub mod limits;
 mod integrations;
+pub mod metrics;
+pub mod notify;
+pub mod reports;
+pub mod server;
+pub mod users;
 pub mod utils;
 
 use integrations::Integrations;
 
+/// Counts the steps executed, numbering their ids
+static STEP_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 /// A request to build a certain commit
 #[derive(Serialize, Deserialize, Debug)]
@@ -51,6 +76,27 @@ pub struct BuildRequest {
     owner: String,
     reponame: String,
     commit: String,
+    /// Branch the commit was built for, if any
+    branch: Option<String>,
+    /// Extra environment-variables for the build-steps
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    /// The pull-request the commit is the source of, if built for one
+    #[serde(default)]
+    pull_request: Option<PullRequest>,
+    /// Who pushed the commit or opened the pull-request, for requests from webhooks
+    #[serde(default)]
+    actor: Option<String>,
+}
+
+/// A pull-request to build the source-commit of
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PullRequest {
+    id: u64,
+    source_branch: String,
+    target_branch: String,
+    /// `owner/reponame` of the fork the source-branch is in, if not the repository
+    fork: Option<String>,
 }
 
 impl BuildRequest {
@@ -59,230 +105,1023 @@ impl BuildRequest {
         owner: String,
         reponame: String,
         commit: String,
+        branch: Option<String>,
     ) -> Result<BuildRequest> {
         Ok(BuildRequest {
             owner,
             reponame,
             commit,
+            branch,
             integration,
+            env: BTreeMap::new(),
+            pull_request: None,
+            actor: None,
+        })
+    }
+
+    /// Whether the request is trusted by `repo_conf`, builds of untrusted requests await
+    /// approval and get no secrets.
+    ///
+    /// Pull-requests from forks are trusted if the owner of the fork is in `trusted`,
+    /// whoever opened or updated them, as the fork's owner decides what is built. Other
+    /// requests through the CLI and API are trusted, those from webhooks if their actor
+    /// is in `trusted`. Without `trusted` only pull-requests from forks are untrusted.
+    pub fn is_trusted(&self, repo_conf: &utils::RepoConfig) -> bool {
+        let trusted = |name: &str| {
+            repo_conf.trusted.as_ref().map(
+                |trusted| trusted.iter().any(|t| t == name),
+            )
+        };
+        let fork_owner = self.pull_request
+            .as_ref()
+            .and_then(|pr| pr.fork.as_ref())
+            .and_then(|fork| fork.split('/').next());
+        if let Some(owner) = fork_owner {
+            return trusted(owner).unwrap_or(false);
+        }
+        match self.actor {
+            Some(ref actor) => trusted(actor).unwrap_or(true),
+            None => true,
+        }
+    }
+
+    /// Fetch latest changes of the repository and create a `BuildRequest` for the
+    /// commit `gitref` currently resolves to.
+    pub fn resolve(
+        conf: &utils::Config,
+        repo_conf: &utils::RepoConfig,
+        gitref: &utils::git::GitRef,
+    ) -> Result<BuildRequest> {
+        let branch = match *gitref {
+            utils::git::GitRef::Branch(ref name) => Some(name.clone()),
+            _ => None,
+        };
+        let mut req = BuildRequest::new(
+            repo_conf.integration.clone(),
+            repo_conf.owner.clone(),
+            repo_conf.reponame.clone(),
+            String::new(),
+            branch,
+        )?;
+        let remote = req.remote(conf, repo_conf)?;
+        let path_root = Runner::path_root(&conf.meta.build_root, &req.owner, &req.reponame);
+        let path_mirror = Runner::subdir(&path_root, "mirror");
+        let _lock = Runner::lock_mirror(&path_root)?;
+        let repo = utils::git::init_mirror(&path_mirror, &remote)?;
+        utils::git::fetch(&repo, &remote, Some(gitref))?;
+        req.commit = utils::git::resolve_ref(&repo, gitref)?;
+        info!("Resolved {:?} to {}", gitref, req.commit);
+        Ok(req)
+    }
+
+    /// Where to fetch the repository from, with the credentials and options configured
+    /// for it
+    fn remote(
+        &self,
+        conf: &utils::Config,
+        repo_conf: &utils::RepoConfig,
+    ) -> Result<utils::git::Remote> {
+        let https = matches!(repo_conf.auth, Some(utils::GitAuth::Https { .. }));
+        Ok(utils::git::Remote {
+            url: repo_conf.url.clone().unwrap_or_else(
+                || self.build_clone_url(https),
+            ),
+            credentials: repo_conf.credentials(&conf.secrets)?,
+            options: repo_conf.clone.clone(),
+            submodules: repo_conf.submodules(&conf.secrets)?,
         })
     }
+
+    /// Remote of `fork`, with the credentials and options of `remote`
+    fn fork_remote(&self, fork: &str, remote: &utils::git::Remote) -> utils::git::Remote {
+        let https = matches!(remote.credentials, utils::git::Credentials::UserPass { .. });
+        utils::git::Remote {
+            url: self.build_fork_url(fork, https),
+            ..remote.clone()
+        }
+    }
 }
 
 
+/// Build `req` with the build-instruction in `repo_conf`.
+///
+/// The build is recorded in the build-history and notifications are sent if it changes
+/// the state of its branch. The build is registered in `hub` for as long as it runs.
+/// Its status is reported to the integration if `post_statuses`, as for builds of the
+/// server.
+pub fn run_build(
+    conf: &utils::Config,
+    repo_conf: &utils::RepoConfig,
+    req: &BuildRequest,
+    hub: &hub::Hub,
+    post_statuses: bool,
+) -> Result<history::BuildRecord> {
+    let history = history::History::new(&conf.meta.build_root, &req.owner, &req.reponame);
+    let record = history.queue(req)?;
+    run_queued_build(conf, repo_conf, req, record, hub, post_statuses)
+}
+
+/// Build `req` for `record`, previously recorded as queued, see `run_build`.
+///
+/// The build is stopped before starting if cancelled while waiting in the queue.
+pub fn run_queued_build(
+    conf: &utils::Config,
+    repo_conf: &utils::RepoConfig,
+    req: &BuildRequest,
+    mut record: history::BuildRecord,
+    hub: &hub::Hub,
+    post_statuses: bool,
+) -> Result<history::BuildRecord> {
+    let history = history::History::new(&conf.meta.build_root, &req.owner, &req.reponame);
+    let live = hub.register(&record);
+    let res = if live.cancelled.load(Ordering::SeqCst) {
+        info!("Build {} of {:?} cancelled while queued", record.id, req);
+        record.status = BuildStatus::Stopped;
+        record.finished = Some(utils::timestamp());
+        history.save(&record)
+    } else {
+        metrics::BUILDS_RUNNING.inc();
+        let res = execute_build(
+            conf,
+            repo_conf,
+            req,
+            &mut record,
+            &history,
+            &live,
+            post_statuses,
+        );
+        metrics::BUILDS_RUNNING.dec();
+        res
+    };
+    hub.finish(&record);
+    res?;
+    metrics::BUILDS.inc(
+        &[
+            &format!("{}/{}", record.owner, record.reponame),
+            &format!("{:?}", record.status),
+        ],
+    );
+
+    let previous = history.previous(&record)?;
+    let event = notify::BuildEvent::from_transition(
+        previous.as_ref().map(|r| &r.status),
+        &record.status,
+    );
+    if let Some(event) = event {
+        notify::notify(conf, repo_conf, event, &record);
+    }
+    Ok(record)
+}
+
+fn execute_build(
+    conf: &utils::Config,
+    repo_conf: &utils::RepoConfig,
+    req: &BuildRequest,
+    record: &mut history::BuildRecord,
+    history: &history::History,
+    live: &hub::LiveBuild,
+    post_statuses: bool,
+) -> Result<()> {
+    record.status = BuildStatus::InProgress;
+    record.started = utils::timestamp();
+    history.save(record)?;
+    info!("Starting build {} of {:?}", record.id, req);
+    if post_statuses {
+        report_status(conf, repo_conf, req, record);
+    }
+
+    let res = log_build(conf, repo_conf, req, record, history, live);
+    // Not left in progress by errors outside of the runner
+    if let Err(ref e) = res {
+        if record.status == BuildStatus::InProgress {
+            record.fail(e);
+            history.save(record)?;
+        }
+    }
+    if post_statuses {
+        report_status(conf, repo_conf, req, record);
+    }
+    res
+}
+
+/// Run the build in progress of `record`, logging and forwarding its updates
+fn log_build(
+    conf: &utils::Config,
+    repo_conf: &utils::RepoConfig,
+    req: &BuildRequest,
+    record: &mut history::BuildRecord,
+    history: &history::History,
+    live: &hub::LiveBuild,
+) -> Result<()> {
+    let path_log = history.path_log(record.id);
+    let mut log = File::create(&path_log).chain_err(|| {
+        format!("Failed creating build-log {:?}", path_log)
+    })?;
+    let log_writer = {
+        let updates = live.broadcast.subscribe();
+        thread::spawn(move || for update in updates {
+            if let Err(e) = writeln!(log, "{}", update) {
+                warn!("Failed writing build-log: {}", e);
+            }
+        })
+    };
+    let webhooks = {
+        let updates = live.broadcast.subscribe();
+        let mut webhooks = notify::webhook::Webhooks::new(conf, repo_conf, record);
+        thread::spawn(move || {
+            for update in updates {
+                webhooks.update(&update);
+            }
+            webhooks
+        })
+    };
+    let (runner_tx, runner_rx) = channel();
+    let forwarder = {
+        let broadcast = live.broadcast.clone();
+        thread::spawn(move || for update in runner_rx {
+            broadcast.publish(update);
+        })
+    };
+
+    let trusted = req.is_trusted(repo_conf);
+    let runner = req.remote(conf, repo_conf).and_then(|remote| {
+        Runner::new(
+            &conf.meta.build_root,
+            req,
+            &remote,
+            &repo_conf.executor,
+            trusted,
+            Some(runner_tx),
+        )
+    });
+    let result = runner.and_then(|mut runner| {
+        runner.cancelled = live.cancelled.clone();
+        runner.limit(&repo_conf.limits, conf.meta.cgroup.as_ref());
+        runner.hide(&conf.private_files);
+        if let Some(user) = repo_conf.user(&conf.meta)? {
+            runner.run_as(user);
+        }
+        record.author = runner.author();
+        if trusted {
+            runner.env.extend(repo_conf.secret_env(&conf.secrets)?);
+        } else if !repo_conf.secret_env.is_empty() {
+            info!("Withholding secrets from untrusted build {}", record.id);
+        }
+        if let Some(ref pr) = req.pull_request {
+            if repo_conf.merge_pull_requests {
+                runner.merge_into(&pr.target_branch)?;
+            }
+        }
+        notify::notify(conf, repo_conf, notify::BuildEvent::Started, record);
+        runner.execute(&repo_conf.build_instruction)
+    });
+    record.finish(result);
+    history.save(record)?;
+
+    // The build-log is complete before the build stops being live
+    let _ = forwarder.join();
+    live.broadcast.close();
+    if log_writer.join().is_err() {
+        warn!("Writing of build-log panicked");
+    }
+    match webhooks.join() {
+        Ok(webhooks) => webhooks.finish(record),
+        Err(_) => warn!("Forwarding of updates to webhooks panicked"),
+    }
+    Ok(())
+}
+
+/// Add the git-option `key` to the ones given in `env`, after any given already
+fn add_git_config(env: &mut BTreeMap<String, String>, key: &str, value: &str) {
+    let count = env.get("GIT_CONFIG_COUNT")
+        .and_then(|count| count.parse::<usize>().ok())
+        .unwrap_or(0);
+    env.insert(format!("GIT_CONFIG_KEY_{}", count), key.into());
+    env.insert(format!("GIT_CONFIG_VALUE_{}", count), value.into());
+    env.insert("GIT_CONFIG_COUNT".into(), (count + 1).to_string());
+}
+
+/// Record a build of `req` as awaiting approval, to be approved through the CLI or API
+pub fn hold_build(
+    conf: &utils::Config,
+    repo_conf: &utils::RepoConfig,
+    req: &BuildRequest,
+) -> Result<history::BuildRecord> {
+    let history = history::History::new(&conf.meta.build_root, &req.owner, &req.reponame);
+    let record = history.hold(req)?;
+    info!("Build {} of {:?} is awaiting approval", record.id, req);
+    report_status(conf, repo_conf, req, &record);
+    Ok(record)
+}
+
+/// Report the status of `record` to the integration, failures are only logged
+fn report_status(
+    conf: &utils::Config,
+    repo_conf: &utils::RepoConfig,
+    req: &BuildRequest,
+    record: &history::BuildRecord,
+) {
+    let target_url = conf.meta.public_url.as_ref().map(|url| record.url(url));
+    let res = req.report_status(
+        &repo_conf.api_token,
+        record,
+        target_url.as_deref(),
+    );
+    if let Err(e) = res {
+        warn!("Failed reporting status of build {}: {}", record.id, e);
+    }
+}
+
+/// Where the code being built comes from
+enum Workspace {
+    /// The requested commit, checked out from rupert's mirror of `remote` into a worktree
+    Clone { remote: utils::git::Remote },
+    /// The working tree of a local repository, uncommitted changes included, copied as
+    /// given by `copy`
+    Local {
+        include_untracked: bool,
+        copy: utils::copy::CopyOptions,
+    },
+}
+
 /// repo:commit checked out on local path
 pub struct Runner {
+    name: String,
+    revision: String,
     path_root: PathBuf,
+    /// Mirror of the repository, or the local working tree
     path_repo: PathBuf,
     path_build: PathBuf,
     path_cache: PathBuf,
+    path_artifacts: PathBuf,
     repo: git2::Repository,
+    workspace: Workspace,
+    /// Held for as long as the build uses `path_build`
+    _build_lock: utils::lock::Lock,
+    /// Held for as long as the build uses `path_artifacts`, shared by builds of a commit
+    _artifacts_lock: utils::lock::Lock,
+    env: BTreeMap<String, String>,
+    executor: Arc<dyn executor::Executor>,
+    limits: limits::Limits,
+    /// Cgroup delegated to rupert, under which steps get cgroups of their own
+    cgroup: Option<PathBuf>,
+    /// User the steps run as, rather than as rupert
+    user: Option<users::User>,
+    /// Files hidden from the steps, see `executor::Step`
+    hidden: Vec<PathBuf>,
+    /// Stops the build when set, the running step is killed
+    cancelled: Arc<AtomicBool>,
     tx: Option<Sender<utils::BuildUpdates>>,
 }
 
 impl Runner {
     /// Initiate a new `Runner` object representing the local data on disk.
     ///
-    /// Either clones repository or fetches and checks out commit in `BuildRequest`.
+    /// Either clones the mirror of the repository from `remote` or fetches the commit in
+    /// `BuildRequest` into it.
+    ///
+    /// Untrusted builds get a build-dir and cache of their own, `builds/untrusted` and
+    /// `cache-untrusted`, so nothing they leave behind is run by trusted builds.
     pub fn new(
         rupert_root: &Path,
         req: &BuildRequest,
+        remote: &utils::git::Remote,
+        executor: &executor::ExecutorConfig,
+        trusted: bool,
         tx: Option<Sender<utils::BuildUpdates>>,
     ) -> Result<Self> {
-        let mut path_root = rupert_root.to_owned();
-        path_root.push(&req.owner);
-        path_root.push(&req.reponame);
+        let path_root = Runner::path_root(rupert_root, &req.owner, &req.reponame);
+        let path_repo = Runner::subdir(&path_root, "mirror");
+        let (build_dir, cache_dir) = if trusted {
+            ("common", "cache")
+        } else {
+            ("untrusted", "cache-untrusted")
+        };
+        let path_cache = Runner::subdir(&path_root, cache_dir);
+        let (path_build, build_lock) = Runner::lock_build_dir(&path_root, build_dir)?;
+        let (path_artifacts, artifacts_lock) = Runner::lock_artifacts(&path_root, &req.commit)?;
+
+        let name = format!("{}/{}", req.owner, req.reponame);
+        info!("init mirror in {:?}", path_repo);
+        let mirror_lock = Runner::lock_mirror(&path_root)?;
+        let cloning = !path_repo.exists();
+        let repo = utils::git::init_mirror(&path_repo, remote)?;
+        let commit = utils::git::GitRef::Commit(req.commit.clone());
+        if !utils::git::has_commit(&repo, &req.commit) {
+            let started = Instant::now();
+            let gitref = match req.branch {
+                Some(ref branch) => utils::git::GitRef::Branch(branch.clone()),
+                None => commit.clone(),
+            };
+            match req.pull_request {
+                Some(PullRequest {
+                         fork: Some(ref fork),
+                         ref source_branch,
+                         ..
+                     }) => {
+                    let fork_remote = req.fork_remote(fork, remote);
+                    utils::git::fetch_fork(&repo, &fork_remote, fork, source_branch)?;
+                }
+                _ => {
+                    utils::git::fetch(&repo, remote, Some(&gitref))?;
+                    // The branch may have moved on beyond the depth fetched
+                    if !utils::git::has_commit(&repo, &req.commit) &&
+                        remote.options.single_branch
+                    {
+                        utils::git::fetch(&repo, remote, Some(&commit))?;
+                    }
+                }
+            }
+            let operation = if cloning { "clone" } else { "fetch" };
+            metrics::GIT_DURATION.observe(&[&name, operation], started.elapsed());
+        }
+        // Pull-request events only give an abbreviated commit-id
+        let revision = utils::git::resolve_ref(&repo, &commit)?;
+        if let Err(e) = Runner::collect_build_dirs(&path_root, build_dir, Some(&repo)) {
+            warn!("Failed removing unused build-dirs of {}: {}", name, e);
+        }
+        drop(mirror_lock);
+
+        let mut env = req.env.clone();
+        if let Some(ref pr) = req.pull_request {
+            env.insert("PR_ID".into(), pr.id.to_string());
+            env.insert("PR_SOURCE_BRANCH".into(), pr.source_branch.clone());
+            env.insert("PR_TARGET_BRANCH".into(), pr.target_branch.clone());
+        }
+
+        Ok(Runner {
+            name,
+            revision,
+            path_root,
+            path_build,
+            path_repo,
+            path_cache,
+            path_artifacts,
+            repo,
+            workspace: Workspace::Clone { remote: remote.clone() },
+            _build_lock: build_lock,
+            _artifacts_lock: artifacts_lock,
+            env,
+            executor: executor.executor(),
+            limits: limits::Limits::default(),
+            cgroup: None,
+            user: None,
+            hidden: Vec::new(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            tx,
+        })
+    }
 
-        let path_repo = Runner::subdir(&path_root, "repo");
+    /// Initiate a new `Runner` for the working tree of the local repository at
+    /// `path_src`.
+    ///
+    /// Nothing is fetched or checked out, the build runs on a snapshot of the files as
+    /// they currently are. It is copied as configured for `repo_conf`.
+    pub fn local(
+        rupert_root: &Path,
+        repo_conf: &utils::RepoConfig,
+        path_src: &Path,
+        include_untracked: bool,
+        tx: Option<Sender<utils::BuildUpdates>>,
+    ) -> Result<Self> {
+        let path_root = Runner::path_root(rupert_root, &repo_conf.owner, &repo_conf.reponame);
         let path_cache = Runner::subdir(&path_root, "cache");
-        let path_build = Runner::path_build(&path_root, &req.commit);
+        // Kept apart from regular builds which reuse their build-dir
+        let (path_build, build_lock) = Runner::lock_build_dir(&path_root, "local")?;
+        let (path_artifacts, artifacts_lock) = Runner::lock_artifacts(&path_root, "local")?;
+        if let Err(e) = Runner::collect_build_dirs(&path_root, "local", None) {
+            warn!("Failed removing unused build-dirs in {:?}: {}", path_root, e);
+        }
 
-        let url = req.build_clone_url();
-        info!("init repo in {:?}", path_repo);
-        let repo = utils::git::init_repo(&path_repo, &url)?;
-        utils::git::fetch_origin_branches(&repo)?;
-        utils::git::checkout(&repo, &req.commit)?;
+        let repo = Repository::discover(path_src).chain_err(|| {
+            format!("No repository found at {:?}", path_src)
+        })?;
+        let path_repo = repo.workdir()
+            .ok_or(format!("Repository at {:?} is bare", path_src))?
+            .to_owned();
+        info!("Using working tree in {:?}", path_repo);
 
         Ok(Runner {
+            name: format!("{}/{}", repo_conf.owner, repo_conf.reponame),
+            revision: format!("working tree {}", path_repo.display()),
             path_root,
             path_build,
             path_repo,
             path_cache,
+            path_artifacts,
             repo,
+            workspace: Workspace::Local {
+                include_untracked,
+                copy: repo_conf.copy.clone(),
+            },
+            _build_lock: build_lock,
+            _artifacts_lock: artifacts_lock,
+            env: BTreeMap::new(),
+            executor: repo_conf.executor.executor(),
+            limits: limits::Limits::default(),
+            cgroup: None,
+            user: None,
+            hidden: Vec::new(),
+            cancelled: Arc::new(AtomicBool::new(false)),
             tx,
         })
     }
 
+    /// Author of the commit being built, not known for local working trees
+    pub fn author(&self) -> Option<history::Author> {
+        match self.workspace {
+            Workspace::Clone { .. } => {
+                utils::git::commit_author(&self.repo, &self.revision)
+                    .map(|(name, email)| history::Author { name, email })
+                    .map_err(|e| warn!("Could not find author: {}", e))
+                    .ok()
+            }
+            Workspace::Local { .. } => None,
+        }
+    }
+
+    /// Limit the resources of the steps, `cgroup` being a cgroup v2 delegated to rupert
+    /// for their `memory` and `cpus`
+    pub fn limit(&mut self, limits: &limits::Limits, cgroup: Option<&PathBuf>) {
+        self.limits = limits.clone();
+        self.cgroup = cgroup.cloned();
+    }
+
+    /// Run the steps as `user`, to whom the build-, cache- and artifact-dirs are handed
+    pub fn run_as(&mut self, user: users::User) {
+        self.user = Some(user);
+    }
+
+    /// Hide `files` from the steps, where the executor can
+    pub fn hide(&mut self, files: &[PathBuf]) {
+        self.hidden = files.to_vec();
+    }
+
+    /// Build the merge of the requested commit into `branch`, rather than the commit
+    /// itself
+    pub fn merge_into(&mut self, branch: &str) -> Result<()> {
+        let merge = {
+            let remote = match self.workspace {
+                Workspace::Clone { ref remote } => remote,
+                Workspace::Local { .. } => bail!("Local working trees can not be merged"),
+            };
+            let _lock = Runner::lock_mirror(&self.path_root)?;
+            let target = utils::git::GitRef::Branch(branch.to_owned());
+            utils::git::fetch(&self.repo, remote, Some(&target))?;
+            let target = utils::git::resolve_ref(&self.repo, &target)?;
+            info!("Building {} merged into {} at {}", self.revision, branch, target);
+            utils::git::merge_commit(&self.repo, &target, &self.revision)?
+        };
+        self.revision = merge;
+        Ok(())
+    }
+
     fn prepare_dirs(&self) -> Result<()> {
-        if self.path_build.exists() {
-            let res = remove_dir_all(&self.path_build).chain_err(|| {
-                format!("Failed remove of {:?}", self.path_build)
-            });
-            if let Err(e) = res {
-                warn!(
-                    "Could not remove old build in {:?} due to {:?}",
-                    self.path_build,
-                    e
+        // Handing hardlinks to the user would hand it the files of the working tree
+        if let (Some(_), Workspace::Local { copy, .. }) = (&self.user, &self.workspace) {
+            if copy.mode == utils::copy::CopyMode::Hardlink {
+                bail!("Files can not be hardlinked for steps running as a user, copy them");
+            }
+        }
+        // Worktrees are reused, only the files changed since their last build are written
+        let mut stale = vec![&self.path_artifacts];
+        if let Workspace::Local { .. } = self.workspace {
+            stale.push(&self.path_build);
+        }
+        for path in stale {
+            if path.exists() {
+                let res = remove_dir_all(path).chain_err(
+                    || format!("Failed remove of {:?}", path),
                 );
+                if let Err(e) = res {
+                    warn!("Could not remove old build in {:?} due to {:?}", path, e);
+                }
             }
         }
         create_dir_all(&self.path_build).chain_err(|| {
             format!("Failed creating build-dir: {:?}", self.path_build)
         })?;
+        create_dir_all(&self.path_artifacts).chain_err(|| {
+            format!("Failed creating artifact-dir: {:?}", self.path_artifacts)
+        })?;
 
-        utils::copy_dir(&self.path_repo, &self.path_build)?;
+        match self.workspace {
+            Workspace::Clone { ref remote } => {
+                let _lock = Runner::lock_mirror(&self.path_root)?;
+                let worktree = utils::git::checkout_worktree(
+                    &self.repo,
+                    &self.path_build,
+                    &self.revision,
+                    remote,
+                )?;
+                utils::git::verify_head(&worktree, &self.revision)?;
+                if remote.options.lfs {
+                    utils::git::lfs_pull(&self.repo, &worktree, remote)?;
+                }
+            }
+            Workspace::Local {
+                include_untracked,
+                ref copy,
+            } => {
+                let files = utils::git::working_tree_files(&self.repo, include_untracked)?;
+                utils::copy::copy_files(&self.path_repo, &files, &self.path_build, copy)?;
+            }
+        }
         create_dir_all(&self.path_cache).chain_err(|| {
             format!("Failed creating cache-dir: {:?}", self.path_cache)
         })?;
+        match self.user {
+            Some(ref user) => users::create_home(&self.path_home(), user)?,
+            None => {
+                create_dir_all(self.path_home()).chain_err(|| {
+                    format!("Failed creating home-directory {:?}", self.path_home())
+                })?
+            }
+        }
+        if let Some(ref user) = self.user {
+            for path in &[&self.path_build, &self.path_cache, &self.path_artifacts] {
+                users::chown_all(path, user)?;
+            }
+        }
         Ok(())
     }
 
+    /// Home-directory of the steps, kept along with the cache
+    fn path_home(&self) -> PathBuf {
+        self.path_cache.join("home")
+    }
+
     /// Execute build-steps from configuration on local code
     pub fn execute(self, build_instruction: &BuildInstruction) -> Result<BuildResult> {
+        let started = Instant::now();
         self.prepare_dirs()?;
         info!("Executing build in {:?}", self.path_build);
+        self.send_update(utils::BuildUpdates::Started)?;
         let mut results = Vec::new();
         for step in &build_instruction.steps {
-            // TODO Clean env so Command runs without outside knowledge
-            let step_result = self.spawn_step_worker(&step)?;
+            if self.cancelled.load(Ordering::SeqCst) {
+                results.push(BuildStepResult {
+                    status: BuildStatus::Stopped,
+                    cmd: step.cmd.clone(),
+                    output: String::new(),
+                    duration: Duration::from_secs(0),
+                    tests: None,
+                    limit: None,
+                });
+                break;
+            }
+            if step.unshallow {
+                if let Workspace::Clone { ref remote } = self.workspace {
+                    let _lock = Runner::lock_mirror(&self.path_root)?;
+                    utils::git::unshallow(&self.repo, remote)?;
+                }
+            }
+            let step_result = self.spawn_step_worker(step)?;
             let status = step_result.status.clone();
+            self.send_update(
+                utils::BuildUpdates::StepFinished(step_result.clone()),
+            )?;
             results.push(step_result);
             if status != BuildStatus::Successful {
                 break;
             }
         }
-        Ok(BuildResult { steps: results })
+        self.send_update(utils::BuildUpdates::Finished)?;
+
+        let reports = reports::write_reports(
+            &self.path_artifacts,
+            &reports::ReportContext {
+                name: &self.name,
+                revision: &self.revision,
+                steps: &build_instruction.steps,
+                results: &results,
+            },
+        )?;
+        metrics::BUILD_DURATION.observe(&[&self.name], started.elapsed());
+        Ok(BuildResult {
+            steps: results,
+            reports,
+        })
     }
 
     fn spawn_step_worker(&self, step: &BuildStep) -> Result<BuildStepResult> {
         self.send_update(
             utils::BuildUpdates::StepStarted(step.cmd.clone()),
         )?;
-        let mut child = Command::new("stdbuf")
-            .current_dir(&self.path_build)
-            .env("PATH_BUILD", &self.path_build)
-            .env("PATH_CACHE", &self.path_cache)
-            .args(&["-oL", "bash", "-c", &step.cmd])
+        let started = Instant::now();
+        let id = format!(
+            "rupert-{}-{}",
+            std::process::id(),
+            STEP_COUNTER.fetch_add(1, Ordering::SeqCst)
+        );
+        let limits = self.limits.merge(&step.limits);
+        let cgroup = match self.cgroup {
+            Some(ref parent) if limits.need_cgroup() && self.executor.cgroup() => {
+                Some(limits::Cgroup::create(parent, &id, &limits)?)
+            }
+            None if limits.need_cgroup() && self.executor.cgroup() => {
+                // `processes` is limited per user instead, without a user it is refused
+                if limits.memory.is_some() || limits.cpus.is_some() {
+                    warn!("Not limiting memory and cpus of the step, no cgroup is delegated");
+                }
+                None
+            }
+            _ => None,
+        };
+        let execution = executor::Step {
+            id,
+            cmd: step.cmd.clone(),
+            path_build: self.path_build.clone(),
+            path_cache: self.path_cache.clone(),
+            path_artifacts: self.path_artifacts.clone(),
+            path_mirror: match self.workspace {
+                Workspace::Clone { .. } => Some(self.path_repo.clone()),
+                Workspace::Local { .. } => None,
+            },
+            env: self.step_env(),
+            limits: limits.clone(),
+            cgroup: cgroup.as_ref().map(|cgroup| cgroup.path().clone()),
+            user: self.user,
+            hidden: self.hidden.clone(),
+        };
+        let mut command = self.executor.command(&execution);
+        let mut child = command
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
+            // In a group of its own, so the step can be killed along with its children
+            .process_group(0)
             .spawn()
-            .chain_err(|| "Failed executing step")?;
-
-        let mut status = BuildStatus::InProgress;
-        let mut keep_waiting = true;
-        let mut stdout_agg = String::new();
-        let mut stderr_agg = String::new();
-        while keep_waiting {
-            match child.try_wait() {
-                Ok(val) => {
-                    let stdout = Runner::grab_line_from_stdout(&mut child)?;
-                    if !stdout.is_empty() {
-                        stdout_agg += &stdout;
-                        self.send_update(utils::BuildUpdates::StepNewOutput(
-                            utils::TextOutput::Stdout(stdout),
-                        ))?;
-                    }
-                    if let Some(retval) = val {
-                        let stderr = Runner::grab_stderr(&mut child)?;
-                        if !stderr.is_empty() {
-                            stderr_agg += &stderr;
-                            self.send_update(utils::BuildUpdates::StepNewOutput(
-                                utils::TextOutput::Stderr(format!(
-                                    "------------- Rupert: Delayed stderr:\n{}\n------------- End of delayed stderr", stderr)
-                                ),
-                            ))?;
-                        }
-                        status = if retval.success() {
-                            BuildStatus::Successful
-                        } else {
-                            BuildStatus::Failed
-                        };
-                        keep_waiting = false;
-                    }
-                }
-                Err(e) => {
-                    return Err(e).chain_err(|| "Could not wait on child-process");
-                }
+            .chain_err(|| format!("Failed executing step with {:?}", command.get_program()))?;
+        let finished = Arc::new(AtomicBool::new(false));
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let killer = Runner::spawn_killer(
+            child.id(),
+            self.executor.clone(),
+            execution,
+            self.cancelled.clone(),
+            exceeded.clone(),
+            finished.clone(),
+        );
+
+        let max_output = limits.output.map(|size| size.0).unwrap_or(u64::MAX);
+        // stdout and stderr are read by threads of their own, so neither fills up and
+        // blocks the step while the other is read. Both end once the step closed them.
+        let (output_tx, output_rx) = channel();
+        let readers = vec![
+            Runner::spawn_reader(
+                child.stdout.take().chain_err(|| "Could not grab stdout")?,
+                utils::TextOutput::Stdout,
+                max_output.saturating_add(1),
+                output_tx.clone(),
+            ),
+            Runner::spawn_reader(
+                child.stderr.take().chain_err(|| "Could not grab stderr")?,
+                utils::TextOutput::Stderr,
+                max_output.saturating_add(1),
+                output_tx,
+            ),
+        ];
+        let mut output = String::new();
+        let mut output_exceeded = false;
+        for line in output_rx {
+            let line = line?;
+            let text = match line {
+                utils::TextOutput::Stdout(ref text) |
+                utils::TextOutput::Stderr(ref text) => text.clone(),
             };
+            if output_exceeded {
+                continue;
+            }
+            // Output beyond the limit is still read, so the step is not blocked until killed
+            if (output.len() + text.len() + 1) as u64 > max_output {
+                output_exceeded = true;
+                exceeded.store(true, Ordering::SeqCst);
+                continue;
+            }
+            output += &text;
+            output += "\n";
+            self.send_update(utils::BuildUpdates::StepNewOutput(line))?;
         }
+        for reader in readers {
+            let _ = reader.join();
+        }
+        let retval = child.wait().chain_err(|| "Could not wait on child-process")?;
+        let mut limit = None;
+        let status = if self.cancelled.load(Ordering::SeqCst) {
+            BuildStatus::Stopped
+        } else if output_exceeded {
+            limit = Some(limits::Limit::Output);
+            BuildStatus::Failed
+        } else if retval.success() {
+            BuildStatus::Successful
+        } else {
+            limit = limits.exceeded(&retval, cgroup.as_ref());
+            BuildStatus::Failed
+        };
+        finished.store(true, Ordering::SeqCst);
+        let _ = killer.join();
+        drop(cgroup);
+        if let Some(limit) = limit {
+            self.send_update(utils::BuildUpdates::StepNewOutput(
+                utils::TextOutput::Stderr(format!("------------- Rupert: Step failed, {}", limit)),
+            ))?;
+        }
+        let duration = started.elapsed();
+        metrics::STEP_DURATION.observe(&[&self.name], duration);
         Ok(BuildStepResult {
             status: status.clone(),
             cmd: step.cmd.clone(),
-            output: stdout_agg,
+            output,
+            duration,
+            tests: self.ingest_test_reports(step),
+            limit,
         })
     }
 
-    fn grab_line_from_stdout(child: &mut Child) -> Result<String> {
-        let new_out = child
-            .stdout
-            .as_mut()
-            .chain_err(|| "Could not grab stdout")?
-            .bytes();
-        let bytes = new_out
-            .map(|v| v.chain_err(|| "Failed reading child output"))
-            .take_while(|v| match v {
-                &Ok(ref c) => {
-                    //info!("stdout: {}", c);
-                    if *c as usize == 10 { false } else { true }
+    /// Environment of the steps, with their home-directory
+    fn step_env(&self) -> BTreeMap<String, String> {
+        let mut env = self.env.clone();
+        env.insert("HOME".into(), self.path_home().to_string_lossy().into_owned());
+        if self.user.is_some() {
+            // Git refuses the worktree, as its git-dir in the mirror is owned by rupert
+            add_git_config(&mut env, "safe.directory", "*");
+        }
+        env
+    }
+
+    /// Summarize the test-reports declared by `step`, reports that are missing or
+    /// malformed are skipped with a warning.
+    fn ingest_test_reports(&self, step: &BuildStep) -> Option<reports::ingest::TestSummary> {
+        if step.reports.is_empty() {
+            return None;
+        }
+        let mut summary = reports::ingest::TestSummary::default();
+        for report in &step.reports {
+            let path = Path::new(&report.path);
+            match reports::ingest::parse(&self.path_build, path, report.format) {
+                Ok(res) => summary.merge(res),
+                Err(e) => warn!("Skipping test-report {:?}: {}", path, e),
+            }
+        }
+        Some(summary)
+    }
+
+    /// Kill the process-group `pgid` if the build is cancelled before the step is
+    /// `finished`
+    fn spawn_killer(
+        pgid: u32,
+        executor: Arc<dyn executor::Executor>,
+        step: executor::Step,
+        cancelled: Arc<AtomicBool>,
+        exceeded: Arc<AtomicBool>,
+        finished: Arc<AtomicBool>,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || while !finished.load(Ordering::SeqCst) {
+            let cancel = cancelled.load(Ordering::SeqCst);
+            if cancel || exceeded.load(Ordering::SeqCst) {
+                if cancel {
+                    info!("Build cancelled, killing step");
+                } else {
+                    info!("Step exceeded its output-limit, killing it");
+                }
+                unsafe {
+                    libc::kill(-(pgid as libc::pid_t), libc::SIGKILL);
                 }
-                _ => true,
-            })
-            .collect::<Result<Vec<u8>>>()?;
-        let stdout = String::from_utf8_lossy(&bytes).trim().into();
-        Ok(stdout)
-    }
-    fn grab_stderr(child: &mut Child) -> Result<String> {
-        // TODO Currently grabbing all of stderr after child-process has finished
-        // instead of interweaved with stdout as there is no "try_read" function.
-        let mut buffer = Vec::new();
-        child
-            .stderr
-            .as_mut()
-            .chain_err(|| "Could not grab stderr")?
-            .read_to_end(&mut buffer)
-            .chain_err(|| "Failed reading child stderr")?;
-
-        let stderr = String::from_utf8_lossy(&buffer).trim().into();
-        Ok(stderr)
+                executor.kill(&step);
+                return;
+            }
+            sleep(Duration::from_millis(100));
+        })
+    }
+
+    /// Send the lines of `output` of a step, of at most `max` bytes each, until it is
+    /// closed
+    fn spawn_reader<R: Read + Send + 'static>(
+        output: R,
+        text: fn(String) -> utils::TextOutput,
+        max: u64,
+        tx: Sender<Result<utils::TextOutput>>,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut output = BufReader::new(output);
+            loop {
+                let mut line = Vec::new();
+                match (&mut output).take(max).read_until(b'\n', &mut line) {
+                    Ok(0) => return,
+                    Ok(_) => {
+                        let line = String::from_utf8_lossy(&line).trim_end().to_owned();
+                        if tx.send(Ok(text(line))).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e).chain_err(|| "Failed reading output of step"));
+                        return;
+                    }
+                }
+            }
+        })
     }
 
     fn send_update(&self, update: utils::BuildUpdates) -> Result<()> {
-        match &self.tx {
-            &Some(ref tx) => {
+        match self.tx {
+            Some(ref tx) => {
                 tx.send(update).chain_err(
                     || "Send of update to subscriber failed",
                 )
             }
-            &None => Ok(()),
+            None => Ok(()),
         }
     }
 
+    fn path_root(rupert_root: &Path, owner: &str, reponame: &str) -> PathBuf {
+        let mut path = rupert_root.to_owned();
+        path.push(owner);
+        path.push(reponame);
+        path
+    }
+
     fn subdir(root: &PathBuf, name: &str) -> PathBuf {
         let mut path = root.to_owned();
         path.push(name);
         path
     }
 
-    fn path_build(root: &PathBuf, commit: &str) -> PathBuf {
-        let mut path = root.clone();
-        path.push("builds");
-        // TODO Do we want each build in a new dir?
-        //path.push(commit);
-        path.push("common");
-        path
+    /// Artifact-dir `artifacts/<revision>`, locked for the build. Concurrent builds of
+    /// the same revision wait for each other, rather than removing each other's artifacts.
+    fn lock_artifacts(root: &PathBuf, revision: &str) -> Result<(PathBuf, utils::lock::Lock)> {
+        let artifacts = Runner::subdir(root, "artifacts");
+        let lock = Runner::subdir(&artifacts, &format!("{}.lock", revision));
+        let lock = utils::lock::Lock::acquire(&lock)?;
+        Ok((Runner::subdir(&artifacts, revision), lock))
+    }
+
+    /// Build-dir `builds/<name>`, locked for the build. Concurrent builds of the
+    /// repository get a build-dir of their own, `<name>-1` and so on.
+    fn lock_build_dir(root: &PathBuf, name: &str) -> Result<(PathBuf, utils::lock::Lock)> {
+        let builds = Runner::subdir(root, "builds");
+        let mut slot = 0;
+        loop {
+            let dir = match slot {
+                0 => name.to_owned(),
+                _ => format!("{}-{}", name, slot),
+            };
+            let lock = Runner::subdir(&builds, &format!("{}.lock", dir));
+            if let Some(lock) = utils::lock::Lock::try_acquire(&lock)? {
+                return Ok((Runner::subdir(&builds, &dir), lock));
+            }
+            slot += 1;
+        }
     }
-}
 
-/// The `BuildConfig` containing all information needed for running tests
-pub struct BuildConfig {
-    request: BuildRequest,
-    runner: Runner,
-    build_instruction: BuildInstruction,
+    /// Remove the build-dirs `builds/<name>-N` of concurrent builds which no build holds,
+    /// as `lock_build_dir` only ever adds them, and forget their worktrees in `mirror`.
+    fn collect_build_dirs(root: &PathBuf, name: &str, mirror: Option<&Repository>) -> Result<()> {
+        let builds = Runner::subdir(root, "builds");
+        let entries = match read_dir(&builds) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(()),
+        };
+        let prefix = format!("{}-", name);
+        for entry in entries {
+            let file_name = entry.chain_err(|| "Failed reading build-dirs")?.file_name();
+            let dir = match file_name.to_str().and_then(|f| f.strip_suffix(".lock")) {
+                Some(dir) => dir,
+                None => continue,
+            };
+            let slot = dir.strip_prefix(&prefix).and_then(|slot| slot.parse::<u32>().ok());
+            if slot.is_none() {
+                continue;
+            }
+            let path_lock = Runner::subdir(&builds, &file_name.to_string_lossy());
+            // Removed while locked, a build waiting for the lock then takes a new one
+            if let Some(_lock) = utils::lock::Lock::try_acquire(&path_lock)? {
+                let path = Runner::subdir(&builds, dir);
+                if path.exists() {
+                    info!("Removing unused build-dir {:?}", path);
+                    remove_dir_all(&path).chain_err(|| format!("Failed removing {:?}", path))?;
+                }
+                remove_file(&path_lock).chain_err(|| format!("Failed removing {:?}", path_lock))?;
+            }
+        }
+        match mirror {
+            Some(mirror) => utils::git::prune_worktrees(mirror),
+            None => Ok(()),
+        }
+    }
+
+    /// Lock the mirror of the repository, until the lock is dropped
+    fn lock_mirror(root: &PathBuf) -> Result<utils::lock::Lock> {
+        utils::lock::Lock::acquire(&Runner::subdir(root, "mirror.lock"))
+    }
 }
 
 /// Contains results of build
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BuildResult {
     pub steps: Vec<BuildStepResult>,
+    /// Reports written to the artifact-directory of the build
+    pub reports: reports::BuildReports,
 }
 
 impl BuildResult {
@@ -291,6 +1130,30 @@ impl BuildResult {
             |res| res.status != BuildStatus::Successful,
         )
     }
+
+    /// Short description of the outcome, e.g. for build-statuses
+    pub fn description(&self) -> String {
+        let failed = self.steps.iter().find(
+            |res| res.status != BuildStatus::Successful,
+        );
+        let (mut description, tests) = match failed {
+            Some(step) => (
+                format!("{} at `{}`", step.status_description(), step.cmd),
+                step.tests.as_ref(),
+            ),
+            None => {
+                let tests = self.steps.iter().rev().find(|res| res.tests.is_some());
+                (
+                    format!("Successful, {} steps", self.steps.len()),
+                    tests.and_then(|res| res.tests.as_ref()),
+                )
+            }
+        };
+        if let Some(tests) = tests {
+            description += &format!(": {}", tests);
+        }
+        description
+    }
 }
 
 /// A single step in a build
@@ -303,14 +1166,40 @@ pub struct BuildInstruction {
 #[derive(Clone, Deserialize, Debug)]
 pub struct BuildStep {
     cmd: String,
+    /// Test-reports written by the step, summarized in its `BuildStepResult`
+    #[serde(default)]
+    reports: Vec<reports::ingest::TestReport>,
+    /// Fetch the full history first, for repositories cloned with a depth
+    #[serde(default)]
+    unshallow: bool,
+    /// Limits of the step, overriding those of the repository
+    #[serde(default)]
+    limits: limits::Limits,
 }
 
 /// The result of executing a `BuildStep`
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BuildStepResult {
     pub status: BuildStatus,
     pub cmd: String,
     pub output: String,
+    pub duration: Duration,
+    /// Results of the test-reports declared by the step
+    #[serde(default)]
+    pub tests: Option<reports::ingest::TestSummary>,
+    /// Limit the step was failed for exceeding
+    #[serde(default)]
+    pub limit: Option<limits::Limit>,
+}
+
+impl BuildStepResult {
+    /// Status of the step, with the limit it exceeded if any
+    pub fn status_description(&self) -> String {
+        match self.limit {
+            Some(limit) => format!("{:?} ({})", self.status, limit),
+            None => format!("{:?}", self.status),
+        }
+    }
 }
 
 /// Status of a `BuildStepResult`
@@ -318,6 +1207,10 @@ pub struct BuildStepResult {
 pub enum BuildStatus {
     Successful,
     Failed,
+    /// Waiting for its turn to be built
+    Queued,
+    /// Waiting for a maintainer to approve the build of an untrusted request
+    AwaitingApproval,
     InProgress,
     Stopped,
 }
@@ -325,6 +1218,95 @@ pub enum BuildStatus {
 
 #[cfg(test)]
 mod tests {
+
+    use std::collections::BTreeMap;
+
+    use toml;
+
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    use {BuildRequest, PullRequest, Runner, add_git_config};
+    use integrations::Integrations;
+    use utils::RepoConfig;
+    use utils::tests::TEST_DIR;
+
     #[test]
     fn it_works() {}
+
+    #[test]
+    fn test_is_trusted() {
+        let repo_conf = |trusted: &str| -> RepoConfig {
+            toml::from_str(&format!(
+                "integration = \"bitbucket\"\nowner = \"superman\"\n\
+                 reponame = \"linux\"\napi_token = \"x\"\n\
+                 build_instruction = {{ steps = [] }}\n{}",
+                trusted
+            )).unwrap()
+        };
+        let request = |actor: Option<&str>, fork: Option<&str>| {
+            let mut req = BuildRequest::new(
+                Integrations::Bitbucket,
+                "superman".into(),
+                "linux".into(),
+                "709d658dc5b6".into(),
+                Some("feature".into()),
+            ).unwrap();
+            req.actor = actor.map(|a| a.into());
+            req.pull_request = Some(PullRequest {
+                id: 1,
+                source_branch: "feature".into(),
+                target_branch: "master".into(),
+                fork: fork.map(|f| f.into()),
+            });
+            req
+        };
+        let open = repo_conf("");
+        assert!(request(Some("lex"), None).is_trusted(&open));
+        assert!(!request(Some("lex"), Some("lex/linux")).is_trusted(&open));
+
+        let allowlist = repo_conf("trusted = [\"clark\", \"dailyplanet\"]");
+        assert!(request(Some("clark"), Some("clark/linux")).is_trusted(&allowlist));
+        assert!(request(Some("lois"), Some("dailyplanet/linux")).is_trusted(&allowlist));
+        assert!(!request(Some("lex"), None).is_trusted(&allowlist));
+        assert!(!request(Some("lex"), Some("lex/linux")).is_trusted(&allowlist));
+        // A trusted user updating an untrusted fork's pull-request does not make it trusted
+        assert!(!request(Some("clark"), Some("lex/linux")).is_trusted(&allowlist));
+        assert!(!request(None, Some("lex/linux")).is_trusted(&allowlist));
+    }
+
+    #[test]
+    fn test_collect_build_dirs() {
+        let root = TEST_DIR.join("test_collect_build_dirs");
+        let _ = remove_dir_all(&root);
+        let (common, _lock) = Runner::lock_build_dir(&root, "common").unwrap();
+        let (held, _held_lock) = Runner::lock_build_dir(&root, "common").unwrap();
+        let (unused, unused_lock) = Runner::lock_build_dir(&root, "common").unwrap();
+        for dir in &[&common, &held, &unused] {
+            create_dir_all(dir).unwrap();
+        }
+        drop(unused_lock);
+        Runner::collect_build_dirs(&root, "common", None).unwrap();
+        assert!(common.exists());
+        assert!(held.exists());
+        assert!(!unused.exists());
+        assert!(!root.join("builds").join("common-2.lock").exists());
+    }
+
+    #[test]
+    fn test_add_git_config() {
+        let mut env = BTreeMap::new();
+        add_git_config(&mut env, "safe.directory", "*");
+        assert_eq!(env["GIT_CONFIG_COUNT"], "1");
+        assert_eq!(env["GIT_CONFIG_KEY_0"], "safe.directory");
+
+        let mut env = BTreeMap::new();
+        env.insert("GIT_CONFIG_COUNT".into(), "1".into());
+        env.insert("GIT_CONFIG_KEY_0".into(), "core.autocrlf".into());
+        env.insert("GIT_CONFIG_VALUE_0".into(), "input".into());
+        add_git_config(&mut env, "safe.directory", "*");
+        assert_eq!(env["GIT_CONFIG_COUNT"], "2");
+        assert_eq!(env["GIT_CONFIG_KEY_0"], "core.autocrlf");
+        assert_eq!(env["GIT_CONFIG_KEY_1"], "safe.directory");
+        assert_eq!(env["GIT_CONFIG_VALUE_1"], "*");
+    }
 }
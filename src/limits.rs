@@ -0,0 +1,416 @@
+//! Limits on the resources of build-steps
+//!
+//! Most limits are rlimits, set in the process of the step before it is executed and
+//! inherited by its children. They apply to each process on its own. `output` is
+//! enforced by the runner, which kills a step writing more.
+//!
+//! `memory`, `cpus` and `processes` apply to the step as a whole through a cgroup v2 of
+//! its own. These need a cgroup delegated to rupert, `meta.cgroup`, under which the
+//! cgroups of the steps are created. Only a step running as a `user` of its own gets
+//! `processes` as an rlimit without one: the rlimit counts all processes of the user,
+//! so for rupert's user it would count rupert's own too. If rupert itself runs in the
+//! delegated cgroup, it moves into a child `rupert` of it first, as only cgroups
+//! without processes can limit their children.
+//!
+//! Whether a failed step exceeded a limit is told by the signal it was killed with for
+//! `cpu_time` and `file_size`, and by the counters of its cgroup for `memory` and
+//! `processes`. Steps failing because of other rlimits, whose calls fail rather than
+//! being killed, are not told apart from other failures, nor are steps of containers
+//! exceeding their `memory`.
+
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{File, OpenOptions, create_dir_all, remove_dir};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, ExitStatus};
+use std::thread::sleep;
+use std::time::Duration;
+
+use libc;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+
+use errors::*;
+
+/// Limits of a repository or step, those of a step override the ones of its repository
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// CPU-time of each process, in seconds
+    pub cpu_time: Option<u64>,
+    /// Virtual memory of each process
+    pub address_space: Option<Size>,
+    /// Open files of each process
+    pub open_files: Option<u64>,
+    /// Processes of the step in its cgroup, or of the user running it
+    pub processes: Option<u64>,
+    /// Size of each file written
+    pub file_size: Option<Size>,
+    /// Output of the step, stdout and stderr together
+    pub output: Option<Size>,
+    /// Memory of the step as a whole, swap included
+    pub memory: Option<Size>,
+    /// CPUs the step as a whole may use, e.g. `1.5`
+    pub cpus: Option<f64>,
+}
+
+/// An amount of bytes, given as a number or a string with a suffix of `K`, `M` or `G`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Size(pub u64);
+
+/// A limit exceeded by a step
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    CpuTime,
+    AddressSpace,
+    OpenFiles,
+    Processes,
+    FileSize,
+    Output,
+    Memory,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Limit::CpuTime => "cpu_time",
+            Limit::AddressSpace => "address_space",
+            Limit::OpenFiles => "open_files",
+            Limit::Processes => "processes",
+            Limit::FileSize => "file_size",
+            Limit::Output => "output",
+            Limit::Memory => "memory",
+        };
+        write!(f, "{} limit exceeded", name)
+    }
+}
+
+impl Limits {
+    /// These limits, overridden by the ones set in `other`
+    pub fn merge(&self, other: &Limits) -> Limits {
+        Limits {
+            cpu_time: other.cpu_time.or(self.cpu_time),
+            address_space: other.address_space.or(self.address_space),
+            open_files: other.open_files.or(self.open_files),
+            processes: other.processes.or(self.processes),
+            file_size: other.file_size.or(self.file_size),
+            output: other.output.or(self.output),
+            memory: other.memory.or(self.memory),
+            cpus: other.cpus.or(self.cpus),
+        }
+    }
+
+    /// Whether a cgroup is needed for these limits
+    pub fn need_cgroup(&self) -> bool {
+        self.memory.is_some() || self.cpus.is_some() || self.processes.is_some()
+    }
+
+    /// Set the rlimits on the process of `command` and move it into the cgroup at
+    /// `cgroup`, if any. `processes` is left to the cgroup if there is one.
+    pub fn confine(&self, command: &mut Command, cgroup: Option<&PathBuf>) {
+        let processes = match cgroup {
+            Some(_) => None,
+            None => self.processes,
+        };
+        // At the soft limit of CPU-time the process gets SIGXCPU, at the hard one SIGKILL
+        let rlimits = [
+            (libc::RLIMIT_CPU, self.cpu_time.map(|s| (s, s + 1))),
+            (libc::RLIMIT_AS, self.address_space.map(|s| (s.0, s.0))),
+            (libc::RLIMIT_NOFILE, self.open_files.map(|n| (n, n))),
+            (libc::RLIMIT_NPROC, processes.map(|n| (n, n))),
+            (libc::RLIMIT_FSIZE, self.file_size.map(|s| (s.0, s.0))),
+        ];
+        let procs = cgroup.map(|path| {
+            CString::new(path.join("cgroup.procs").as_os_str().as_bytes())
+                .expect("Path of cgroup contains NUL")
+        });
+        let confine = move || {
+            for &(resource, limit) in &rlimits {
+                if let Some((soft, hard)) = limit {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: soft as libc::rlim_t,
+                        rlim_max: hard as libc::rlim_t,
+                    };
+                    if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+            if let Some(ref procs) = procs {
+                // Writing 0 moves the writing process
+                let written = unsafe {
+                    let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    if fd < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+                    libc::close(fd);
+                    written
+                };
+                if written != 1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        };
+        // Only async-signal-safe calls are made between fork and exec
+        unsafe {
+            command.pre_exec(confine);
+        }
+    }
+
+    /// Arguments to `podman run` or `docker run` setting these limits on the container
+    pub fn container_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let ulimits = [
+            ("cpu", self.cpu_time.map(|s| (s, s + 1))),
+            ("as", self.address_space.map(|s| (s.0, s.0))),
+            ("nofile", self.open_files.map(|n| (n, n))),
+            ("fsize", self.file_size.map(|s| (s.0, s.0))),
+        ];
+        for &(name, limit) in &ulimits {
+            if let Some((soft, hard)) = limit {
+                args.push(format!("--ulimit={}={}:{}", name, soft, hard));
+            }
+        }
+        if let Some(processes) = self.processes {
+            args.push(format!("--pids-limit={}", processes));
+        }
+        if let Some(memory) = self.memory {
+            args.push(format!("--memory={}", memory.0));
+            args.push(format!("--memory-swap={}", memory.0));
+        }
+        if let Some(cpus) = self.cpus {
+            args.push(format!("--cpus={}", cpus));
+        }
+        args
+    }
+
+    /// The limit a failed step, run in `cgroup` if any, exceeded by exiting with
+    /// `status`, as far as can be told
+    pub fn exceeded(&self, status: &ExitStatus, cgroup: Option<&Cgroup>) -> Option<Limit> {
+        let signal = status.signal();
+        let oom_killed = cgroup.is_some_and(Cgroup::oom_killed);
+        if self.cpu_time.is_some() && signal == Some(libc::SIGXCPU) {
+            Some(Limit::CpuTime)
+        } else if self.file_size.is_some() && signal == Some(libc::SIGXFSZ) {
+            Some(Limit::FileSize)
+        } else if self.memory.is_some() && oom_killed {
+            Some(Limit::Memory)
+        } else if self.processes.is_some() && cgroup.is_some_and(Cgroup::forks_refused) {
+            Some(Limit::Processes)
+        } else {
+            None
+        }
+    }
+}
+
+/// The cgroup of a step, its processes are killed and it is removed when dropped
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Create the cgroup `name` below the cgroup `parent` delegated to rupert, with the
+    /// `memory`, `cpus` and `processes` of `limits`
+    pub fn create(parent: &Path, name: &str, limits: &Limits) -> Result<Cgroup> {
+        delegate(parent).chain_err(|| {
+            format!("Failed delegating cgroup {:?} to the steps", parent)
+        })?;
+        let path = parent.join(name);
+        create_dir_all(&path).chain_err(
+            || format!("Failed creating cgroup {:?}", path),
+        )?;
+        let cgroup = Cgroup { path };
+        if let Some(memory) = limits.memory {
+            cgroup.write("memory.max", &memory.0.to_string())?;
+            // Not there without swap-accounting
+            let _ = cgroup.write("memory.swap.max", "0");
+        }
+        if let Some(cpus) = limits.cpus {
+            let period = 100_000;
+            let quota = (cpus * period as f64) as u64;
+            cgroup.write("cpu.max", &format!("{} {}", quota, period))?;
+        }
+        if let Some(processes) = limits.processes {
+            cgroup.write("pids.max", &processes.to_string())?;
+        }
+        Ok(cgroup)
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Whether a process of the step was killed for exceeding `memory`
+    pub fn oom_killed(&self) -> bool {
+        self.counted("memory.events", "oom_kill")
+    }
+
+    /// Whether a process of the step failed to fork for exceeding `processes`
+    pub fn forks_refused(&self) -> bool {
+        self.counted("pids.events", "max")
+    }
+
+    /// Whether the counter `name` in the events-file `file` is above zero
+    fn counted(&self, file: &str, name: &str) -> bool {
+        let mut events = String::new();
+        let res = File::open(self.path.join(file)).and_then(
+            |mut f| f.read_to_string(&mut events),
+        );
+        if let Err(e) = res {
+            warn!("Failed reading {} of cgroup {:?}: {}", file, self.path, e);
+        }
+        events.lines().any(|line| {
+            let mut fields = line.split_whitespace();
+            fields.next() == Some(name) && fields.next().is_some_and(|n| n != "0")
+        })
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<()> {
+        write_file(&self.path.join(file), value).chain_err(|| {
+            format!("Failed setting {} of cgroup {:?} to {}", file, self.path, value)
+        })
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Left-over processes, not in the process-group of the step, are killed first
+        let _ = write_file(&self.path.join("cgroup.kill"), "1");
+        for _ in 0..50 {
+            if remove_dir(&self.path).is_ok() {
+                return;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        warn!("Failed removing cgroup {:?}, it still has processes", self.path);
+    }
+}
+
+/// Enable the memory-, cpu- and pids-controllers for the children of `cgroup`, moving rupert
+/// into a child of its own if it is in `cgroup`
+fn delegate(cgroup: &Path) -> io::Result<()> {
+    let mut own = String::new();
+    File::open("/proc/self/cgroup")?.read_to_string(
+        &mut own,
+    )?;
+    let own = own.lines()
+        .find(|line| line.starts_with("0::"))
+        .map(|line| Path::new("/sys/fs/cgroup").join(line[3..].trim_start_matches('/')));
+    if own.as_deref() == Some(cgroup) {
+        let leaf = cgroup.join("rupert");
+        create_dir_all(&leaf)?;
+        write_file(&leaf.join("cgroup.procs"), &process::id().to_string())?;
+    }
+    write_file(&cgroup.join("cgroup.subtree_control"), "+memory +cpu +pids")
+}
+
+fn write_file(path: &Path, value: &str) -> io::Result<()> {
+    OpenOptions::new().write(true).open(path)?.write_all(
+        value.as_bytes(),
+    )
+}
+
+impl<'de> Deserialize<'de> for Size {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Size, D::Error> {
+        struct SizeVisitor;
+
+        impl<'de> Visitor<'de> for SizeVisitor {
+            type Value = Size;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a number of bytes, or a string like \"512M\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> ::std::result::Result<Size, E> {
+                Ok(Size(v))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> ::std::result::Result<Size, E> {
+                if v < 0 {
+                    return Err(E::custom("size is negative"));
+                }
+                Ok(Size(v as u64))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> ::std::result::Result<Size, E> {
+                let v = v.trim();
+                let (number, factor) = match v.chars().last() {
+                    Some('K') | Some('k') => (&v[..v.len() - 1], 1 << 10),
+                    Some('M') | Some('m') => (&v[..v.len() - 1], 1 << 20),
+                    Some('G') | Some('g') => (&v[..v.len() - 1], 1 << 30),
+                    _ => (v, 1),
+                };
+                let number = number.trim().parse::<u64>().map_err(|_| {
+                    E::custom(format!("invalid size {:?}", v))
+                })?;
+                number.checked_mul(factor).map(Size).ok_or_else(|| {
+                    E::custom(format!("size {:?} is too large", v))
+                })
+            }
+        }
+
+        deserializer.deserialize_any(SizeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::process::Command;
+
+    use toml;
+
+    use limits::{Limit, Limits, Size};
+
+    #[test]
+    fn test_limits() {
+        let repo: Limits = toml::from_str("cpu_time = 1\noutput = \"1K\"").unwrap();
+        let step: Limits = toml::from_str("address_space = 268435456\noutput = \"2M\"")
+            .unwrap();
+        let limits = repo.merge(&step);
+        assert_eq!(limits.cpu_time, Some(1));
+        assert_eq!(limits.address_space, Some(Size(256 << 20)));
+        assert_eq!(limits.output, Some(Size(2 << 20)));
+        assert!(!limits.need_cgroup());
+        let processes = Limits {
+            processes: Some(64),
+            ..Default::default()
+        };
+        assert!(processes.need_cgroup());
+        assert_eq!(processes.container_args(), ["--pids-limit=64"]);
+        assert!(toml::from_str::<Limits>("memory = \"lots\"").is_err());
+        assert!(toml::from_str::<Limits>("memory = \"17179869184G\"").is_err());
+
+        // A busy loop gets SIGXCPU after a second of CPU-time
+        let mut command = Command::new("bash");
+        command.args(["-c", "while true; do :; done"]);
+        limits.confine(&mut command, None);
+        let status = command.status().unwrap();
+        assert_eq!(limits.exceeded(&status, None), Some(Limit::CpuTime));
+
+        let mut command = Command::new("bash");
+        command.args(["-c", "head -c 1M /dev/zero"]);
+        let limits = Limits {
+            address_space: Some(Size(64 << 20)),
+            ..Default::default()
+        };
+        limits.confine(&mut command, None);
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(limits.exceeded(&output.status, None), None);
+
+        let limits = Limits {
+            memory: Some(Size(64 << 20)),
+            ..Default::default()
+        };
+        // Exit-codes are not taken for signals, nor is memory told apart without a cgroup
+        let status = Command::new("bash").args(["-c", "exit 137"]).status().unwrap();
+        assert_eq!(limits.exceeded(&status, None), None);
+        let status = Command::new("bash").args(["-c", "exit 1"]).status().unwrap();
+        assert_eq!(limits.exceeded(&status, None), None);
+    }
+}
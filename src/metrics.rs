@@ -0,0 +1,319 @@
+//! Metrics of the server, rendered in Prometheus' text-format on `/metrics`
+//!
+//! Metrics are process-wide statics updated where the measured work happens.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs::{read_dir, symlink_metadata};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Label-values of a time-series, in the order of the metric's label-names
+type LabelValues = Vec<String>;
+
+pub static BUILDS_QUEUED: Gauge = Gauge::new(
+    "rupert_builds_queued",
+    "Builds waiting for their turn",
+);
+pub static BUILDS_RUNNING: Gauge = Gauge::new(
+    "rupert_builds_running",
+    "Builds currently running",
+);
+pub static BUILDS: Counter = Counter::new(
+    "rupert_builds_total",
+    "Finished builds",
+    &["repo", "status"],
+);
+pub static BUILD_DURATION: Histogram = Histogram::new(
+    "rupert_build_duration_seconds",
+    "Time spent executing the build-steps of a build",
+    &["repo"],
+    &[1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0],
+);
+pub static STEP_DURATION: Histogram = Histogram::new(
+    "rupert_step_duration_seconds",
+    "Time spent executing a single build-step",
+    &["repo"],
+    &[0.1, 1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0],
+);
+pub static GIT_DURATION: Histogram = Histogram::new(
+    "rupert_git_duration_seconds",
+    "Time spent cloning and fetching repositories",
+    &["repo", "operation"],
+    &[0.1, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0],
+);
+pub static WEBHOOK_REQUESTS: Counter = Counter::new(
+    "rupert_webhook_requests_total",
+    "Received webhook-requests by outcome",
+    &["integration", "outcome"],
+);
+static DISK_USAGE: Mutex<Option<(Instant, u64)>> = Mutex::new(None);
+
+/// How long a measured disk-usage is reused, walking `build_root` is expensive
+const DISK_USAGE_MAX_AGE: Duration = Duration::from_secs(300);
+
+/// A value going up and down
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicIsize,
+}
+
+impl Gauge {
+    const fn new(name: &'static str, help: &'static str) -> Gauge {
+        Gauge {
+            name,
+            help,
+            value: AtomicIsize::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "gauge");
+        let _ = writeln!(out, "{} {}", self.name, self.value.load(Ordering::SeqCst));
+    }
+}
+
+/// A value only going up, per combination of labels
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<LabelValues, u64>>,
+}
+
+impl Counter {
+    const fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+    ) -> Counter {
+        Counter {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, label_values: &[&str]) {
+        let mut values = self.values.lock().expect("Metric poisoned");
+        *values.entry(owned(label_values)).or_insert(0) += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        for (label_values, value) in self.values.lock().expect("Metric poisoned").iter() {
+            let _ = writeln!(
+                out,
+                "{}{} {}",
+                self.name,
+                labels(self.labels, label_values, None),
+                value
+            );
+        }
+    }
+}
+
+/// Distribution of observed values, per combination of labels
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    buckets: &'static [f64],
+    values: Mutex<BTreeMap<LabelValues, HistogramValues>>,
+}
+
+#[derive(Default)]
+struct HistogramValues {
+    /// Observations per bucket, not cumulative
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    const fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+        buckets: &'static [f64],
+    ) -> Histogram {
+        Histogram {
+            name,
+            help,
+            labels,
+            buckets,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, label_values: &[&str], duration: Duration) {
+        let secs = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+        let mut values = self.values.lock().expect("Metric poisoned");
+        let values = values.entry(owned(label_values)).or_insert_with(|| {
+            HistogramValues {
+                buckets: vec![0; self.buckets.len()],
+                ..Default::default()
+            }
+        });
+        if let Some(i) = self.buckets.iter().position(|&le| secs <= le) {
+            values.buckets[i] += 1;
+        }
+        values.count += 1;
+        values.sum += secs;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        for (label_values, values) in self.values.lock().expect("Metric poisoned").iter() {
+            let mut cumulative = 0;
+            for (le, count) in self.buckets.iter().zip(&values.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    self.name,
+                    labels(self.labels, label_values, Some(&le.to_string())),
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                self.name,
+                labels(self.labels, label_values, Some("+Inf")),
+                values.count
+            );
+            let label_set = labels(self.labels, label_values, None);
+            let _ = writeln!(out, "{}_sum{} {}", self.name, label_set, values.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, label_set, values.count);
+        }
+    }
+}
+
+fn owned(label_values: &[&str]) -> LabelValues {
+    label_values.iter().map(|v| v.to_string()).collect()
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// `{name="value",...}`, with `le` added for histogram-buckets
+fn labels(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+/// All metrics, with the disk-usage of `build_root`
+pub fn render(build_root: &Path) -> String {
+    let mut out = String::new();
+    BUILDS_QUEUED.render(&mut out);
+    BUILDS_RUNNING.render(&mut out);
+    BUILDS.render(&mut out);
+    BUILD_DURATION.render(&mut out);
+    STEP_DURATION.render(&mut out);
+    GIT_DURATION.render(&mut out);
+    WEBHOOK_REQUESTS.render(&mut out);
+
+    header(
+        &mut out,
+        "rupert_build_root_bytes",
+        "Disk-usage of build_root, measured at most every 5 minutes",
+        "gauge",
+    );
+    let mut usage = DISK_USAGE.lock().expect("Metric poisoned");
+    let bytes = match *usage {
+        Some((measured, bytes)) if measured.elapsed() < DISK_USAGE_MAX_AGE => bytes,
+        _ => {
+            let bytes = disk_usage(build_root);
+            *usage = Some((Instant::now(), bytes));
+            bytes
+        }
+    };
+    let _ = writeln!(out, "rupert_build_root_bytes {}", bytes);
+    out
+}
+
+/// Size of the files below `path`, symlinks are not followed
+fn disk_usage(path: &Path) -> u64 {
+    let metadata = match symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return 0,
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    match read_dir(path) {
+        Ok(entries) => {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| disk_usage(&entry.path()))
+                .sum()
+        }
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+
+    use metrics::{Counter, Histogram};
+
+    #[test]
+    fn test_render() {
+        let counter = Counter::new("builds_total", "Builds", &["repo", "status"]);
+        counter.inc(&["superman/linux", "Failed"]);
+        counter.inc(&["superman/linux", "Failed"]);
+        let mut out = String::new();
+        counter.render(&mut out);
+        assert_eq!(
+            out,
+            "# HELP builds_total Builds\n# TYPE builds_total counter\n\
+             builds_total{repo=\"superman/linux\",status=\"Failed\"} 2\n"
+        );
+
+        let histogram = Histogram::new("duration_seconds", "Duration", &[], &[1.0, 10.0]);
+        histogram.observe(&[], Duration::from_millis(500));
+        histogram.observe(&[], Duration::from_secs(5));
+        histogram.observe(&[], Duration::from_secs(50));
+        let mut out = String::new();
+        histogram.render(&mut out);
+        assert!(out.contains("duration_seconds_bucket{le=\"1\"} 1\n"));
+        assert!(out.contains("duration_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(out.contains("duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("duration_seconds_sum 55.5\n"));
+        assert!(out.contains("duration_seconds_count 3\n"));
+    }
+}
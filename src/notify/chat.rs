@@ -0,0 +1,249 @@
+//! Chat-notifications posted to incoming webhooks
+//!
+//! The payload, `{"text": "..."}`, is understood by both Slack and Mattermost.
+
+use std::time::Duration;
+
+use serde_json;
+
+use errors::*;
+use history::BuildRecord;
+use notify::{BuildEvent, Notifier};
+use reports;
+use utils::{self, ChatConfig};
+use BuildStatus;
+
+/// Number of trailing output-lines of the failing step included in messages
+const TAIL_LINES: usize = 10;
+
+/// Attempts to deliver a message before giving up
+const ATTEMPTS: u32 = 3;
+
+/// Used unless a repository configures its own template
+pub const DEFAULT_TEMPLATE: &str = "{icon} *{owner}/{reponame}* `{branch}` build #{id} \
+{event} at `{short_commit}` by {author}: {description}{details}{link}";
+
+pub struct ChatNotifier {
+    pub chat: ChatConfig,
+    /// Where the dashboard is served, for linking to the logs
+    pub public_url: Option<String>,
+}
+
+impl Notifier for ChatNotifier {
+    fn notify(&self, event: BuildEvent, record: &BuildRecord) -> Result<()> {
+        if !self.chat.events.contains(&event) {
+            return Ok(());
+        }
+        let template = self.chat.template.as_deref().unwrap_or(DEFAULT_TEMPLATE);
+        let text = render(template, event, record, self.public_url.as_ref());
+        let payload = json!({ "text": text });
+        let body = serde_json::to_vec(&payload).chain_err(
+            || "Failed serializing message",
+        )?;
+        utils::retry(ATTEMPTS, Duration::from_secs(1), || {
+            utils::http::post(
+                &self.chat.url,
+                &[("Content-Type", "application/json")],
+                &body,
+            )
+        }).map(|_| ())
+    }
+}
+
+/// Fill in the placeholders of `template`.
+///
+/// Available placeholders are `{owner}`, `{reponame}`, `{branch}`, `{id}`, `{commit}`,
+/// `{short_commit}`, `{author}`, `{event}`, `{icon}`, `{description}`, `{failed_step}`,
+/// `{output_tail}`, `{details}` (failing step and its output-tail as a code-block),
+/// `{url}` and `{link}` (a link to the logs).
+pub fn render(
+    template: &str,
+    event: BuildEvent,
+    record: &BuildRecord,
+    public_url: Option<&String>,
+) -> String {
+    let failed_step = record
+        .result
+        .as_ref()
+        .and_then(|res| res.steps.iter().find(|s| s.status != BuildStatus::Successful));
+    let (failed_cmd, output_tail) = match failed_step {
+        Some(step) => {
+            let tail = reports::tail(&utils::strip_ansi(&step.output), TAIL_LINES).join("\n");
+            (step.cmd.clone(), tail)
+        }
+        None => (String::new(), String::new()),
+    };
+    let details = match failed_step {
+        Some(_) => format!("\nFailing step `{}`:\n```\n{}\n```", failed_cmd, output_tail),
+        None => String::new(),
+    };
+    let url = public_url.map(|u| record.url(u)).unwrap_or_default();
+    let link = if url.is_empty() {
+        String::new()
+    } else {
+        format!("\n<{}|Logs>", url)
+    };
+    let icon = match event {
+        BuildEvent::Started => ":arrow_forward:",
+        BuildEvent::Failed => ":x:",
+        BuildEvent::Fixed => ":white_check_mark:",
+    };
+    let author = record.author.as_ref().map(|a| a.name.clone()).unwrap_or(
+        "unknown".into(),
+    );
+
+    let vars = [
+        ("owner", record.owner.clone()),
+        ("reponame", record.reponame.clone()),
+        ("branch", record.branch.clone().unwrap_or("no branch".into())),
+        ("id", record.id.to_string()),
+        ("commit", record.commit.clone()),
+        ("short_commit", record.short_commit().to_owned()),
+        ("author", author),
+        ("event", event.verb().to_owned()),
+        ("icon", icon.to_owned()),
+        ("description", record.description()),
+        ("failed_step", failed_cmd),
+        ("output_tail", output_tail),
+        ("details", details),
+        ("url", url),
+        ("link", link),
+    ];
+    fill(template, &vars)
+}
+
+/// Replace the placeholders `{key}` in `template` by their value in `vars`, in a single
+/// pass so placeholders in the values are left as they are. Unknown ones are kept.
+fn fill(template: &str, vars: &[(&str, String)]) -> String {
+    let mut text = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let key = &rest[1..end];
+            vars.iter().find(|&&(k, _)| k == key).map(|(_, v)| (v, end))
+        });
+        match value {
+            Some((value, end)) => {
+                text.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                text.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use history::{Author, BuildRecord};
+    use notify::{BuildEvent, Notifier};
+    use notify::chat::{ChatNotifier, DEFAULT_TEMPLATE, fill, render};
+    use reports::BuildReports;
+    use utils::ChatConfig;
+    use {BuildResult, BuildStatus, BuildStepResult};
+
+    fn record() -> BuildRecord {
+        BuildRecord {
+            id: 7,
+            owner: "purew".into(),
+            reponame: "foobar".into(),
+            commit: "709d658dc5b6d6afcd46049c2f332ee3f515a67d".into(),
+            branch: Some("main".into()),
+            pull_request: None,
+            author: Some(Author {
+                name: "Anders".into(),
+                email: "anders@example.com".into(),
+            }),
+            status: BuildStatus::Failed,
+            started: 0,
+            finished: Some(1),
+            result: Some(BuildResult {
+                steps: vec![
+                    BuildStepResult {
+                        status: BuildStatus::Failed,
+                        cmd: "make test".into(),
+                        output: "compiling\nerror: oops\n".into(),
+                        duration: Duration::from_secs(1),
+                        tests: None,
+                        limit: None,
+                    },
+                ],
+                reports: BuildReports {
+                    junit: "junit.xml".into(),
+                    markdown: "summary.md".into(),
+                },
+            }),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let url = "https://ci.example.com/".to_owned();
+        let text = render(DEFAULT_TEMPLATE, BuildEvent::Failed, &record(), Some(&url));
+        assert_eq!(
+            text,
+            ":x: *purew/foobar* `main` build #7 failed at `709d658` by Anders: \
+             Failed at `make test`\nFailing step `make test`:\n```\ncompiling\nerror: oops\n```\n\
+             <https://ci.example.com/repos/purew/foobar/builds/7|Logs>"
+        );
+        let text = render("{event} {failed_step} {link}", BuildEvent::Fixed, &record(), None);
+        assert_eq!(text, "fixed make test ");
+    }
+
+    #[test]
+    fn test_fill() {
+        let vars = [("author", "{url}".to_owned()), ("url", "https://ci".to_owned())];
+        assert_eq!(fill("{author} at {url}", &vars), "{url} at https://ci");
+        assert_eq!(fill("{unknown} {{author}}", &vars), "{unknown} {{url}}");
+        assert_eq!(fill("{author", &vars), "{author");
+    }
+
+    #[test]
+    fn test_retry_delivery() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for reply in &["500 Internal Server Error", "200 OK"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                // Read until the JSON-body is complete
+                while !request.ends_with(b"}") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                bodies.push(String::from_utf8(request).unwrap());
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", reply).unwrap();
+            }
+            bodies
+        });
+        let notifier = ChatNotifier {
+            chat: ChatConfig {
+                url: format!("http://127.0.0.1:{}/hook", port),
+                template: Some("{owner} {event}".into()),
+                events: vec![BuildEvent::Failed],
+            },
+            public_url: None,
+        };
+        notifier.notify(BuildEvent::Fixed, &record()).unwrap();
+        notifier.notify(BuildEvent::Failed, &record()).unwrap();
+        let bodies = handle.join().unwrap();
+        assert_eq!(bodies.len(), 2);
+        assert!(bodies[1].ends_with(r#"{"text":"purew failed"}"#));
+    }
+}
@@ -0,0 +1,218 @@
+//! Email-notifications sent through an SMTP-relay
+//!
+//! Only plain SMTP is spoken, authentication and TLS are left to the relay, which is
+//! expected to be local or otherwise trusted.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use errors::*;
+use history::BuildRecord;
+use notify::{self, BuildEvent, Notifier};
+use utils::SmtpConfig;
+
+/// Mails the commit-author and the configured recipients of a repository
+pub struct EmailNotifier {
+    pub smtp: SmtpConfig,
+    pub recipients: Vec<String>,
+}
+
+impl Notifier for EmailNotifier {
+    fn notify(&self, event: BuildEvent, record: &BuildRecord) -> Result<()> {
+        // Mails are only about the outcome of builds
+        if event == BuildEvent::Started {
+            return Ok(());
+        }
+        let mut recipients = self.recipients.clone();
+        if let Some(ref author) = record.author {
+            if !author.email.is_empty() && !recipients.contains(&author.email) {
+                recipients.push(author.email.clone());
+            }
+        }
+        if recipients.is_empty() {
+            return Ok(());
+        }
+        let (subject, body) = notify::render_text(event, record);
+        send_mail(&self.smtp, &recipients, &subject, &body)
+    }
+}
+
+/// Send a plain-text mail through the relay in `smtp`
+pub fn send_mail(smtp: &SmtpConfig, to: &[String], subject: &str, body: &str) -> Result<()> {
+    info!("Mailing {:?} through {}:{}", to, smtp.host, smtp.port);
+    let stream = TcpStream::connect((smtp.host.as_str(), smtp.port))
+        .chain_err(|| format!("Failed connecting to {}:{}", smtp.host, smtp.port))?;
+    stream
+        .set_read_timeout(Some(Duration::from_secs(60)))
+        .chain_err(|| "Failed setting timeout")?;
+    let mut session = Session {
+        reader: BufReader::new(stream.try_clone().chain_err(|| "Failed cloning stream")?),
+        writer: stream,
+    };
+
+    session.expect(220)?;
+    session.command("EHLO rupert", 250)?;
+    session.command(&format!("MAIL FROM:<{}>", header_safe(&smtp.from)), 250)?;
+    for recipient in to {
+        session.command(&format!("RCPT TO:<{}>", header_safe(recipient)), 250)?;
+    }
+    session.command("DATA", 354)?;
+
+    let mut message = String::new();
+    message += &format!("From: {}\r\n", header_safe(&smtp.from));
+    message += &format!("To: {}\r\n", header_safe(&to.join(", ")));
+    message += &format!("Subject: {}\r\n", header_safe(subject));
+    message += "MIME-Version: 1.0\r\n";
+    message += "Content-Type: text/plain; charset=utf-8\r\n";
+    message += "Content-Transfer-Encoding: 8bit\r\n\r\n";
+    for line in body.lines() {
+        // Lines starting with "." are escaped as the lone "." ends the message
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message += line;
+        message += "\r\n";
+    }
+    message += ".";
+    session.command(&message, 250)?;
+    session.command("QUIT", 221)
+}
+
+/// Headers and addresses must not contain line-breaks or non-ASCII
+fn header_safe(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_control() { c } else { '?' })
+        .collect()
+}
+
+struct Session {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Session {
+    fn command(&mut self, line: &str, code: u16) -> Result<()> {
+        write!(self.writer, "{}\r\n", line).chain_err(
+            || "Failed writing to SMTP-server",
+        )?;
+        self.expect(code)
+    }
+
+    /// Read a, possibly multi-line, reply and verify its code
+    fn expect(&mut self, code: u16) -> Result<()> {
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).chain_err(
+                || "Failed reading from SMTP-server",
+            )?;
+            if line.len() < 4 {
+                bail!("Unexpected reply from SMTP-server: {:?}", line);
+            }
+            // "250-" continues the reply, "250 " ends it
+            if &line[3..4] == "-" {
+                continue;
+            }
+            let got: u16 = line[..3].parse().chain_err(|| {
+                format!("Unexpected reply from SMTP-server: {:?}", line)
+            })?;
+            if got != code && !(code == 250 && got == 251) {
+                bail!("SMTP-server replied {:?}, expected {}", line.trim(), code);
+            }
+            return Ok(());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use notify::email::send_mail;
+    use utils::SmtpConfig;
+
+    /// Accept a single session, replying `rcpt_reply` to recipients, and return the
+    /// received commands and message
+    fn smtp_sink(rcpt_reply: &'static str) -> (u16, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut received = Vec::new();
+            let mut in_data = false;
+            writer.write_all(b"220 sink ESMTP\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end_matches("\r\n").to_owned();
+                received.push(line.clone());
+                let reply: &[u8] = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-sink\r\n250 8BITMIME\r\n"
+                } else if line.starts_with("RCPT") {
+                    rcpt_reply.as_bytes()
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).unwrap();
+            }
+            received
+        });
+        (port, handle)
+    }
+
+    fn smtp(port: u16) -> SmtpConfig {
+        SmtpConfig {
+            host: "127.0.0.1".into(),
+            port,
+            from: "rupert@example.com".into(),
+        }
+    }
+
+    #[test]
+    fn test_send_mail() {
+        let (port, handle) = smtp_sink("250 ok\r\n");
+        send_mail(
+            &smtp(port),
+            &["a@example.com".into(), "b@example.com".into()],
+            "Build failed\r\nBcc: evil@example.com",
+            "Step failed\n.hidden\n",
+        ).unwrap();
+        let received = handle.join().unwrap();
+        assert_eq!(received[0], "EHLO rupert");
+        assert_eq!(received[1], "MAIL FROM:<rupert@example.com>");
+        assert_eq!(received[2], "RCPT TO:<a@example.com>");
+        assert_eq!(received[3], "RCPT TO:<b@example.com>");
+        assert!(received.contains(&"Subject: Build failed??Bcc: evil@example.com".into()));
+        assert!(received.contains(&"..hidden".into()));
+        assert_eq!(received.last().unwrap(), "QUIT");
+    }
+
+    #[test]
+    fn test_send_mail_rejected() {
+        let (port, handle) = smtp_sink("550 no such user\r\n");
+        let res = send_mail(&smtp(port), &["a@example.com".into()], "Subject", "Body");
+        assert!(res.is_err());
+        drop(handle);
+    }
+}
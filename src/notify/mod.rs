@@ -0,0 +1,162 @@
+//! Notifications about builds changing the state of a branch
+//!
+//! Notifications are only sent when a branch starts failing or is fixed, as judged from
+//! the previous build of the same branch, to avoid notifying about every build.
+
+use errors::*;
+use history::BuildRecord;
+use reports;
+use utils::{Config, RepoConfig};
+use BuildStatus;
+
+pub mod chat;
+pub mod email;
+pub mod webhook;
+
+/// Events notified about
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildEvent {
+    /// A build is about to execute its build-steps
+    Started,
+    /// A build failed and the previous build of the branch did not
+    Failed,
+    /// A build succeeded and the previous build of the branch failed
+    Fixed,
+}
+
+impl BuildEvent {
+    /// The event, if any, of a finished build given the status of the previous build of
+    /// the same branch
+    pub fn from_transition(
+        previous: Option<&BuildStatus>,
+        current: &BuildStatus,
+    ) -> Option<BuildEvent> {
+        match (previous, current) {
+            (Some(&BuildStatus::Failed), &BuildStatus::Successful) => Some(BuildEvent::Fixed),
+            (Some(&BuildStatus::Failed), &BuildStatus::Failed) => None,
+            (_, &BuildStatus::Failed) => Some(BuildEvent::Failed),
+            _ => None,
+        }
+    }
+
+    pub fn verb(&self) -> &'static str {
+        match *self {
+            BuildEvent::Started => "started",
+            BuildEvent::Failed => "failed",
+            BuildEvent::Fixed => "fixed",
+        }
+    }
+}
+
+/// Something notifying people about builds
+pub trait Notifier {
+    fn notify(&self, event: BuildEvent, record: &BuildRecord) -> Result<()>;
+}
+
+/// Notify everyone configured for the repository.
+///
+/// Failing notifications are logged but never fail the build.
+pub fn notify(conf: &Config, repo_conf: &RepoConfig, event: BuildEvent, record: &BuildRecord) {
+    for notifier in notifiers(conf, repo_conf) {
+        if let Err(e) = notifier.notify(event, record) {
+            warn!(
+                "Failed notifying about build {} of {}/{}: {}",
+                record.id,
+                record.owner,
+                record.reponame,
+                e
+            );
+        }
+    }
+}
+
+fn notifiers(conf: &Config, repo_conf: &RepoConfig) -> Vec<Box<dyn Notifier>> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+    if let Some(ref smtp) = conf.meta.smtp {
+        notifiers.push(Box::new(email::EmailNotifier {
+            smtp: smtp.clone(),
+            recipients: repo_conf.notify_emails.clone(),
+        }));
+    }
+    for chat in &repo_conf.chat {
+        notifiers.push(Box::new(chat::ChatNotifier {
+            chat: chat.clone(),
+            public_url: conf.meta.public_url.clone(),
+        }));
+    }
+    notifiers
+}
+
+/// Subject and plain-text body describing `event`
+pub fn render_text(event: BuildEvent, record: &BuildRecord) -> (String, String) {
+    let branch = record.branch.as_deref().unwrap_or("no branch");
+    let subject = format!(
+        "[rupert] {}/{} ({}): build {} at {}",
+        record.owner,
+        record.reponame,
+        branch,
+        event.verb(),
+        record.short_commit()
+    );
+
+    let mut body = format!(
+        "Build #{} of {}/{} {}.\n\n",
+        record.id,
+        record.owner,
+        record.reponame,
+        event.verb()
+    );
+    body += &format!("Branch:  {}\n", branch);
+    body += &format!("Commit:  {}\n", record.commit);
+    if let Some(ref author) = record.author {
+        body += &format!("Author:  {} <{}>\n", author.name, author.email);
+    }
+    body += &format!("Result:  {}\n", record.description());
+    if let Some(ref result) = record.result {
+        body += "\nSteps:\n";
+        for step in &result.steps {
+            body += &format!(
+                "  {:<11} {:>8.2}s  {}\n",
+                step.status_description(),
+                reports::secs(&step.duration),
+                step.cmd
+            );
+        }
+        for step in &result.steps {
+            if step.status == BuildStatus::Successful {
+                continue;
+            }
+            body += &format!(
+                "\nLast {} lines of `{}`:\n\n",
+                reports::TAIL_LINES,
+                step.cmd
+            );
+            for line in reports::tail(&::utils::strip_ansi(&step.output), reports::TAIL_LINES) {
+                body += line;
+                body += "\n";
+            }
+        }
+    }
+    (subject, body)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use BuildStatus::*;
+    use notify::BuildEvent;
+
+    #[test]
+    fn test_from_transition() {
+        let event = |prev, cur| BuildEvent::from_transition(prev, &cur);
+        assert_eq!(event(None, Failed), Some(BuildEvent::Failed));
+        assert_eq!(event(Some(&Successful), Failed), Some(BuildEvent::Failed));
+        assert_eq!(event(Some(&Failed), Failed), None);
+        assert_eq!(event(Some(&Failed), Successful), Some(BuildEvent::Fixed));
+        assert_eq!(event(Some(&Successful), Successful), None);
+        assert_eq!(event(None, Successful), None);
+        assert_eq!(event(Some(&Failed), Stopped), None);
+    }
+}
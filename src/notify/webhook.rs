@@ -0,0 +1,318 @@
+//! Build-events forwarded as signed JSON to arbitrary webhooks
+//!
+//! The `BuildUpdates` of a build are posted as `update`-events and a `summary`-event
+//! with the `BuildRecord` is posted once the build is finished. The output of a step is
+//! not posted line by line but once with the result of the step, when it finished. The body is signed with
+//! HMAC-SHA256 using the secret of the subscription, `X-Rupert-Signature:
+//! sha256=<hex>`, so receivers can verify it came from rupert.
+//!
+//! Each subscription is delivered to by its own thread, in order, which is left to finish
+//! in the background once the build is done, so a slow or failing receiver never holds
+//! up the build. Deliveries are retried with exponential backoff
+//! and the outcome of each is appended to the delivery-log of the subscription,
+//! `<build_root>/<owner>/<repo>/webhooks/<name>.jsonl`.
+
+use std::fs::{OpenOptions, create_dir_all};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Sender, channel};
+use std::thread;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use serde_json::{self, Value};
+use sha2::Sha256;
+
+use errors::*;
+use history::BuildRecord;
+use utils::{self, BuildUpdates, Config, RepoConfig, WebhookConfig};
+use Runner;
+
+/// Attempts to deliver an event before giving up, with the delay doubling each time
+const ATTEMPTS: u32 = 5;
+const INITIAL_DELAY_MS: u64 = 500;
+
+/// An event as posted to subscribers
+#[derive(Serialize, Debug)]
+pub struct Envelope {
+    /// "update" or "summary"
+    pub event: &'static str,
+    /// Unique for each event, "<owner>/<repo>/<build>/<sequence>"
+    pub delivery: String,
+    pub owner: String,
+    pub reponame: String,
+    pub build: u64,
+    pub commit: String,
+    pub branch: Option<String>,
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub data: Value,
+}
+
+/// Entry in the delivery-log of a subscription
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeliveryLogEntry {
+    pub delivery: String,
+    pub event: String,
+    pub url: String,
+    pub timestamp: u64,
+    pub attempts: u32,
+    /// Response-code of the successful attempt
+    pub code: Option<u32>,
+    /// Error of the last attempt if all failed
+    pub error: Option<String>,
+}
+
+/// Forwards the events of a single build to the webhooks of its repository
+pub struct Webhooks {
+    record: BuildRecord,
+    sequence: u64,
+    subscribers: Vec<Sender<Envelope>>,
+}
+
+impl Webhooks {
+    pub fn new(conf: &Config, repo_conf: &RepoConfig, record: &BuildRecord) -> Webhooks {
+        let subscribers = repo_conf
+            .webhooks
+            .iter()
+            .map(|hook| {
+                let (tx, rx) = channel::<Envelope>();
+                let hook = hook.clone();
+                let log = path_log(
+                    &conf.meta.build_root,
+                    &record.owner,
+                    &record.reponame,
+                    &hook.name,
+                );
+                thread::spawn(move || for envelope in rx {
+                    deliver(&hook, &envelope, &log)
+                });
+                tx
+            })
+            .collect();
+        Webhooks {
+            record: record.clone(),
+            sequence: 0,
+            subscribers,
+        }
+    }
+
+    pub fn update(&mut self, update: &BuildUpdates) {
+        if !is_forwarded(update) {
+            return;
+        }
+        match serde_json::to_value(update) {
+            Ok(data) => self.send("update", data),
+            Err(e) => warn!("Failed serializing update for webhooks: {}", e),
+        }
+    }
+
+    /// Send the summary of the finished build, the deliveries finish in the background
+    pub fn finish(mut self, record: &BuildRecord) {
+        match serde_json::to_value(record) {
+            Ok(data) => self.send("summary", data),
+            Err(e) => warn!("Failed serializing summary for webhooks: {}", e),
+        }
+    }
+
+    fn send(&mut self, event: &'static str, data: Value) {
+        self.sequence += 1;
+        for tx in &self.subscribers {
+            let envelope = Envelope {
+                event,
+                delivery: format!(
+                    "{}/{}/{}/{}",
+                    self.record.owner,
+                    self.record.reponame,
+                    self.record.id,
+                    self.sequence
+                ),
+                owner: self.record.owner.clone(),
+                reponame: self.record.reponame.clone(),
+                build: self.record.id,
+                commit: self.record.commit.clone(),
+                branch: self.record.branch.clone(),
+                sequence: self.sequence,
+                timestamp: utils::timestamp(),
+                data: data.clone(),
+            };
+            if tx.send(envelope).is_err() {
+                warn!("Webhook-delivery has stopped");
+            }
+        }
+    }
+}
+
+/// Whether `update` is posted, the output of a step is part of its `StepFinished`
+fn is_forwarded(update: &BuildUpdates) -> bool {
+    !matches!(*update, BuildUpdates::StepNewOutput(_))
+}
+
+/// Post `envelope` to `hook`, retrying on failure, and log the outcome
+fn deliver(hook: &WebhookConfig, envelope: &Envelope, log: &Path) {
+    let body = match serde_json::to_vec(envelope) {
+        Ok(body) => body,
+        Err(e) => return warn!("Failed serializing event: {}", e),
+    };
+    let signature = format!("sha256={}", sign(hook.secret.as_bytes(), &body));
+    let mut attempts = 0;
+    let res = utils::retry(ATTEMPTS, Duration::from_millis(INITIAL_DELAY_MS), || {
+        attempts += 1;
+        utils::http::post(
+            &hook.url,
+            &[
+                ("Content-Type", "application/json"),
+                ("X-Rupert-Event", envelope.event),
+                ("X-Rupert-Delivery", &envelope.delivery),
+                ("X-Rupert-Signature", &signature),
+            ],
+            &body,
+        )
+    });
+    if let Err(ref e) = res {
+        warn!("Giving up delivery of {} to {}: {}", envelope.delivery, hook.name, e);
+    }
+    let entry = DeliveryLogEntry {
+        delivery: envelope.delivery.clone(),
+        event: envelope.event.into(),
+        url: hook.url.clone(),
+        timestamp: utils::timestamp(),
+        attempts,
+        code: res.as_ref().ok().map(|r| r.code),
+        error: res.err().map(|e| e.to_string()),
+    };
+    if let Err(e) = append_log(log, &entry) {
+        warn!("Failed writing delivery-log {:?}: {}", log, e);
+    }
+}
+
+fn append_log(path: &Path, entry: &DeliveryLogEntry) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).chain_err(|| {
+            format!("Failed creating {:?}", parent)
+        })?;
+    }
+    let mut line = serde_json::to_string(entry).chain_err(
+        || "Failed serializing log-entry",
+    )?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut f| f.write_all(line.as_bytes()))
+        .chain_err(|| format!("Failed appending to {:?}", path))
+}
+
+/// Hex-encoded HMAC-SHA256 of `body`
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Where the delivery-log of subscription `name` is kept
+pub fn path_log(rupert_root: &Path, owner: &str, reponame: &str, name: &str) -> PathBuf {
+    let path_root = Runner::path_root(rupert_root, owner, reponame);
+    Runner::subdir(&path_root, "webhooks").join(format!("{}.jsonl", name))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{File, remove_dir_all};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    use serde_json::{self, Value};
+
+    use notify::webhook::{self, DeliveryLogEntry, Envelope, is_forwarded, sign};
+    use utils::{BuildUpdates, TextOutput, WebhookConfig};
+    use utils::tests::TEST_DIR;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_is_forwarded() {
+        assert!(is_forwarded(&BuildUpdates::StepStarted("make".into())));
+        assert!(is_forwarded(&BuildUpdates::Finished));
+        assert!(!is_forwarded(
+            &BuildUpdates::StepNewOutput(TextOutput::Stdout("line".into())),
+        ));
+    }
+
+    #[test]
+    fn test_deliver() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for reply in &["503 Service Unavailable", "204 No Content"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                while !request.ends_with(b"}") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                requests.push(String::from_utf8(request).unwrap());
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", reply).unwrap();
+            }
+            requests
+        });
+
+        let log = TEST_DIR.join("test_deliver");
+        let _ = remove_dir_all(&log);
+        let log: PathBuf = log.join("deploy-bot.jsonl");
+        let hook = WebhookConfig {
+            name: "deploy-bot".into(),
+            url: format!("http://127.0.0.1:{}/events", port),
+            secret: "s3cret".into(),
+        };
+        let envelope = Envelope {
+            event: "summary",
+            delivery: "purew/foobar/1/3".into(),
+            owner: "purew".into(),
+            reponame: "foobar".into(),
+            build: 1,
+            commit: "709d658dc5b6d6afcd46049c2f332ee3f515a67d".into(),
+            branch: None,
+            sequence: 3,
+            timestamp: 0,
+            data: Value::Null,
+        };
+        webhook::deliver(&hook, &envelope, &log);
+
+        let requests = handle.join().unwrap();
+        let request = &requests[1];
+        let body = &request[request.find("\r\n\r\n").unwrap() + 4..];
+        let signature = format!("sha256={}", sign(b"s3cret", body.as_bytes()));
+        assert!(request.contains(&format!("X-Rupert-Signature: {}", signature)));
+        assert!(request.contains("X-Rupert-Delivery: purew/foobar/1/3"));
+        let received: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(received["event"], "summary");
+
+        let line = BufReader::new(File::open(&log).unwrap())
+            .lines()
+            .next()
+            .unwrap()
+            .unwrap();
+        let entry: DeliveryLogEntry = serde_json::from_str(&line).unwrap();
+        assert_eq!((entry.attempts, entry.code), (2, Some(204)));
+        assert!(entry.error.is_none());
+    }
+}
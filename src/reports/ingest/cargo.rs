@@ -0,0 +1,39 @@
+//! JSON test-output from libtest, `cargo test -- -Z unstable-options --format json`
+
+use serde_json::{self, Value};
+
+use reports::ingest::TestSummary;
+
+/// Parse events line by line, lines which are not test-events are ignored
+pub fn parse(contents: &str) -> TestSummary {
+    let mut summary = TestSummary::default();
+    for line in contents.lines() {
+        let event: Value = match serde_json::from_str(line) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        if event.get("type").and_then(Value::as_str) != Some("test") {
+            continue;
+        }
+        let name = event
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned();
+        match event.get("event").and_then(Value::as_str) {
+            Some("ok") => summary.passed += 1,
+            Some("ignored") => summary.skipped += 1,
+            Some("failed") | Some("timeout") => {
+                let message = event
+                    .get("stdout")
+                    .or(event.get("message"))
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_owned();
+                summary.fail(name, message)
+            }
+            _ => {}
+        }
+    }
+    summary
+}
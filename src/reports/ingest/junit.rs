@@ -0,0 +1,86 @@
+//! JUnit XML test-reports
+
+use xml::reader::{EventReader, XmlEvent};
+
+use errors::*;
+use reports::ingest::TestSummary;
+
+/// What has been seen of the `testcase` currently being parsed
+struct TestCase {
+    name: String,
+    failure: Option<String>,
+    skipped: bool,
+}
+
+pub fn parse(contents: &str) -> Result<TestSummary> {
+    let mut summary = TestSummary::default();
+    let mut current: Option<TestCase> = None;
+    let mut in_failure = false;
+    for event in EventReader::from_str(contents) {
+        match event.chain_err(|| "Invalid XML")? {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                let attr = |key: &str| {
+                    attributes
+                        .iter()
+                        .find(|a| a.name.local_name == key)
+                        .map(|a| a.value.clone())
+                };
+                match name.local_name.as_str() {
+                    "testcase" => {
+                        let name = attr("name").unwrap_or_default();
+                        let name = match attr("classname") {
+                            Some(class) => format!("{}.{}", class, name),
+                            None => name,
+                        };
+                        current = Some(TestCase {
+                            name,
+                            failure: None,
+                            skipped: false,
+                        });
+                    }
+                    "failure" | "error" => {
+                        if let Some(ref mut case) = current {
+                            case.failure = Some(attr("message").unwrap_or_default());
+                            in_failure = true;
+                        }
+                    }
+                    "skipped" => {
+                        if let Some(ref mut case) = current {
+                            case.skipped = true;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            XmlEvent::Characters(text) |
+            XmlEvent::CData(text) => {
+                if let (true, Some(&mut Some(ref mut failure))) =
+                    (in_failure, current.as_mut().map(|c| &mut c.failure))
+                {
+                    if !failure.is_empty() {
+                        failure.push('\n');
+                    }
+                    failure.push_str(text.trim());
+                }
+            }
+            XmlEvent::EndElement { name } => {
+                match name.local_name.as_str() {
+                    "failure" | "error" => in_failure = false,
+                    "testcase" => {
+                        match current.take() {
+                            Some(TestCase { name, failure: Some(msg), .. }) => {
+                                summary.fail(name, msg)
+                            }
+                            Some(TestCase { skipped: true, .. }) => summary.skipped += 1,
+                            Some(_) => summary.passed += 1,
+                            None => {}
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(summary)
+}
@@ -0,0 +1,229 @@
+//! Ingestion of test-results written by build-steps
+//!
+//! A `BuildStep` can declare reports produced by the test-framework it runs. These are
+//! parsed once the step is finished and summarized in its `BuildStepResult`.
+
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+
+use errors::*;
+use users;
+
+mod cargo;
+mod junit;
+mod tap;
+
+/// Longest failure-message kept per failing test
+const MAX_MESSAGE_LEN: usize = 4096;
+
+/// A test-report produced by a build-step
+#[derive(Clone, Deserialize, Debug)]
+pub struct TestReport {
+    /// Path of the report, relative to the build-dir
+    pub path: String,
+    pub format: TestReportFormat,
+}
+
+/// Formats of test-reports rupert understands
+#[derive(Clone, Copy, Deserialize, Debug)]
+pub enum TestReportFormat {
+    /// JUnit XML as written by most test-frameworks
+    #[serde(rename = "junit")]
+    JUnit,
+    /// Output of `cargo test -- -Z unstable-options --format json`
+    #[serde(rename = "cargo-json")]
+    CargoJson,
+    /// Test Anything Protocol
+    #[serde(rename = "tap")]
+    Tap,
+}
+
+/// Per-test results of a build-step
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct TestSummary {
+    pub passed: u64,
+    pub failed: u64,
+    pub skipped: u64,
+    pub failures: Vec<TestFailure>,
+}
+
+/// A single failing test
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TestFailure {
+    pub name: String,
+    pub message: String,
+}
+
+impl TestSummary {
+    /// Add up results from another report
+    pub fn merge(&mut self, other: TestSummary) {
+        self.passed += other.passed;
+        self.failed += other.failed;
+        self.skipped += other.skipped;
+        self.failures.extend(other.failures);
+    }
+
+    fn fail(&mut self, name: String, message: String) {
+        self.failed += 1;
+        let mut message = message.trim().to_owned();
+        if message.len() > MAX_MESSAGE_LEN {
+            let mut end = MAX_MESSAGE_LEN;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+            message += "...";
+        }
+        self.failures.push(TestFailure { name, message });
+    }
+}
+
+impl fmt::Display for TestSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} passed, {} failed", self.passed, self.failed)?;
+        if self.skipped > 0 {
+            write!(f, ", {} skipped", self.skipped)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the test-report at `path` within the build-dir `dir`, not following symlinks
+/// which the steps may have left there
+pub fn parse(dir: &Path, path: &Path, format: TestReportFormat) -> Result<TestSummary> {
+    let mut contents = String::new();
+    users::open_beneath(dir, path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .chain_err(|| format!("Failed reading test-report {:?}", path))?;
+    match format {
+        TestReportFormat::JUnit => junit::parse(&contents),
+        TestReportFormat::CargoJson => Ok(cargo::parse(&contents)),
+        TestReportFormat::Tap => Ok(tap::parse(&contents)),
+    }.chain_err(|| ErrorKind::ParseError(format!("Bad test-report {:?}", path)))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use reports::ingest::{TestFailure, TestSummary};
+    use reports::ingest::{cargo, junit, tap};
+
+    #[test]
+    fn test_parse_junit() {
+        let summary = junit::parse(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites>
+  <testsuite name="suite" tests="4">
+    <testcase name="a" classname="pkg.A"/>
+    <testcase name="b" classname="pkg.A">
+      <failure message="expected 1">assert failed</failure>
+    </testcase>
+    <testcase name="c" classname="pkg.A"><skipped/></testcase>
+    <testcase name="d"><error type="Panic"><![CDATA[boom]]></error></testcase>
+  </testsuite>
+</testsuites>"#,
+        ).unwrap();
+        assert_eq!(summary.to_string(), "1 passed, 2 failed, 1 skipped");
+        assert_eq!(
+            summary.failures,
+            vec![
+                TestFailure {
+                    name: "pkg.A.b".into(),
+                    message: "expected 1\nassert failed".into(),
+                },
+                TestFailure {
+                    name: "d".into(),
+                    message: "boom".into(),
+                },
+            ]
+        );
+        assert!(junit::parse("<testsuite><testcase>").is_err());
+    }
+
+    #[test]
+    fn test_parse_cargo_json() {
+        let summary = cargo::parse(
+            r#"{ "type": "suite", "event": "started", "test_count": 3 }
+{ "type": "test", "event": "started", "name": "a" }
+{ "type": "test", "name": "a", "event": "ok" }
+{ "type": "test", "name": "b", "event": "failed", "stdout": "panicked at 'oops'\n" }
+{ "type": "test", "name": "c", "event": "ignored" }
+running 3 tests
+{ "type": "suite", "event": "failed", "passed": 1, "failed": 1, "ignored": 1 }"#,
+        );
+        assert_eq!(summary.to_string(), "1 passed, 1 failed, 1 skipped");
+        assert_eq!(summary.failures[0].name, "b");
+        assert_eq!(summary.failures[0].message, "panicked at 'oops'");
+    }
+
+    #[test]
+    fn test_parse_tap() {
+        let summary = tap::parse(
+            "TAP version 13
+1..5
+ok 1 - first
+not ok 2 - second
+  ---
+  message: 'oops'
+  ...
+ok 3 # SKIP no network
+not ok 4 - later # TODO not done
+# diagnostic for nothing
+not ok 5
+",
+        );
+        assert_eq!(
+            summary,
+            TestSummary {
+                passed: 1,
+                failed: 2,
+                skipped: 2,
+                failures: vec![
+                    TestFailure {
+                        name: "2 - second".into(),
+                        message: "---\nmessage: 'oops'\n...".into(),
+                    },
+                    TestFailure {
+                        name: "5".into(),
+                        message: "".into(),
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_tap_subtests() {
+        let summary = tap::parse(
+            "TAP version 14
+1..2
+# Subtest: nested
+    1..2
+    ok 1 - inner
+    not ok 2 - inner failing
+ok 1 - nested # TODO inner failing
+okay, that was all for the first one
+not ok 2 - second
+  ---
+  okay: false
+  ...
+",
+        );
+        assert_eq!(
+            summary,
+            TestSummary {
+                passed: 0,
+                failed: 1,
+                skipped: 1,
+                failures: vec![
+                    TestFailure {
+                        name: "2 - second".into(),
+                        message: "---\nokay: false\n...".into(),
+                    },
+                ],
+            }
+        );
+    }
+}
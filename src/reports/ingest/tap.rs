@@ -0,0 +1,74 @@
+//! Test Anything Protocol, https://testanything.org
+
+use reports::ingest::TestSummary;
+
+/// A `not ok` test whose diagnostics are still being collected
+struct Failing {
+    name: String,
+    diagnostics: Vec<String>,
+}
+
+pub fn parse(contents: &str) -> TestSummary {
+    let mut summary = TestSummary::default();
+    let mut failing: Option<Failing> = None;
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        let indented = line.starts_with(' ') || line.starts_with('\t');
+        let (ok, rest) = if let Some(point) = test_point(trimmed) {
+            // Indented test-points belong to subtests, summed up by their parent
+            if indented {
+                continue;
+            }
+            point
+        } else {
+            // Diagnostics, as comments or YAML-blocks, belong to the preceding test
+            if let Some(ref mut failing) = failing {
+                if trimmed.starts_with('#') || indented {
+                    failing.diagnostics.push(
+                        trimmed.trim_start_matches('#').trim().to_owned(),
+                    );
+                }
+            }
+            continue;
+        };
+        if let Some(done) = failing.take() {
+            summary.fail(done.name, done.diagnostics.join("\n"));
+        }
+
+        let (description, directive) = match rest.find('#') {
+            Some(i) => (&rest[..i], rest[i + 1..].trim().to_uppercase()),
+            None => (rest, String::new()),
+        };
+        // Failing TODO-tests are expected to fail and not counted as failures
+        if directive.starts_with("SKIP") || directive.starts_with("TODO") {
+            summary.skipped += 1;
+        } else if ok {
+            summary.passed += 1;
+        } else {
+            failing = Some(Failing {
+                name: description.trim().to_owned(),
+                diagnostics: Vec::new(),
+            });
+        }
+    }
+    if let Some(done) = failing.take() {
+        summary.fail(done.name, done.diagnostics.join("\n"));
+    }
+    summary
+}
+
+/// Whether `line` is an `ok` or `not ok` test-point and the text following it
+fn test_point(line: &str) -> Option<(bool, &str)> {
+    let (ok, rest) = if let Some(rest) = line.strip_prefix("not ok") {
+        (false, rest)
+    } else if let Some(rest) = line.strip_prefix("ok") {
+        (true, rest)
+    } else {
+        return None;
+    };
+    if rest.is_empty() || rest.starts_with(' ') {
+        Some((ok, rest))
+    } else {
+        None
+    }
+}
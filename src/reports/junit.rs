@@ -0,0 +1,85 @@
+//! JUnit XML report with every build-step as a testcase
+
+use BuildStatus;
+use reports::{ReportContext, secs};
+
+pub fn render(ctx: &ReportContext) -> String {
+    let failures = ctx.results
+        .iter()
+        .filter(|res| res.status != BuildStatus::Successful)
+        .count();
+    let skipped = ctx.skipped().len();
+    let tests = ctx.results.len() + skipped;
+    let time = secs(&ctx.duration());
+
+    let mut xml = String::new();
+    xml += "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+    xml += &format!(
+        "<testsuites name=\"rupert\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+        tests,
+        failures,
+        time
+    );
+    xml += &format!(
+        "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+        escape(ctx.name),
+        tests,
+        failures,
+        skipped,
+        time
+    );
+    xml += &format!(
+        "    <properties>\n      <property name=\"revision\" value=\"{}\"/>\n    </properties>\n",
+        escape(ctx.revision)
+    );
+    for res in ctx.results {
+        xml += &format!(
+            "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\">\n",
+            escape(&res.cmd),
+            escape(ctx.name),
+            secs(&res.duration)
+        );
+        if res.status != BuildStatus::Successful {
+            let mut message = format!("Step finished as {}", res.status_description());
+            if let Some(ref tests) = res.tests {
+                message += &format!(": {}", tests);
+            }
+            // The output is in <system-out> already
+            xml += &format!("      <failure message=\"{}\"/>\n", escape(&message));
+        }
+        xml += &format!("      <system-out>{}</system-out>\n", escape(&res.output));
+        xml += "    </testcase>\n";
+    }
+    for step in ctx.skipped() {
+        xml += &format!(
+            "    <testcase name=\"{}\" classname=\"{}\" time=\"0\">\n",
+            escape(&step.cmd),
+            escape(ctx.name)
+        );
+        xml += "      <skipped message=\"Not executed due to earlier failure\"/>\n";
+        xml += "    </testcase>\n";
+    }
+    xml += "  </testsuite>\n";
+    xml += "</testsuites>\n";
+    xml
+}
+
+/// Escape text for use in XML, dropping terminal escape-codes and other characters
+/// not allowed in XML 1.0
+pub fn escape(text: &str) -> String {
+    let text = ::utils::strip_ansi(text);
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&apos;",
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if (c as u32) < 0x20 => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,84 @@
+//! Human readable Markdown summary of a build
+
+use BuildStatus;
+use reports::{ReportContext, secs, tail};
+
+pub fn render(ctx: &ReportContext, tail_lines: usize) -> String {
+    let successful = ctx.skipped().is_empty() &&
+        ctx.results.iter().all(
+            |res| res.status == BuildStatus::Successful,
+        );
+    let mut md = format!("# Build of {} at {}\n\n", ctx.name, ctx.revision);
+    md += &format!(
+        "**Result:** {} ({} of {} steps executed in {:.2}s)\n\n",
+        if successful { "Successful" } else { "Failed" },
+        ctx.results.len(),
+        ctx.steps.len(),
+        secs(&ctx.duration())
+    );
+
+    md += "| # | Step | Status | Duration | Tests |\n";
+    md += "|---|------|--------|----------|-------|\n";
+    for (i, res) in ctx.results.iter().enumerate() {
+        md += &format!(
+            "| {} | {} | {:?} | {:.2}s | {} |\n",
+            i + 1,
+            escape_cell(&code(&res.cmd)),
+            res.status,
+            secs(&res.duration),
+            res.tests.as_ref().map(|t| t.to_string()).unwrap_or(
+                "-".into(),
+            )
+        );
+    }
+    for (i, step) in ctx.skipped().iter().enumerate() {
+        md += &format!(
+            "| {} | {} | Skipped | - | - |\n",
+            ctx.results.len() + i + 1,
+            escape_cell(&code(&step.cmd))
+        );
+    }
+
+    for (i, res) in ctx.results.iter().enumerate() {
+        if res.status == BuildStatus::Successful {
+            continue;
+        }
+        md += &format!(
+            "\n## Step {} {} finished as {}\n\n",
+            i + 1,
+            code(&res.cmd),
+            res.status_description()
+        );
+        if let Some(ref tests) = res.tests {
+            md += &format!("Tests: {}\n\n", tests);
+            for failure in &tests.failures {
+                let first_line = failure.message.lines().next().unwrap_or_default();
+                md += &format!("* {}: {}\n", code(&failure.name), first_line);
+            }
+            md += "\n";
+        }
+        md += &format!("Last {} lines of output:\n\n```text\n", tail_lines);
+        for line in tail(&::utils::strip_ansi(&res.output), tail_lines) {
+            md += line;
+            md += "\n";
+        }
+        md += "```\n";
+    }
+    md
+}
+
+/// `text` as inline code, in a fence of more backticks than any run of them in `text`
+fn code(text: &str) -> String {
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest + 1);
+    // Padded so backticks at either end are not taken for the fence
+    if text.starts_with('`') || text.ends_with('`') {
+        format!("{} {} {}", fence, text, fence)
+    } else {
+        format!("{}{}{}", fence, text, fence)
+    }
+}
+
+fn escape_cell(text: &str) -> String {
+    text.replace('|', "\\|")
+}
@@ -0,0 +1,169 @@
+//! Reports written to the artifact-directory after each build
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use errors::*;
+use users;
+use {BuildStep, BuildStepResult};
+
+pub mod ingest;
+pub mod junit;
+pub mod markdown;
+
+const FNAME_JUNIT: &str = "rupert-junit.xml";
+const FNAME_MARKDOWN: &str = "rupert-summary.md";
+
+/// Number of trailing output-lines of a failing step included in summaries
+pub const TAIL_LINES: usize = 30;
+
+/// Paths of the reports written for a build
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BuildReports {
+    pub junit: PathBuf,
+    pub markdown: PathBuf,
+}
+
+/// What the reports are about
+pub struct ReportContext<'a> {
+    /// "owner/reponame"
+    pub name: &'a str,
+    /// Commit-id or other description of what was built
+    pub revision: &'a str,
+    /// All steps of the build-instruction, including the ones never executed
+    pub steps: &'a [BuildStep],
+    pub results: &'a [BuildStepResult],
+}
+
+impl<'a> ReportContext<'a> {
+    /// Steps that were never executed as an earlier step failed
+    pub fn skipped(&self) -> &'a [BuildStep] {
+        &self.steps[self.results.len().min(self.steps.len())..]
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.results.iter().fold(
+            Duration::from_secs(0),
+            |acc, res| acc + res.duration,
+        )
+    }
+}
+
+/// Write all reports for a build into `dir`
+pub fn write_reports(dir: &Path, ctx: &ReportContext) -> Result<BuildReports> {
+    write_file(dir, FNAME_JUNIT, &junit::render(ctx))?;
+    write_file(dir, FNAME_MARKDOWN, &markdown::render(ctx, TAIL_LINES))?;
+    Ok(BuildReports {
+        junit: dir.join(FNAME_JUNIT),
+        markdown: dir.join(FNAME_MARKDOWN),
+    })
+}
+
+/// Write `contents` into `name` in `dir`, which may belong to the user of the steps, so
+/// symlinks left there by them are not followed
+fn write_file(dir: &Path, name: &str, contents: &str) -> Result<()> {
+    let path = dir.join(name);
+    let mut file = users::create_beneath(dir, Path::new(name)).chain_err(|| {
+        format!("Failed creating {:?}", path)
+    })?;
+    file.write_all(contents.as_bytes()).chain_err(|| {
+        format!("Failed writing {:?}", path)
+    })
+}
+
+/// The last `n` lines of `output`
+pub fn tail(output: &str, n: usize) -> Vec<&str> {
+    let lines: Vec<&str> = output.lines().collect();
+    let skip = lines.len().saturating_sub(n);
+    lines[skip..].to_vec()
+}
+
+/// Seconds as a float, as used in reports
+pub fn secs(duration: &Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+
+    use {BuildStatus, BuildStep, BuildStepResult};
+    use reports::{self, ReportContext};
+    use reports::ingest::{TestFailure, TestSummary};
+
+    fn step_result(cmd: &str, status: BuildStatus, output: &str) -> BuildStepResult {
+        BuildStepResult {
+            status,
+            cmd: cmd.into(),
+            output: output.into(),
+            duration: Duration::from_millis(1500),
+            tests: None,
+            limit: None,
+        }
+    }
+
+    #[test]
+    fn test_tail() {
+        assert_eq!(reports::tail("a\nb\nc\n", 2), vec!["b", "c"]);
+        assert_eq!(reports::tail("a", 2), vec!["a"]);
+    }
+
+    #[test]
+    fn test_render() {
+        let steps: Vec<BuildStep> = vec!["make", "make test", "make <install>", "echo `date`"]
+            .into_iter()
+            .map(|cmd| {
+                BuildStep {
+                    cmd: cmd.into(),
+                    reports: Vec::new(),
+                    unshallow: false,
+                    limits: Default::default(),
+                }
+            })
+            .collect();
+        let mut results = vec![
+            step_result("make", BuildStatus::Successful, "ok"),
+            step_result(
+                "make test",
+                BuildStatus::Failed,
+                "line 1\nline 2\n\u{1b}[31mfailed & <done>\u{1b}[0m",
+            ),
+        ];
+        results[1].tests = Some(TestSummary {
+            passed: 412,
+            failed: 1,
+            skipped: 0,
+            failures: vec![
+                TestFailure {
+                    name: "tests::it_works".into(),
+                    message: "assertion failed".into(),
+                },
+            ],
+        });
+        let ctx = ReportContext {
+            name: "purew/foobar",
+            revision: "abc123",
+            steps: &steps,
+            results: &results,
+        };
+
+        let xml = reports::junit::render(&ctx);
+        assert!(xml.contains(r#"<testsuite name="purew/foobar" tests="4" failures="1" skipped="2" time="3.000""#));
+        // The output of a failing step is only in <system-out>
+        assert_eq!(xml.matches("failed &amp; &lt;done&gt;").count(), 1);
+        assert!(xml.contains(r#"<testcase name="make &lt;install&gt;""#));
+        assert!(!xml.contains('\u{1b}'));
+
+        let md = reports::markdown::render(&ctx, 2);
+        assert!(md.contains("| 2 | `make test` | Failed | 1.50s | 412 passed, 1 failed |"));
+        assert!(md.contains("`tests::it_works`: assertion failed"));
+        assert!(xml.contains(r#"message="Step finished as Failed: 412 passed, 1 failed""#));
+        assert!(md.contains("| 3 | `make <install>` | Skipped | - |"));
+        assert!(md.contains("| 4 | `` echo `date` `` | Skipped | - |"));
+        assert!(md.contains("line 2\nfailed & <done>\n"));
+        assert!(!md.contains("line 1"));
+    }
+}
@@ -0,0 +1,248 @@
+//! JSON-API for triggering, querying, cancelling and approving builds
+//!
+//! Requests are authenticated with one of the `api_tokens` of the configuration, given as
+//! `Authorization: Bearer <token>`. Build-ids are numbered per repository, so requests
+//! for a single build name its repository as `?repo=<owner>/<reponame>`. The API is
+//! described by the OpenAPI-schema served without authentication on `/api/openapi.json`.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+
+use serde_json;
+use tiny_http::{Method, Request, Response};
+
+use errors::*;
+use history::{BuildRecord, History};
+use server::{HttpResponse, State, constant_time_eq, header, query_param, request_header,
+             text_response};
+use utils::{self, Config};
+use utils::git::GitRef;
+use {BuildRequest, BuildStatus};
+
+/// Schema of the API, kept in sync with `handle`
+const SCHEMA: &str = include_str!("openapi.json");
+
+/// Builds listed if no `limit` is given
+const DEFAULT_LIMIT: usize = 50;
+
+/// Body of `POST /api/builds`, exactly one of `branch`, `tag` and `commit` is given
+#[derive(Deserialize)]
+struct TriggerRequest {
+    owner: String,
+    reponame: String,
+    branch: Option<String>,
+    tag: Option<String>,
+    commit: Option<String>,
+    /// Extra environment-variables for the build-steps
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+}
+
+/// Handle `/api/<segments>`
+pub fn handle(
+    state: &State,
+    method: &Method,
+    segments: &[&str],
+    query: &str,
+    request: &mut Request,
+) -> Result<HttpResponse> {
+    if let (&Method::Get, &["openapi.json"]) = (method, segments) {
+        return Ok(json_response(200, SCHEMA.to_owned()));
+    }
+    if !authorized(&state.conf, request) {
+        return Ok(error(401, "Missing or invalid token").with_header(
+            header("WWW-Authenticate", "Bearer"),
+        ));
+    }
+    match (method, segments) {
+        (&Method::Post, &["builds"]) => trigger(state, request),
+        (&Method::Get, &["builds"]) => list(&state.conf, query),
+        (&Method::Get, &["builds", id]) => {
+            with_build(state, query, id, |_, record| Ok(record_response(200, &record)))
+        }
+        (&Method::Get, &["builds", id, "log"]) => with_build(state, query, id, log),
+        (&Method::Post, &["builds", id, "cancel"]) => {
+            with_build(state, query, id, |history, record| cancel(state, history, record))
+        }
+        (&Method::Post, &["builds", id, "approve"]) => {
+            with_build(state, query, id, |history, record| approve(state, history, record))
+        }
+        _ => Ok(error(404, "Not found")),
+    }
+}
+
+/// Whether `request` carries one of the `api_tokens` as bearer-token
+pub fn authorized(conf: &Config, request: &Request) -> bool {
+    let token = match request_header(request, "Authorization") {
+        Some(value) => {
+            match value.trim().splitn(2, ' ').collect::<Vec<_>>().as_slice() {
+                &[scheme, token] if scheme.eq_ignore_ascii_case("Bearer") => {
+                    token.trim().to_owned()
+                }
+                _ => return false,
+            }
+        }
+        None => return false,
+    };
+    conf.meta.api_tokens.iter().any(|t| {
+        constant_time_eq(t.as_bytes(), token.as_bytes())
+    })
+}
+
+fn trigger(state: &State, request: &mut Request) -> Result<HttpResponse> {
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body).chain_err(
+        || "Failed reading request",
+    )?;
+    let trigger: TriggerRequest = match serde_json::from_str(&body) {
+        Ok(trigger) => trigger,
+        Err(e) => return Ok(error(400, &format!("Invalid request: {}", e))),
+    };
+    let gitref = match (trigger.branch, trigger.tag, trigger.commit) {
+        (Some(branch), None, None) => GitRef::Branch(branch),
+        (None, Some(tag), None) => GitRef::Tag(tag),
+        (None, None, Some(commit)) => GitRef::Commit(commit),
+        _ => return Ok(error(400, "Exactly one of branch, tag and commit is required")),
+    };
+    let key = (trigger.owner.to_lowercase(), trigger.reponame.to_lowercase());
+    let repo_conf = match state.conf.repos.get(&key) {
+        Some(repo_conf) => repo_conf,
+        None => return Ok(error(404, "Repository not configured")),
+    };
+    let mut req = match BuildRequest::resolve(&state.conf, repo_conf, &gitref) {
+        Ok(req) => req,
+        Err(Error(ErrorKind::RefNotFound(r), _)) => {
+            return Ok(error(422, &format!("Ref not found: {}", r)))
+        }
+        Err(e) => return Err(e),
+    };
+    req.env = trigger.env;
+    let record = state.enqueue(req)?;
+    Ok(record_response(202, &record))
+}
+
+fn list(conf: &Config, query: &str) -> Result<HttpResponse> {
+    let limit = match query_param(query, "limit") {
+        Some(limit) => {
+            match limit.parse() {
+                Ok(limit) => limit,
+                Err(_) => return Ok(error(400, "Invalid limit")),
+            }
+        }
+        None => DEFAULT_LIMIT,
+    };
+    let repos: Vec<&(String, String)> = match query_param(query, "repo") {
+        Some(repo) => {
+            match conf.repos.keys().find(|k| format!("{}/{}", k.0, k.1) == repo) {
+                Some(key) => vec![key],
+                None => return Ok(error(404, "Repository not configured")),
+            }
+        }
+        None => conf.repos.keys().collect(),
+    };
+    let mut records = Vec::new();
+    for (owner, reponame) in repos {
+        records.extend(History::new(&conf.meta.build_root, owner, reponame).list()?);
+    }
+    records.sort_by_key(|record| Reverse(record.started));
+    records.truncate(limit);
+    Ok(json_response(200, json!({ "builds": records }).to_string()))
+}
+
+/// Call `f` with build `id` of the repository given in the query
+fn with_build<F>(state: &State, query: &str, id: &str, f: F) -> Result<HttpResponse>
+where
+    F: FnOnce(&History, BuildRecord) -> Result<HttpResponse>,
+{
+    let repo = match query_param(query, "repo") {
+        Some(repo) => repo,
+        None => return Ok(error(400, "Missing repo, as <owner>/<reponame>")),
+    };
+    let key = match state.conf.repos.keys().find(
+        |k| format!("{}/{}", k.0, k.1) == repo,
+    ) {
+        Some(key) => key,
+        None => return Ok(error(404, "Repository not configured")),
+    };
+    let history = History::new(&state.conf.meta.build_root, &key.0, &key.1);
+    match id.parse().ok().and_then(|id| history.get(id).ok()) {
+        Some(record) => f(&history, record),
+        None => Ok(error(404, "Build not found")),
+    }
+}
+
+fn log(history: &History, record: BuildRecord) -> Result<HttpResponse> {
+    let mut log = String::new();
+    match File::open(history.path_log(record.id)) {
+        Ok(mut file) => {
+            file.read_to_string(&mut log).chain_err(
+                || "Failed reading build-log",
+            )?
+        }
+        Err(_) => return Ok(error(404, "No log for build")),
+    };
+    Ok(text_response(200, &utils::strip_ansi(&log)))
+}
+
+fn cancel(state: &State, history: &History, mut record: BuildRecord) -> Result<HttpResponse> {
+    if record.finished.is_some() {
+        return Ok(error(409, "Build already finished"));
+    }
+    if !state.hub.cancel(&record.owner, &record.reponame, record.id) {
+        // Left unfinished by a server which is no longer running
+        record.status = BuildStatus::Stopped;
+        record.finished = Some(utils::timestamp());
+        history.save(&record)?;
+    }
+    info!("Cancelled build {} of {}/{}", record.id, record.owner, record.reponame);
+    Ok(record_response(202, &record))
+}
+
+fn approve(state: &State, history: &History, record: BuildRecord) -> Result<HttpResponse> {
+    if record.status != BuildStatus::AwaitingApproval {
+        return Ok(error(409, "Build not awaiting approval"));
+    }
+    let (req, record) = history.approve(record.id)?;
+    info!("Approved build {} of {}/{}", record.id, record.owner, record.reponame);
+    state.submit(req, record.clone())?;
+    Ok(record_response(202, &record))
+}
+
+fn record_response(code: u16, record: &BuildRecord) -> HttpResponse {
+    json_response(code, json!({ "build": record }).to_string())
+}
+
+fn json_response(code: u16, json: String) -> HttpResponse {
+    Response::from_data(json)
+        .with_status_code(code)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn error(code: u16, message: &str) -> HttpResponse {
+    json_response(code, json!({ "error": message }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use serde_json::{self, Value};
+    use server::api::SCHEMA;
+
+    #[test]
+    fn test_schema() {
+        let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+        let paths = schema["paths"].as_object().unwrap();
+        for path in &[
+            "/api/builds",
+            "/api/builds/{id}",
+            "/api/builds/{id}/log",
+            "/api/builds/{id}/cancel",
+            "/api/builds/{id}/approve",
+        ]
+        {
+            assert!(paths.contains_key(*path), "{} missing", path);
+        }
+    }
+}
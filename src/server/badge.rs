@@ -0,0 +1,184 @@
+//! SVG status-badges, showing the latest build of a branch in READMEs
+//!
+//! Badges are revalidated on every view through their `ETag`, which changes with the
+//! build and its status.
+
+use tiny_http::{Request, Response};
+
+use errors::*;
+use history::{BuildRecord, History};
+use server::{HttpResponse, constant_time_eq, header, not_found, query_param, request_header};
+use utils::Config;
+use BuildStatus;
+
+/// State of a branch as shown on its badge
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Badge {
+    Passing,
+    Failing,
+    Running,
+    Unknown,
+}
+
+impl Badge {
+    /// Badge of the latest build, cancelled builds and those awaiting approval are passed
+    /// over
+    pub fn from_history<'a, I>(records: I) -> (Badge, Option<u64>)
+    where
+        I: IntoIterator<Item = &'a BuildRecord>,
+    {
+        let latest = records.into_iter().find(|r| {
+            r.status != BuildStatus::Stopped && r.status != BuildStatus::AwaitingApproval
+        });
+        let badge = match latest.map(|r| &r.status) {
+            Some(&BuildStatus::Successful) => Badge::Passing,
+            Some(&BuildStatus::Failed) => Badge::Failing,
+            Some(&BuildStatus::Queued) |
+            Some(&BuildStatus::InProgress) => Badge::Running,
+            Some(&BuildStatus::Stopped) |
+            Some(&BuildStatus::AwaitingApproval) |
+            None => Badge::Unknown,
+        };
+        (badge, latest.map(|r| r.id))
+    }
+
+    pub fn text(&self) -> &'static str {
+        match *self {
+            Badge::Passing => "passing",
+            Badge::Failing => "failing",
+            Badge::Running => "running",
+            Badge::Unknown => "unknown",
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        match *self {
+            Badge::Passing => "#4c1",
+            Badge::Failing => "#e05d44",
+            Badge::Running => "#dfb317",
+            Badge::Unknown => "#9f9f9f",
+        }
+    }
+}
+
+/// Badge of `owner/reponame`, for the branch given in `query` or any branch
+pub fn badge(
+    conf: &Config,
+    request: &Request,
+    owner: &str,
+    reponame: &str,
+    query: &str,
+) -> Result<HttpResponse> {
+    let repo_conf = match conf.repos.get(&(owner.to_owned(), reponame.to_owned())) {
+        Some(repo_conf) => repo_conf,
+        None => return Ok(not_found()),
+    };
+    // Private repositories are not revealed to those without the token
+    if let Some(ref token) = repo_conf.badge_token {
+        let given = query_param(query, "token").unwrap_or_default();
+        if !constant_time_eq(token.as_bytes(), given.as_bytes()) {
+            return Ok(not_found());
+        }
+    }
+    let branch = query_param(query, "branch");
+    let records = History::new(&conf.meta.build_root, owner, reponame).list()?;
+    // Pull-requests are not yet part of their branch
+    let (badge, id) = Badge::from_history(records.iter().filter(|r| {
+        r.pull_request.is_none() && (branch.is_none() || r.branch == branch)
+    }));
+
+    let etag = format!("\"{}-{}\"", id.unwrap_or(0), badge.text());
+    let cache_control = match repo_conf.badge_token {
+        Some(_) => "private, no-cache",
+        None => "no-cache",
+    };
+    let response = if request_header(request, "If-None-Match").as_ref() == Some(&etag) {
+        Response::from_data("").with_status_code(304)
+    } else {
+        Response::from_data(render("build", badge.text(), badge.color()))
+            .with_header(header("Content-Type", "image/svg+xml"))
+    };
+    Ok(
+        response
+            .with_header(header("Cache-Control", cache_control))
+            .with_header(header("ETag", &etag)),
+    )
+}
+
+/// A flat badge with `label` on grey and `text` on `color`
+pub fn render(label: &str, text: &str, color: &str) -> String {
+    let label_width = text_width(label);
+    let text_width = text_width(text);
+    let width = label_width + text_width;
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"20\" \
+         role=\"img\" aria-label=\"{label}: {text}\">\
+         <title>{label}: {text}</title>\
+         <linearGradient id=\"s\" x2=\"0\" y2=\"100%\">\
+         <stop offset=\"0\" stop-color=\"#bbb\" stop-opacity=\".1\"/>\
+         <stop offset=\"1\" stop-opacity=\".1\"/></linearGradient>\
+         <clipPath id=\"r\"><rect width=\"{width}\" height=\"20\" rx=\"3\" fill=\"#fff\"/>\
+         </clipPath>\
+         <g clip-path=\"url(#r)\">\
+         <rect width=\"{label_width}\" height=\"20\" fill=\"#555\"/>\
+         <rect x=\"{label_width}\" width=\"{text_width}\" height=\"20\" fill=\"{color}\"/>\
+         <rect width=\"{width}\" height=\"20\" fill=\"url(#s)\"/></g>\
+         <g fill=\"#fff\" text-anchor=\"middle\" \
+         font-family=\"Verdana,Geneva,DejaVu Sans,sans-serif\" font-size=\"11\">\
+         <text x=\"{label_x}\" y=\"14\">{label}</text>\
+         <text x=\"{text_x}\" y=\"14\">{text}</text></g></svg>",
+        width = width,
+        label_width = label_width,
+        text_width = text_width,
+        label_x = label_width / 2,
+        text_x = label_width + text_width / 2,
+        label = label,
+        text = text,
+        color = color
+    )
+}
+
+/// Approximate width of `text` in 11px Verdana, with padding
+fn text_width(text: &str) -> usize {
+    text.chars().count() * 7 + 10
+}
+
+#[cfg(test)]
+mod tests {
+
+    use history::BuildRecord;
+    use server::badge::{Badge, render};
+    use BuildStatus;
+
+    #[test]
+    fn test_badge() {
+        let record = |id, status| {
+            BuildRecord {
+                id,
+                owner: "superman".into(),
+                reponame: "linux".into(),
+                commit: "abc".into(),
+                branch: Some("main".into()),
+                pull_request: None,
+                author: None,
+                status,
+                started: 0,
+                finished: None,
+                result: None,
+                error: None,
+            }
+        };
+        let records = vec![
+            record(3, BuildStatus::Stopped),
+            record(2, BuildStatus::Failed),
+            record(1, BuildStatus::Successful),
+        ];
+        assert_eq!(Badge::from_history(&records), (Badge::Failing, Some(2)));
+        assert_eq!(Badge::from_history(&records[2..]), (Badge::Passing, Some(1)));
+        assert_eq!(Badge::from_history(&records[..1]), (Badge::Unknown, None));
+
+        let svg = render("build", "passing", "#4c1");
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"104\""));
+        assert!(svg.contains(">passing</text>"));
+    }
+}
@@ -0,0 +1,287 @@
+//! HTML-pages showing repositories, their build-history and the logs of builds
+
+use std::fs::File;
+use std::io::Read;
+
+use errors::*;
+use history::{BuildRecord, History};
+use reports;
+use server::html::{ansi_to_html, escape, page};
+use server::percent_encode;
+use utils::Config;
+
+/// Builds shown per page of the build-history
+pub const PAGE_SIZE: usize = 20;
+
+/// All configured repositories with the status of their latest build, those with a
+/// `badge_token` only if `private` ones are shown
+pub fn repos(conf: &Config, private: bool) -> Result<String> {
+    let mut keys: Vec<&(String, String)> = conf.repos
+        .iter()
+        .filter(|&(_, repo_conf)| private || repo_conf.badge_token.is_none())
+        .map(|(key, _)| key)
+        .collect();
+    keys.sort();
+    let mut body = String::from(
+        "<table>\n<tr><th>Repository</th><th>Status</th><th>Build</th>\
+         <th>Branch</th><th>Commit</th><th>Started</th></tr>\n",
+    );
+    for (owner, reponame) in keys {
+        let history = History::new(&conf.meta.build_root, owner, reponame);
+        let latest = history.list()?.into_iter().next();
+        body += &format!(
+            "<tr><td><a href=\"/repos/{0}/{1}\">{0}/{1}</a></td>",
+            escape(owner),
+            escape(reponame)
+        );
+        body += &match latest {
+            Some(record) => {
+                format!(
+                    "{}<td><a href=\"{}\">#{}</a></td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                    status_cell(&record),
+                    build_path(&record, None),
+                    record.id,
+                    escape(record.branch.as_deref().unwrap_or("")),
+                    escape(record.short_commit()),
+                    format_time(record.started)
+                )
+            }
+            None => "<td>No builds</td><td></td><td></td><td></td><td></td></tr>\n".into(),
+        };
+    }
+    body += "</table>\n";
+    Ok(page("Repositories", &body))
+}
+
+/// A page, counting from 1, of the build-history of a repository. The `token` the page
+/// was viewed with is passed on by its links.
+pub fn history(
+    conf: &Config,
+    owner: &str,
+    reponame: &str,
+    page_nr: usize,
+    token: Option<&str>,
+) -> Result<String> {
+    let records = History::new(&conf.meta.build_root, owner, reponame).list()?;
+    let pages = records.len().div_ceil(PAGE_SIZE);
+    let page_nr = page_nr.max(1);
+
+    let mut body = String::from(
+        "<table>\n<tr><th>Build</th><th>Status</th><th>Branch</th><th>Commit</th>\
+         <th>Author</th><th>Started</th><th>Duration</th><th>Description</th></tr>\n",
+    );
+    for record in records.iter().skip((page_nr - 1) * PAGE_SIZE).take(PAGE_SIZE) {
+        body += &format!(
+            "<tr><td><a href=\"{}\">#{}</a></td>{}<td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+             <td>{}</td><td>{}</td></tr>\n",
+            build_path(record, token),
+            record.id,
+            status_cell(record),
+            escape(record.branch.as_deref().unwrap_or("")),
+            escape(record.short_commit()),
+            escape(&record.author.as_ref().map(|a| a.name.clone()).unwrap_or_default()),
+            format_time(record.started),
+            record
+                .finished
+                .map(|f| format!("{}s", f.saturating_sub(record.started)))
+                .unwrap_or_default(),
+            escape(&record.description())
+        );
+    }
+    body += "</table>\n<p>";
+    if page_nr > 1 {
+        body += &format!(
+            "<a href=\"?page={}{}\">Newer</a> ",
+            page_nr - 1,
+            token_param("&amp;", token)
+        );
+    }
+    body += &format!("Page {} of {}", page_nr, pages.max(1));
+    if page_nr < pages {
+        body += &format!(
+            " <a href=\"?page={}{}\">Older</a>",
+            page_nr + 1,
+            token_param("&amp;", token)
+        );
+    }
+    body += "</p>\n";
+    Ok(page(&format!("{}/{}", owner, reponame), &body))
+}
+
+/// Status, steps and logs of a single build, following the log of a `live` build
+/// through its events. The `token` the page was viewed with is passed on by its links.
+pub fn build(
+    conf: &Config,
+    owner: &str,
+    reponame: &str,
+    id: u64,
+    live: bool,
+    token: Option<&str>,
+) -> Result<String> {
+    let history = History::new(&conf.meta.build_root, owner, reponame);
+    let record = history.get(id)?;
+
+    let mut body = String::from("<table>\n");
+    let mut row = |key: &str, value: String| {
+        body += &format!("<tr><th>{}</th><td>{}</td></tr>\n", key, value)
+    };
+    row(
+        "Repository",
+        format!(
+            "<a href=\"/repos/{0}/{1}{2}\">{0}/{1}</a>",
+            escape(owner),
+            escape(reponame),
+            token_param("?", token)
+        ),
+    );
+    row(
+        "Status",
+        format!(
+            "<span class=\"status-{:?}\">{:?}</span>",
+            record.status,
+            record.status
+        ),
+    );
+    row("Description", escape(&record.description()));
+    row(
+        "Branch",
+        escape(record.branch.as_deref().unwrap_or("")),
+    );
+    row("Commit", escape(&record.commit));
+    if let Some(ref author) = record.author {
+        row("Author", escape(&format!("{} <{}>", author.name, author.email)));
+    }
+    row("Started", format_time(record.started));
+    if let Some(finished) = record.finished {
+        row("Finished", format_time(finished));
+    }
+    body += "</table>\n";
+
+    if let Some(ref result) = record.result {
+        body += "<h2>Steps</h2>\n<table>\n<tr><th>#</th><th>Step</th><th>Status</th>\
+                 <th>Duration</th><th>Tests</th></tr>\n";
+        for (i, step) in result.steps.iter().enumerate() {
+            body += &format!(
+                "<tr><td><a href=\"#step-{0}\">{0}</a></td><td><code>{1}</code></td>\
+                 <td class=\"status-{2:?}\">{3}</td><td>{4:.2}s</td><td>{5}</td></tr>\n",
+                i + 1,
+                escape(&step.cmd),
+                step.status,
+                step.status_description(),
+                reports::secs(&step.duration),
+                step.tests.as_ref().map(|t| t.to_string()).unwrap_or_default()
+            );
+        }
+        body += "</table>\n";
+        for (i, step) in result.steps.iter().enumerate() {
+            body += &format!(
+                "<h3 id=\"step-{}\">{}. <code>{}</code></h3>\n<pre>{}</pre>\n",
+                i + 1,
+                i + 1,
+                escape(&step.cmd),
+                ansi_to_html(&step.output)
+            );
+        }
+    }
+
+    body += "<h2>Log</h2>\n";
+    let live = live && record.finished.is_none();
+    if live {
+        body += "<pre id=\"log\"></pre>\n";
+        body += LIVE_LOG_SCRIPT;
+    } else {
+        let mut log = String::new();
+        match File::open(history.path_log(id)) {
+            Ok(mut file) => {
+                file.read_to_string(&mut log).chain_err(
+                    || "Failed reading build-log",
+                )?;
+            }
+            Err(_) => log += "No log",
+        }
+        body += &format!("<pre id=\"log\">{}</pre>\n", ansi_to_html(&log));
+    }
+
+    let mut html = page(&format!("{}/{} #{}", owner, reponame, id), &body);
+    if record.finished.is_none() && !live {
+        // Keep polling a build which is not yet running
+        html = html.replace(
+            "<head>",
+            "<head>\n<meta http-equiv=\"refresh\" content=\"5\">",
+        );
+    }
+    Ok(html)
+}
+
+/// Appends the updates of a running build to the log, reloading the page once it ends
+const LIVE_LOG_SCRIPT: &str = r#"<script>
+var log = document.getElementById("log");
+var events = new EventSource(location.pathname + "/events" + location.search);
+events.onmessage = function(e) {
+  var update = JSON.parse(e.data);
+  var line;
+  switch (update.type) {
+    case "Started": line = "Starting build"; break;
+    case "StepStarted": line = "Starting step: " + update.data; break;
+    case "StepNewOutput": line = update.data.data; break;
+    case "StepFinished": line = "Finished build-step: " + update.data.status; break;
+    default: return;
+  }
+  log.textContent += line.replace(/\x1b\[[0-9;]*m/g, "") + "\n";
+};
+events.addEventListener("end", function() {
+  events.close();
+  location.reload();
+});
+</script>
+"#;
+
+fn build_path(record: &BuildRecord, token: Option<&str>) -> String {
+    format!(
+        "/repos/{}/{}/builds/{}{}",
+        escape(&record.owner),
+        escape(&record.reponame),
+        record.id,
+        token_param("?", token)
+    )
+}
+
+/// `token` as a parameter of a link, after `separator`
+fn token_param(separator: &str, token: Option<&str>) -> String {
+    token
+        .map(|token| format!("{}token={}", separator, percent_encode(token)))
+        .unwrap_or_default()
+}
+
+fn status_cell(record: &BuildRecord) -> String {
+    format!(
+        "<td class=\"status-{:?}\">{:?}</td>",
+        record.status,
+        record.status
+    )
+}
+
+/// Seconds since the epoch as "YYYY-MM-DD HH:MM:SS UTC"
+pub fn format_time(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+    // Civil date from days since 1970-01-01, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
@@ -0,0 +1,97 @@
+//! Live updates of running builds as Server-Sent Events
+//!
+//! Every update is sent as a JSON-encoded message with its index as event-id, so a
+//! reconnecting client sending `Last-Event-ID` continues where it left off, as far as
+//! the updates are still kept by the hub. Those the hub no longer keeps are replayed
+//! from the build-log, one line each as output. An `end` event is sent once the build
+//! is finished.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+
+use serde_json;
+use tiny_http::Request;
+
+use hub::Hub;
+use server::{request_header, text_response};
+use utils::{BuildUpdates, TextOutput};
+
+/// How often a comment is sent while the build is silent, to notice clients leaving
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Stream the updates of build `id`, logged to `path_log`, to the client of `request`
+pub fn stream(
+    hub: &Hub,
+    request: Request,
+    owner: &str,
+    reponame: &str,
+    id: u64,
+    path_log: &Path,
+) {
+    let broadcast = match hub.get(owner, reponame, id) {
+        Some(build) => build.broadcast,
+        None => {
+            let _ = request.respond(text_response(404, "Build is not running"));
+            return;
+        }
+    };
+    // Updates up to and including the last one received are skipped
+    let skip = request_header(&request, "Last-Event-ID")
+        .and_then(|id| id.parse::<usize>().ok())
+        .map_or(0, |id| id + 1);
+    let (first, updates) = broadcast.subscribe_indexed();
+    drop(broadcast);
+
+    let mut writer = request.into_writer();
+    let res = (|| -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+             Cache-Control: no-cache\r\nConnection: close\r\n\r\n"
+        )?;
+        writer.flush()?;
+        if first > skip {
+            // Every update is logged as a line by the time the hub drops it
+            let mut replayed = skip;
+            if let Ok(log) = File::open(path_log) {
+                for line in BufReader::new(log).lines().skip(skip).take(first - skip) {
+                    let update = BuildUpdates::StepNewOutput(TextOutput::Stdout(line?));
+                    send(&mut writer, replayed, &update)?;
+                    replayed += 1;
+                }
+            }
+            if replayed < first {
+                write!(writer, ": {} updates no longer kept\n\n", first - replayed)?;
+            }
+        }
+        let mut next = first;
+        loop {
+            match updates.recv_timeout(KEEPALIVE) {
+                Ok(update) => {
+                    if next >= skip {
+                        send(&mut writer, next, &update)?;
+                    }
+                    next += 1;
+                }
+                Err(RecvTimeoutError::Timeout) => write!(writer, ": keepalive\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => {
+                    write!(writer, "event: end\ndata: \n\n")?;
+                    return writer.flush();
+                }
+            }
+            writer.flush()?;
+        }
+    })();
+    if let Err(e) = res {
+        debug!("Stopped streaming build {} of {}/{}: {}", id, owner, reponame, e);
+    }
+}
+
+/// Send `update` as the message with id `index`
+fn send<W: Write>(writer: &mut W, index: usize, update: &BuildUpdates) -> io::Result<()> {
+    let data = serde_json::to_string(update).map_err(io::Error::other)?;
+    write!(writer, "id: {}\ndata: {}\n\n", index, data)
+}
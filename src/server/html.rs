@@ -0,0 +1,125 @@
+//! Helpers for rendering HTML-pages
+
+use utils::{Ansi, parse_ansi};
+
+/// Escape `text` for use in HTML-content and attributes
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&#39;",
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Convert terminal output, colored e.g. by `Paint`, to escaped HTML with the colors as
+/// `<span class="ansi-..">`. Escape-sequences other than colors are dropped.
+pub fn ansi_to_html(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    let mut open_spans = 0;
+    for part in parse_ansi(text) {
+        let params = match part {
+            Ansi::Text(text) => {
+                html += &escape(text);
+                continue;
+            }
+            Ansi::Colors(params) => params,
+        };
+        for param in params.split(';') {
+            let code: u32 = param.parse().unwrap_or(0);
+            let class = match code {
+                0 => {
+                    for _ in 0..open_spans {
+                        html += "</span>";
+                    }
+                    open_spans = 0;
+                    continue;
+                }
+                1 => "ansi-bold".to_owned(),
+                3 => "ansi-italic".to_owned(),
+                4 => "ansi-underline".to_owned(),
+                30..=37 => format!("ansi-fg-{}", code - 30),
+                90..=97 => format!("ansi-fg-{}", code - 90 + 8),
+                40..=47 => format!("ansi-bg-{}", code - 40),
+                _ => continue,
+            };
+            html += &format!("<span class=\"{}\">", class);
+            open_spans += 1;
+        }
+    }
+    for _ in 0..open_spans {
+        html += "</span>";
+    }
+    html
+}
+
+/// A complete page with the common layout
+pub fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>{title} - rupert</title>
+<style>{style}</style>
+</head>
+<body>
+<header><a href=\"/\">rupert</a></header>
+<h1>{title}</h1>
+{body}
+</body>
+</html>
+",
+        title = escape(title),
+        style = STYLE,
+        body = body
+    )
+}
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; }
+header a { font-weight: bold; text-decoration: none; color: #444; }
+table { border-collapse: collapse; }
+th, td { text-align: left; padding: 0.3em 1em 0.3em 0; }
+pre { background: #1e1e1e; color: #ddd; padding: 1em; overflow-x: auto; }
+.status-Successful { color: #2a7d2a; }
+.status-Failed { color: #c0392b; }
+.status-InProgress, .status-Queued, .status-AwaitingApproval { color: #b7950b; }
+.status-Stopped { color: #777; }
+.ansi-bold { font-weight: bold; }
+.ansi-italic { font-style: italic; }
+.ansi-underline { text-decoration: underline; }
+.ansi-fg-0 { color: #555; } .ansi-fg-1 { color: #e74c3c; } .ansi-fg-2 { color: #2ecc71; }
+.ansi-fg-3 { color: #f1c40f; } .ansi-fg-4 { color: #3498db; } .ansi-fg-5 { color: #9b59b6; }
+.ansi-fg-6 { color: #1abc9c; } .ansi-fg-7 { color: #ddd; } .ansi-fg-8 { color: #888; }
+.ansi-fg-9 { color: #ff6b6b; } .ansi-fg-10 { color: #7bed9f; } .ansi-fg-11 { color: #ffeaa7; }
+.ansi-fg-12 { color: #74b9ff; } .ansi-fg-13 { color: #d6a2e8; } .ansi-fg-14 { color: #81ecec; }
+.ansi-fg-15 { color: #fff; }
+.ansi-bg-1 { background: #e74c3c; } .ansi-bg-2 { background: #2ecc71; }
+.ansi-bg-3 { background: #f1c40f; } .ansi-bg-4 { background: #3498db; }
+";
+
+
+#[cfg(test)]
+mod tests {
+
+    use server::html::ansi_to_html;
+
+    #[test]
+    fn test_ansi_to_html() {
+        assert_eq!(
+            ansi_to_html("\u{1b}[33mStarting <step>\u{1b}[0m done"),
+            "<span class=\"ansi-fg-3\">Starting &lt;step&gt;</span> done"
+        );
+        assert_eq!(
+            ansi_to_html("\u{1b}[1;91mbold\u{1b}[K"),
+            "<span class=\"ansi-bold\"><span class=\"ansi-fg-9\">bold</span></span>"
+        );
+    }
+}
@@ -0,0 +1,419 @@
+//! HTTP-server receiving webhooks and serving the dashboard
+//!
+//! Builds are queued and executed one at a time by a worker-thread while every request
+//! is handled in a thread of its own. Running builds can be followed live through their
+//! events.
+
+use std::io::Cursor;
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread;
+
+use serde_json::{self, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use errors::*;
+use history::{BuildRecord, History};
+use hub::Hub;
+use integrations::Hookable;
+use notify::webhook::sign;
+use utils::Config;
+use {BuildRequest, hold_build, metrics, run_queued_build};
+
+pub mod api;
+pub mod badge;
+pub mod dashboard;
+pub mod events;
+pub mod html;
+
+pub type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+/// Shared by all request-handlers
+pub struct State {
+    pub conf: Config,
+    pub hub: Hub,
+    queue: Sender<(BuildRequest, BuildRecord)>,
+}
+
+impl State {
+    /// Record a build of `req` as queued and hand it to the build-worker
+    pub fn enqueue(&self, req: BuildRequest) -> Result<BuildRecord> {
+        let history = History::new(&self.conf.meta.build_root, &req.owner, &req.reponame);
+        let record = history.queue(&req)?;
+        self.submit(req, record.clone())?;
+        Ok(record)
+    }
+
+    /// Hand `record` of `req`, recorded as queued, to the build-worker
+    pub fn submit(&self, req: BuildRequest, record: BuildRecord) -> Result<()> {
+        self.hub.register(&record);
+        info!("Queueing build {} of {:?}", record.id, req);
+        self.queue.send((req, record)).chain_err(
+            || "Build-worker has stopped",
+        )?;
+        metrics::BUILDS_QUEUED.inc();
+        Ok(())
+    }
+}
+
+/// Serve on `addr` until the process is stopped
+pub fn serve(conf: Config, addr: &str) -> Result<()> {
+    let server = Server::http(addr).map_err(|e| {
+        Error::from(format!("Failed listening on {}: {}", addr, e))
+    })?;
+    info!("Listening on {}", server.server_addr());
+
+    let (queue, requests) = channel();
+    let state = Arc::new(State {
+        conf,
+        hub: Hub::new(),
+        queue,
+    });
+    {
+        let state = state.clone();
+        thread::spawn(move || build_worker(&state, requests));
+    }
+
+    for request in server.incoming_requests() {
+        let state = state.clone();
+        thread::spawn(move || handle(&state, request));
+    }
+    Ok(())
+}
+
+fn build_worker(state: &State, requests: Receiver<(BuildRequest, BuildRecord)>) {
+    for (req, record) in requests {
+        metrics::BUILDS_QUEUED.dec();
+        let key = (req.owner.clone(), req.reponame.clone());
+        let repo_conf = match state.conf.repos.get(&key) {
+            Some(repo_conf) => repo_conf,
+            None => {
+                warn!("{}/{} not setup in configuration", req.owner, req.reponame);
+                continue;
+            }
+        };
+        match run_queued_build(&state.conf, repo_conf, &req, record, &state.hub, true) {
+            Ok(record) => info!("Build {} of {:?} finished: {}", record.id, req, record.description()),
+            Err(e) => warn!("Build of {:?} failed: {}", req, e),
+        }
+    }
+}
+
+fn handle(state: &State, mut request: Request) {
+    let url = request.url().to_owned();
+    let (path, query) = match url.find('?') {
+        Some(i) => (&url[..i], &url[i + 1..]),
+        None => (url.as_str(), ""),
+    };
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let method = request.method().clone();
+    debug!("{} {}", method, url);
+
+    // Events are streamed for as long as the build runs, instead of a single response
+    if let (&Method::Get, &["repos", owner, reponame, "builds", id, "events"]) =
+        (&method, segments.as_slice())
+    {
+        let visible = can_view(&state.conf, &request, query, owner, reponame);
+        match id.parse() {
+            Ok(id) if visible => {
+                let path_log = History::new(&state.conf.meta.build_root, owner, reponame)
+                    .path_log(id);
+                events::stream(&state.hub, request, owner, reponame, id, &path_log)
+            }
+            _ => {
+                let _ = request.respond(not_found());
+            }
+        }
+        return;
+    }
+
+    let response = match (&method, segments.as_slice()) {
+        (&Method::Post, &["hooks", "bitbucket", owner, reponame]) => {
+            let (outcome, response) = hook_bitbucket(state, &mut request, owner, reponame);
+            metrics::WEBHOOK_REQUESTS.inc(&["bitbucket", outcome]);
+            response
+        }
+        (&Method::Get, &["metrics"]) => {
+            Ok(
+                Response::from_data(metrics::render(&state.conf.meta.build_root))
+                    .with_header(header("Content-Type", "text/plain; version=0.0.4")),
+            )
+        }
+        (_, &["api", ref rest @ ..]) => api::handle(state, &method, rest, query, &mut request),
+        (&Method::Get, &["badge", owner, file]) if file.ends_with(".svg") => {
+            let reponame = &file[..file.len() - ".svg".len()];
+            badge::badge(&state.conf, &request, owner, reponame, query)
+        }
+        (&Method::Get, &[]) => {
+            let private = api::authorized(&state.conf, &request);
+            dashboard::repos(&state.conf, private).map(html_response)
+        }
+        (&Method::Get, &["repos", owner, reponame]) => {
+            let page = query_param(query, "page").and_then(|p| p.parse().ok()).unwrap_or(1);
+            let token = query_param(query, "token");
+            with_repo(state, &request, query, owner, reponame, || {
+                dashboard::history(&state.conf, owner, reponame, page, token.as_deref())
+                    .map(html_response)
+            })
+        }
+        (&Method::Get, &["repos", owner, reponame, "builds", id]) => {
+            let token = query_param(query, "token");
+            with_repo(state, &request, query, owner, reponame, || match id.parse() {
+                Ok(id) => {
+                    let live = state.hub.get(owner, reponame, id).is_some();
+                    dashboard::build(&state.conf, owner, reponame, id, live, token.as_deref())
+                        .map(html_response)
+                        .or_else(|_| Ok(not_found()))
+                }
+                Err(_) => Ok(not_found()),
+            })
+        }
+        _ => Ok(not_found()),
+    };
+    let response = response.unwrap_or_else(|e| {
+        warn!("{} {} failed: {}", method, url, e);
+        text_response(500, "Internal server error")
+    });
+    if let Err(e) = request.respond(response) {
+        debug!("Failed responding to {}: {}", url, e);
+    }
+}
+
+/// Only serve pages of configured repositories the client of `request` may view
+fn with_repo<F>(
+    state: &State,
+    request: &Request,
+    query: &str,
+    owner: &str,
+    reponame: &str,
+    f: F,
+) -> Result<HttpResponse>
+where
+    F: FnOnce() -> Result<HttpResponse>,
+{
+    if can_view(&state.conf, request, query, owner, reponame) {
+        f()
+    } else {
+        Ok(not_found())
+    }
+}
+
+/// Whether the pages of `owner/reponame` are shown to the client of `request`.
+///
+/// Repositories with a `badge_token` are private, their pages need it as `?token=` or one
+/// of the `api_tokens` as bearer-token. Unconfigured repositories are never shown.
+pub fn can_view(
+    conf: &Config,
+    request: &Request,
+    query: &str,
+    owner: &str,
+    reponame: &str,
+) -> bool {
+    match conf.repos.get(&(owner.to_owned(), reponame.to_owned())) {
+        Some(repo_conf) => {
+            match repo_conf.badge_token {
+                Some(ref token) => {
+                    let given = query_param(query, "token").unwrap_or_default();
+                    constant_time_eq(token.as_bytes(), given.as_bytes()) ||
+                        api::authorized(conf, request)
+                }
+                None => true,
+            }
+        }
+        None => false,
+    }
+}
+
+/// Push- and pull-request-events from Bitbucket for the repository `owner/reponame`,
+/// queueing a build of the pushed commit or the source of the pull-request.
+///
+/// Only events signed with the `webhook_secret` of the repository are accepted. The
+/// outcome is returned along with the response, for metrics.
+fn hook_bitbucket(
+    state: &State,
+    request: &mut Request,
+    owner: &str,
+    reponame: &str,
+) -> (&'static str, Result<HttpResponse>) {
+    let repo_conf = match state.conf.repos.get(&(owner.to_owned(), reponame.to_owned())) {
+        Some(repo_conf) => repo_conf,
+        None => return ("unconfigured", Ok(text_response(404, "Repository not configured"))),
+    };
+    let secret = match repo_conf.webhook_secret {
+        Some(ref secret) => secret,
+        None => return ("unauthorized", Ok(text_response(403, "No webhook_secret configured"))),
+    };
+    let mut body = String::new();
+    if let Err(e) = request.as_reader().read_to_string(&mut body) {
+        return ("error", Err(e).chain_err(|| "Failed reading request"));
+    }
+    let signature = request_header(request, "X-Hub-Signature");
+    if !verify_signature(secret, body.as_bytes(), signature.as_deref()) {
+        return ("unauthorized", Ok(text_response(401, "Invalid signature")));
+    }
+
+    let event = request_header(request, "X-Event-Key").unwrap_or_else(|| "repo:push".into());
+    let parse = match event.as_str() {
+        "repo:push" => BuildRequest::parse_push_request,
+        "pullrequest:created" |
+        "pullrequest:updated" => BuildRequest::parse_pull_request,
+        _ => return ("ignored", Ok(text_response(202, "Event ignored"))),
+    };
+    let val: Value = match serde_json::from_str(&body) {
+        Ok(val) => val,
+        Err(_) => return ("invalid", Ok(text_response(400, "Invalid JSON"))),
+    };
+    let full_name = val.pointer("/repository/full_name")
+        .and_then(Value::as_str)
+        .map(str::to_owned);
+    let req = match parse(val) {
+        Ok(req) => req,
+        Err(e) => return ("invalid", Ok(text_response(400, &e.to_string()))),
+    };
+    // The secret only vouches for events of its own repository, named by its full_name
+    // as the owner of the repository isn't necessarily who triggered the event
+    if full_name != Some(format!("{}/{}", owner, reponame)) {
+        return ("invalid", Ok(text_response(400, "Event is of another repository")));
+    }
+    if !req.is_trusted(repo_conf) {
+        return match hold_build(&state.conf, repo_conf, &req) {
+            Ok(_) => ("held", Ok(text_response(202, "Build awaiting approval"))),
+            Err(e) => ("error", Err(e)),
+        };
+    }
+    match state.enqueue(req) {
+        Ok(_) => ("queued", Ok(text_response(202, "Build queued"))),
+        Err(e) => ("error", Err(e)),
+    }
+}
+
+/// Value of `key` in an url-encoded query-string
+pub fn query_param(query: &str, key: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k == key => Some(percent_decode(v)),
+                (Some(k), None) if k == key => Some(String::new()),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// `value` url-encoded, for a query-string
+pub fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            b if b.is_ascii_alphanumeric() => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = ::std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|h| {
+                    u8::from_str_radix(h, 16).ok()
+                });
+                match hex {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Value of header `key` in `request`
+pub fn request_header(request: &Request, key: &str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(key))
+        .map(|h| h.value.as_str().to_owned())
+}
+
+/// Whether `signature`, as in Bitbucket's `X-Hub-Signature: sha256=<hex>`, is the
+/// HMAC-SHA256 of `body` with `secret`
+fn verify_signature(secret: &str, body: &[u8], signature: Option<&str>) -> bool {
+    let expected = format!("sha256={}", sign(secret.as_bytes(), body));
+    signature.is_some_and(|signature| constant_time_eq(expected.as_bytes(), signature.as_bytes()))
+}
+
+/// Compare without leaking the length of a matching prefix through timing
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn header(key: &str, value: &str) -> Header {
+    Header::from_bytes(key.as_bytes(), value.as_bytes()).expect("Valid header")
+}
+
+pub fn html_response(html: String) -> HttpResponse {
+    Response::from_data(html).with_header(header("Content-Type", "text/html; charset=utf-8"))
+}
+
+pub fn text_response(code: u16, text: &str) -> HttpResponse {
+    Response::from_data(text)
+        .with_status_code(code)
+        .with_header(header("Content-Type", "text/plain; charset=utf-8"))
+}
+
+pub fn not_found() -> HttpResponse {
+    text_response(404, "Not found")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use notify::webhook::sign;
+    use server::{constant_time_eq, percent_encode, query_param, verify_signature};
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(query_param("page=2&repo=a%2Fb", "repo"), Some("a/b".into()));
+        assert_eq!(query_param("page=2&repo=a%2Fb", "page"), Some("2".into()));
+        assert_eq!(query_param("q=a+b%", "q"), Some("a b%".into()));
+        assert_eq!(query_param("page=2", "repo"), None);
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("a-b_c.d~1"), "a-b_c.d~1");
+        assert_eq!(percent_encode("a b/c&d"), "a%20b%2Fc%26d");
+        let query = format!("token={}", percent_encode("s3+c/r=t"));
+        assert_eq!(query_param(&query, "token"), Some("s3+c/r=t".into()));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let signature = format!("sha256={}", sign(b"s3cret", b"{}"));
+        assert!(verify_signature("s3cret", b"{}", Some(&signature)));
+        assert!(!verify_signature("s3cret", b"{ }", Some(&signature)));
+        assert!(!verify_signature("other", b"{}", Some(&signature)));
+        assert!(!verify_signature("s3cret", b"{}", None));
+    }
+}
@@ -0,0 +1,423 @@
+//! Users build-steps run as, rather than as rupert
+//!
+//! Steps of a repository with a `user` run with its uid and gid and without supplementary
+//! groups, which needs rupert to run as root. A `static` user is a user of the system. A
+//! `dynamic` user is allocated to the repository from `meta.dynamic_users`, a range of
+//! uids without users, when it is first built. Allocations are recorded in
+//! `<build_root>/users/<uid>` and never released, so a repository keeps its uid and no
+//! other repository gets it. The gid of a dynamic user is its uid.
+//!
+//! The build-, cache- and artifact-dirs are handed to the user before every build.
+
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::{self, File, OpenOptions, create_dir_all};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Component, Path};
+use std::process::Command;
+use std::ptr;
+
+use libc;
+
+use errors::*;
+use utils::lock::Lock;
+
+/// User the steps of a repository run as
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum UserConfig {
+    Static { uid: u32, gid: u32 },
+    Dynamic,
+}
+
+/// Uids dynamic users are allocated from
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct UidRange {
+    pub first: u32,
+    pub count: u32,
+}
+
+impl Default for UidRange {
+    /// The range of systemd's dynamic users
+    fn default() -> Self {
+        UidRange {
+            first: 61184,
+            count: 4336,
+        }
+    }
+}
+
+impl UidRange {
+    fn contains(&self, id: u32) -> bool {
+        id >= self.first && id - self.first < self.count
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct User {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl UserConfig {
+    /// The user of the repository `name`, allocating it if dynamic
+    pub fn user(&self, build_root: &Path, range: &UidRange, name: &str) -> Result<User> {
+        match *self {
+            UserConfig::Static { uid, gid } => Ok(User { uid, gid }),
+            UserConfig::Dynamic => allocate(build_root, range, name),
+        }
+    }
+
+    /// Fail if steps running as this user could read the file at `path`, or files put
+    /// next to it later. The file must not be readable by them, nor carry an ACL, and
+    /// one of its directories must not be traversable by them.
+    pub fn check_unreadable(&self, path: &Path, range: &UidRange) -> Result<()> {
+        let path = path.canonicalize().chain_err(
+            || format!("Failed resolving {:?}", path),
+        )?;
+        if has_acl(&path)? {
+            bail!("{:?} has an ACL, its permissions must be given by its mode alone", path);
+        }
+        if self.may_access(&path, range, 0o004)? {
+            bail!(
+                "{:?} is readable by the user steps run as, it must not be readable by others",
+                path
+            );
+        }
+        for dir in path.ancestors().skip(1) {
+            if !has_acl(dir)? && !self.may_access(dir, range, 0o001)? {
+                return Ok(());
+            }
+        }
+        bail!(
+            "Every directory of {:?} is traversable by the user steps run as, one must not be \
+             traversable by others",
+            path
+        )
+    }
+
+    /// Whether this user has the access to `path` that `others`, a permission-bit of
+    /// others like `0o004`, stands for
+    fn may_access(&self, path: &Path, range: &UidRange, others: u32) -> Result<bool> {
+        let meta = fs::metadata(path).chain_err(|| {
+            format!("Failed reading metadata of {:?}", path)
+        })?;
+        let (owner, group) = match *self {
+            UserConfig::Static { uid, gid } => (meta.uid() == uid, meta.gid() == gid),
+            UserConfig::Dynamic => (range.contains(meta.uid()), range.contains(meta.gid())),
+        };
+        let mode = meta.mode();
+        Ok(
+            owner && mode & (others << 6) != 0 || group && mode & (others << 3) != 0 ||
+                mode & others != 0,
+        )
+    }
+}
+
+/// Whether the file at `path` carries a POSIX ACL
+fn has_acl(path: &Path) -> Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).chain_err(|| {
+        format!("Path {:?} contains NUL", path)
+    })?;
+    let name = b"system.posix_acl_access\0";
+    let size = unsafe {
+        libc::getxattr(
+            c_path.as_ptr(),
+            name.as_ptr() as *const libc::c_char,
+            ptr::null_mut(),
+            0,
+        )
+    };
+    if size >= 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(false),
+        _ => Err(e).chain_err(|| format!("Failed reading ACL of {:?}", path)),
+    }
+}
+
+/// Look up the uid allocated to `name`, or allocate one
+fn allocate(build_root: &Path, range: &UidRange, name: &str) -> Result<User> {
+    let dir = build_root.join("users");
+    // Allocations are serialized, between threads as well as processes
+    let _lock = Lock::acquire(&dir.join(".lock"))?;
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    let start = hasher.finish() % range.count as u64;
+    for i in 0..range.count as u64 {
+        let uid = range.first + ((start + i) % range.count as u64) as u32;
+        let path = dir.join(uid.to_string());
+        let mut owner = String::new();
+        match File::open(&path).and_then(|mut f| f.read_to_string(&mut owner)) {
+            Ok(_) if owner.trim() == name => return Ok(User { uid, gid: uid }),
+            Ok(_) => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).chain_err(|| format!("Failed reading {:?}", path)),
+        }
+        if unsafe { !libc::getpwuid(uid).is_null() } {
+            continue;
+        }
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .and_then(|mut f| f.write_all(format!("{}\n", name).as_bytes()))
+            .chain_err(|| format!("Failed recording allocation of uid {}", uid))?;
+        info!("Allocated uid {} to {}", uid, name);
+        return Ok(User { uid, gid: uid });
+    }
+    bail!(
+        "No uid left for {} in the {} from {} on",
+        name,
+        range.count,
+        range.first
+    )
+}
+
+/// Hand `path`, with everything below it, to `user`.
+///
+/// The tree is walked through file-descriptors of its directories, opened without
+/// following symlinks, so steps replacing a directory by a symlink while it is walked
+/// cannot have anything outside of it handed to them.
+pub fn chown_all(path: &Path, user: &User) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).chain_err(|| {
+        format!("Path {:?} contains NUL", path)
+    })?;
+    let dir = Dir::open(libc::AT_FDCWD, &c_path).chain_err(|| {
+        format!("Failed opening {:?}", path)
+    })?;
+    if unsafe { libc::fchown(dir.fd(), user.uid, user.gid) } != 0 {
+        return Err(io::Error::last_os_error()).chain_err(|| {
+            format!("Failed handing {:?} to uid {}", path, user.uid)
+        });
+    }
+    chown_below(&dir, path, user)
+}
+
+/// Hand everything below the directory `dir` at `path` to `user`
+fn chown_below(dir: &Dir, path: &Path, user: &User) -> Result<()> {
+    loop {
+        let (name, file_type) = match dir.next() {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        if name.as_bytes() == b"." || name.as_bytes() == b".." {
+            continue;
+        }
+        let entry_path = path.join(OsStr::from_bytes(name.as_bytes()));
+        let chowned = unsafe {
+            libc::fchownat(
+                dir.fd(),
+                name.as_ptr(),
+                user.uid,
+                user.gid,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if chowned != 0 {
+            return Err(io::Error::last_os_error()).chain_err(|| {
+                format!("Failed handing {:?} to uid {}", entry_path, user.uid)
+            });
+        }
+        if file_type != libc::DT_DIR && file_type != libc::DT_UNKNOWN {
+            continue;
+        }
+        match Dir::open(dir.fd(), &name) {
+            Ok(child) => chown_below(&child, &entry_path, user)?,
+            // Not a directory, or replaced by something else meanwhile
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOTDIR) ||
+                              e.raw_os_error() == Some(libc::ELOOP) => {}
+            Err(e) => return Err(e).chain_err(|| format!("Failed opening {:?}", entry_path)),
+        }
+    }
+}
+
+/// A directory opened without following symlinks, closed when dropped
+struct Dir(*mut libc::DIR);
+
+impl Dir {
+    /// Open the directory `name` in the directory `parent`, a file-descriptor
+    fn open(parent: libc::c_int, name: &CStr) -> io::Result<Dir> {
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        let fd = unsafe { libc::openat(parent, name.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let dir = unsafe { libc::fdopendir(fd) };
+        if dir.is_null() {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        Ok(Dir(dir))
+    }
+
+    fn fd(&self) -> libc::c_int {
+        unsafe { libc::dirfd(self.0) }
+    }
+
+    /// Name and type of the next entry
+    fn next(&self) -> Option<(CString, u8)> {
+        let entry = unsafe { libc::readdir(self.0) };
+        if entry.is_null() {
+            return None;
+        }
+        unsafe {
+            let name = CStr::from_ptr((*entry).d_name.as_ptr()).to_owned();
+            Some((name, (*entry).d_type))
+        }
+    }
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        unsafe { libc::closedir(self.0) };
+    }
+}
+
+/// Open the file `relative` below the directory `dir` for reading, without following
+/// symlinks, as for the reports steps write into directories handed to their user
+pub fn open_beneath(dir: &Path, relative: &Path) -> io::Result<File> {
+    openat_beneath(dir, relative, libc::O_RDONLY)
+}
+
+/// Create, or truncate, the file `relative` below the directory `dir` for writing,
+/// without following symlinks
+pub fn create_beneath(dir: &Path, relative: &Path) -> io::Result<File> {
+    openat_beneath(dir, relative, libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC)
+}
+
+/// Open `relative` below `dir` with `flags`, through file-descriptors of the directories
+/// on the way, opened like those of `chown_all`. Paths leaving `dir` are refused.
+fn openat_beneath(dir: &Path, relative: &Path, flags: libc::c_int) -> io::Result<File> {
+    let c_dir = CString::new(dir.as_os_str().as_bytes())?;
+    let mut names = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => names.push(CString::new(name.as_bytes())?),
+            Component::CurDir => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?} is not below {:?}", relative, dir),
+                ))
+            }
+        }
+    }
+    let file = match names.pop() {
+        Some(file) => file,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "No file-name")),
+    };
+    let mut parent = Dir::open(libc::AT_FDCWD, &c_dir)?;
+    for name in &names {
+        parent = Dir::open(parent.fd(), name)?;
+    }
+    let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = unsafe { libc::openat(parent.fd(), file.as_ptr(), flags, 0o644 as libc::c_uint) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Create the home-directory `path` of `user`
+pub fn create_home(path: &Path, user: &User) -> Result<()> {
+    create_dir_all(path).chain_err(|| {
+        format!("Failed creating home-directory {:?}", path)
+    })?;
+    chown_all(path, user)
+}
+
+/// Run the process of `command` as `user`, after everything else done before its exec
+pub fn run_as(command: &mut Command, user: &User) {
+    let user = *user;
+    // Only async-signal-safe calls are made between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            let dropped = libc::setgroups(0, ptr::null()) == 0 &&
+                libc::setgid(user.gid) == 0 &&
+                libc::setuid(user.uid) == 0;
+            if dropped {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{File, Permissions, create_dir_all, metadata, remove_dir_all, set_permissions};
+    use std::io::{Read, Write};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt, symlink};
+    use std::path::Path;
+
+    use users::{UidRange, UserConfig, create_beneath, open_beneath};
+    use utils::tests::TEST_DIR;
+
+    #[test]
+    fn test_open_beneath() {
+        let root = TEST_DIR.join("test_open_beneath");
+        let _ = remove_dir_all(&root);
+        create_dir_all(root.join("dir")).unwrap();
+        File::create(root.join("secret")).unwrap();
+        symlink(root.join("secret"), root.join("dir").join("link")).unwrap();
+        symlink(&root, root.join("dir").join("up")).unwrap();
+
+        let report = Path::new("./dir/report.xml");
+        create_beneath(&root, report).unwrap().write_all(b"passed").unwrap();
+        let mut contents = String::new();
+        open_beneath(&root, report).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "passed");
+
+        for path in &["dir/link", "dir/up/secret", "../secret", "/etc/passwd"] {
+            assert!(open_beneath(&root.join("dir"), Path::new(path)).is_err());
+        }
+        assert!(create_beneath(&root, Path::new("dir/link")).is_err());
+        assert_eq!(metadata(root.join("secret")).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_dynamic_users() {
+        let root = TEST_DIR.join("test_dynamic_users");
+        let _ = remove_dir_all(&root);
+        let range = UidRange {
+            first: 61184,
+            count: 2,
+        };
+        let user = UserConfig::Dynamic;
+        let a = user.user(&root, &range, "superman/linux").unwrap();
+        let b = user.user(&root, &range, "lex/linux").unwrap();
+        assert!(range.contains(a.uid) && range.contains(b.uid));
+        assert!(a.uid != b.uid);
+        assert_eq!(a.gid, a.uid);
+        assert_eq!(user.user(&root, &range, "superman/linux").unwrap(), a);
+        assert!(user.user(&root, &range, "lex/rust").is_err());
+
+        let path = root.join("rupert-conf.toml");
+        File::create(&path).unwrap();
+        let meta = metadata(&path).unwrap();
+        let owner = UserConfig::Static {
+            uid: meta.uid(),
+            gid: meta.gid(),
+        };
+        set_permissions(&path, Permissions::from_mode(0o640)).unwrap();
+        set_permissions(&root, Permissions::from_mode(0o755)).unwrap();
+        assert!(user.check_unreadable(&path, &range).is_err());
+        set_permissions(&root, Permissions::from_mode(0o700)).unwrap();
+        assert!(user.check_unreadable(&path, &range).is_ok());
+        set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        assert!(user.check_unreadable(&path, &range).is_err());
+        set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+        assert!(owner.check_unreadable(&path, &range).is_err());
+    }
+}
@@ -0,0 +1,330 @@
+//! Copying of directory-trees into build-directories
+//!
+//! Symlinks are copied as symlinks and never followed, so a tree can neither be escaped
+//! nor loop. Permissions and modification-times are kept, special files such as sockets
+//! and fifos are skipped.
+
+use std::ffi::CString;
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt, symlink};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+
+use libc;
+
+use errors::*;
+
+/// `_IOW(0x94, 9, int)`, clones a file sharing its extents on btrfs, xfs and others
+const FICLONE: libc::c_ulong = 0x4004_9409;
+
+/// How the files of a tree are brought into its copy
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CopyMode {
+    #[default]
+    Copy,
+    /// Hardlinks to the originals, changes in the copy change the originals too
+    Hardlink,
+    /// Copy-on-write clones of the originals where supported
+    Reflink,
+}
+
+#[derive(Clone, Default, Deserialize, Debug)]
+pub struct CopyOptions {
+    /// Falls back to copying where the filesystem does not support it
+    #[serde(default)]
+    pub mode: CopyMode,
+    /// Globs of paths not copied, see `glob_match`
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl CopyOptions {
+    fn excluded(&self, path: &Path) -> bool {
+        self.exclude.iter().any(|pattern| glob_match(pattern, path))
+    }
+}
+
+/// Copy the contents of directory `src` into the existing directory `dst`
+pub fn copy_dir(src: &Path, dst: &Path, opts: &CopyOptions) -> Result<()> {
+    copy_children(src, dst, Path::new(""), opts)
+}
+
+/// Copy `files`, given relative to `src`, into the same relative locations in `dst`.
+///
+/// Directories among `files`, such as submodules, are copied with their contents.
+pub fn copy_files(src: &Path, files: &[PathBuf], dst: &Path, opts: &CopyOptions) -> Result<()> {
+    for file in files {
+        if opts.excluded(file) {
+            continue;
+        }
+        if let Some(parent) = dst.join(file).parent() {
+            fs::create_dir_all(parent).chain_err(
+                || format!("Failed to create {:?}", parent),
+            )?;
+        }
+        copy_entry(src, dst, file, opts)?;
+    }
+    Ok(())
+}
+
+/// Copy the entries of directory `relative` in `src` into `dst`
+fn copy_children(src: &Path, dst: &Path, relative: &Path, opts: &CopyOptions) -> Result<()> {
+    let dir = src.join(relative);
+    let entries = fs::read_dir(&dir).chain_err(
+        || format!("Failed read_dir of {:?}", dir),
+    )?;
+    for entry in entries {
+        let entry = entry.chain_err(|| "Failed reading entry")?;
+        let path = relative.join(entry.file_name());
+        if !opts.excluded(&path) {
+            copy_entry(src, dst, &path, opts)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy `relative` in `src`, recursively, to the same location in `dst`
+fn copy_entry(src: &Path, dst: &Path, relative: &Path, opts: &CopyOptions) -> Result<()> {
+    let from = src.join(relative);
+    let to = dst.join(relative);
+    let metadata = fs::symlink_metadata(&from).chain_err(
+        || format!("Failed reading metadata of {:?}", from),
+    )?;
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        let target = fs::read_link(&from).chain_err(
+            || format!("Failed reading link {:?}", from),
+        )?;
+        symlink(&target, &to).chain_err(|| {
+            format!("Failed creating link {:?} to {:?}", to, target)
+        })?;
+    } else if file_type.is_dir() {
+        if !to.is_dir() {
+            fs::create_dir(&to).chain_err(
+                || format!("Failed to create {:?}", to),
+            )?;
+        }
+        copy_children(src, dst, relative, opts)?;
+        // After the contents, as they could not be written to a read-only directory
+        fs::set_permissions(&to, metadata.permissions()).chain_err(|| {
+            format!("Failed setting permissions of {:?}", to)
+        })?;
+    } else if file_type.is_file() {
+        copy_file(&from, &to, opts.mode).chain_err(|| {
+            format!("Failed copy of {:?} to {:?}", from, to)
+        })?;
+        if opts.mode == CopyMode::Hardlink {
+            return Ok(());
+        }
+    } else {
+        warn!("Skipping special file {:?}", from);
+        return Ok(());
+    }
+    set_times(&to, &metadata).chain_err(
+        || format!("Failed setting times of {:?}", to),
+    )
+}
+
+/// Copy the regular file `from` to `to` as `mode`, falling back to a plain copy
+fn copy_file(from: &Path, to: &Path, mode: CopyMode) -> io::Result<()> {
+    match mode {
+        CopyMode::Copy => {}
+        CopyMode::Hardlink => {
+            match fs::hard_link(from, to) {
+                Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {}
+                res => return res,
+            }
+        }
+        CopyMode::Reflink => {
+            match reflink(from, to) {
+                Ok(()) => return Ok(()),
+                Err(ref e) if unsupported(e) => fs::remove_file(to)?,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    fs::copy(from, to).map(|_| ())
+}
+
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    let src = File::open(from)?;
+    let dst = File::create(to)?;
+    let res = unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE, src.as_raw_fd()) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mode = src.metadata()?.permissions().mode();
+    dst.set_permissions(fs::Permissions::from_mode(mode))
+}
+
+/// Whether reflinking failed for lack of support, rather than by a real error
+fn unsupported(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(code) => {
+            code == libc::EOPNOTSUPP || code == libc::ENOTTY || code == libc::EXDEV ||
+                code == libc::EINVAL || code == libc::ENOSYS
+        }
+        None => false,
+    }
+}
+
+/// Set access- and modification-time of `path` to those of `metadata`, without
+/// following symlinks
+fn set_times(path: &Path, metadata: &Metadata) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime() as libc::time_t,
+            tv_nsec: metadata.atime_nsec() as libc::c_long,
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime() as libc::time_t,
+            tv_nsec: metadata.mtime_nsec() as libc::c_long,
+        },
+    ];
+    let res = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether the relative `path` matches `pattern`.
+///
+/// `*` matches within a path-component, `**` across components and `?` a single
+/// character. Patterns without a `/` match the name of any component, like in
+/// `.gitignore`, so `target` excludes every directory named so.
+pub fn glob_match(pattern: &str, path: &Path) -> bool {
+    let components: Vec<String> = path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    if !pattern.contains('/') {
+        return components.iter().any(|name| {
+            wildcard_match(pattern.as_bytes(), name.as_bytes(), false)
+        });
+    }
+    let path = components.join("/");
+    let pattern = pattern.trim_start_matches('/');
+    wildcard_match(pattern.as_bytes(), path.as_bytes(), true) ||
+        // Excluding a directory excludes its contents
+        (1..components.len()).any(|n| {
+            let parent = components[..n].join("/");
+            wildcard_match(pattern.as_bytes(), parent.as_bytes(), true)
+        })
+}
+
+fn wildcard_match(pattern: &[u8], text: &[u8], separators: bool) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(&b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = pattern[2..].strip_prefix(b"/").unwrap_or(&pattern[2..]);
+            (0..text.len() + 1).any(|i| {
+                (i == 0 || text[i - 1] == b'/' || rest.is_empty()) &&
+                    wildcard_match(rest, &text[i..], separators)
+            })
+        }
+        Some(&b'*') => {
+            (0..text.len() + 1)
+                .take_while(|&i| i == 0 || !separators || text[i - 1] != b'/')
+                .any(|i| wildcard_match(&pattern[1..], &text[i..], separators))
+        }
+        Some(&b'?') => {
+            !text.is_empty() && (!separators || text[0] != b'/') &&
+                wildcard_match(&pattern[1..], &text[1..], separators)
+        }
+        Some(&c) => {
+            text.first() == Some(&c) && wildcard_match(&pattern[1..], &text[1..], separators)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{self, File, create_dir_all, remove_dir_all};
+    use std::io::Write;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt, symlink};
+    use std::path::Path;
+
+    use utils::copy::{CopyMode, CopyOptions, copy_dir, glob_match};
+    use utils::tests::TEST_DIR;
+
+    #[test]
+    fn test_glob_match() {
+        let matches = |pattern, path| glob_match(pattern, Path::new(path));
+        assert!(matches("target", "target"));
+        assert!(matches("target", "sub/target/debug/build"));
+        assert!(!matches("target", "targets"));
+        assert!(matches("*.log", "logs/build.log"));
+        assert!(matches("/docs/*.md", "docs/README.md"));
+        assert!(!matches("docs/*.md", "docs/api/index.md"));
+        assert!(matches("docs/**/*.md", "docs/api/index.md"));
+        assert!(matches("docs/**/*.md", "docs/index.md"));
+        assert!(matches("docs/**", "docs/api/index.md"));
+        assert!(matches("docs", "docs/api"));
+        assert!(matches("data/?.bin", "data/a.bin"));
+        assert!(!matches("data/?.bin", "data/ab.bin"));
+        assert!(matches("build/out", "build/out/lib.so"));
+    }
+
+    #[test]
+    fn test_copy_dir() {
+        let root = TEST_DIR.join("test_copy_dir");
+        let _ = remove_dir_all(&root);
+        let src = root.join("src");
+        create_dir_all(src.join("dir/target")).unwrap();
+        File::create(src.join("dir/file"))
+            .unwrap()
+            .write_all(b"contents")
+            .unwrap();
+        File::create(src.join("dir/target/out")).unwrap();
+        File::create(src.join("script")).unwrap();
+        fs::set_permissions(src.join("script"), fs::Permissions::from_mode(0o750)).unwrap();
+        // A loop and a link out of the tree are copied, not followed
+        symlink("..", src.join("dir/parent")).unwrap();
+        symlink("/etc/passwd", src.join("passwd")).unwrap();
+        fs::set_permissions(src.join("dir"), fs::Permissions::from_mode(0o555)).unwrap();
+
+        for mode in &[CopyMode::Copy, CopyMode::Hardlink, CopyMode::Reflink] {
+            let dst = root.join(format!("{:?}", mode));
+            create_dir_all(&dst).unwrap();
+            let opts = CopyOptions {
+                mode: *mode,
+                exclude: vec!["target".into()],
+            };
+            copy_dir(&src, &dst, &opts).unwrap();
+
+            assert_eq!(fs::read_to_string(dst.join("dir/file")).unwrap(), "contents");
+            assert!(!dst.join("dir/target").exists());
+            assert_eq!(fs::read_link(dst.join("dir/parent")).unwrap(), Path::new(".."));
+            assert_eq!(
+                fs::read_link(dst.join("passwd")).unwrap(),
+                Path::new("/etc/passwd")
+            );
+            let mode_of = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode_of(&dst.join("script")), 0o750);
+            assert_eq!(mode_of(&dst.join("dir")), 0o555);
+            let mtime = |path: &Path| fs::metadata(path).unwrap().mtime();
+            assert_eq!(mtime(&dst.join("dir")), mtime(&src.join("dir")));
+            assert_eq!(mtime(&dst.join("dir/file")), mtime(&src.join("dir/file")));
+            let linked = fs::metadata(dst.join("dir/file")).unwrap().ino() ==
+                fs::metadata(src.join("dir/file")).unwrap().ino();
+            assert_eq!(linked, *mode == CopyMode::Hardlink);
+            fs::set_permissions(dst.join("dir"), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        fs::set_permissions(src.join("dir"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
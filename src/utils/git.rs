@@ -1,92 +1,879 @@
-use std::error::Error;
+use std::cmp;
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::OsStr;
+use std::fs::{OpenOptions, create_dir_all, remove_dir_all};
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
 
 use git2;
-use git2::{Repository, Oid};
+use git2::{Repository, Oid, ObjectType};
 
 use errors::*;
 
 
-pub fn fetch_origin_branches(repo: &Repository) -> Result<()> {
-    info!("Fetching latest changes from origin.");
-    Command::new("git")
-        .current_dir(repo.path())
-        .arg("fetch")
-        .output()
-        .chain_err(|| "Subprocess \"git fetch\" failed")?;
+/// How to authenticate against a remote, and the remotes of its submodules
+#[derive(Clone)]
+pub enum Credentials {
+    /// Keys of ssh-agent, then git's credential-helpers
+    Default,
+    /// A private key, unlocked with `passphrase` if encrypted
+    SshKey {
+        key: PathBuf,
+        passphrase: Option<String>,
+    },
+    /// A username with a password, app-password or token
+    UserPass { username: String, password: String },
+}
+
+/// Where a repository is cloned from
+#[derive(Clone)]
+pub struct Remote {
+    pub url: String,
+    pub credentials: Credentials,
+    pub options: CloneOptions,
+    pub submodules: Submodules,
+}
+
+impl Remote {
+    /// Remote of a submodule at `url`, with the credentials configured for its url or
+    /// else those of this remote
+    fn submodule(&self, url: String) -> Remote {
+        let credentials = self.submodules
+            .credentials
+            .iter()
+            .find(|(prefix, _)| url.starts_with(prefix.as_str()))
+            .map(|(_, credentials)| credentials.clone())
+            .unwrap_or_else(|| self.credentials.clone());
+        let strategy = match self.submodules.strategy {
+            SubmoduleStrategy::TopLevel => SubmoduleStrategy::None,
+            strategy => strategy,
+        };
+        Remote {
+            url,
+            credentials,
+            options: CloneOptions::default(),
+            submodules: Submodules {
+                strategy,
+                ..self.submodules.clone()
+            },
+        }
+    }
+}
+
+/// Which submodules are checked out
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq)]
+pub enum SubmoduleStrategy {
+    #[serde(rename = "none")]
+    None,
+    /// Only those of the repository itself, not those of its submodules
+    #[serde(rename = "top-level")]
+    TopLevel,
+    #[serde(rename = "recursive")]
+    #[default]
+    Recursive,
+}
+
+/// How the submodules of a repository are checked out
+#[derive(Clone)]
+pub struct Submodules {
+    pub strategy: SubmoduleStrategy,
+    /// Submodules fetched and checked out at the same time
+    pub jobs: usize,
+    /// Credentials for the submodules whose url starts with the prefix
+    pub credentials: Vec<(String, Credentials)>,
+}
+
+impl Default for Submodules {
+    fn default() -> Submodules {
+        Submodules {
+            strategy: SubmoduleStrategy::default(),
+            jobs: 1,
+            credentials: Vec::new(),
+        }
+    }
+}
+
+/// A submodule waiting to be checked out by `update_submodule`
+struct PendingSubmodule {
+    name: String,
+    commit: String,
+    remote: Remote,
+    /// Where it is checked out
+    path: PathBuf,
+}
+
+/// How much of a remote is fetched
+#[derive(Clone, Default, Deserialize, Debug)]
+pub struct CloneOptions {
+    /// Commits of history fetched per ref, all of them without it
+    pub depth: Option<u32>,
+    /// Fetch file-contents only once checked out, as a partial clone
+    #[serde(default)]
+    pub blobless: bool,
+    /// Fetch only the ref being built, rather than all branches and tags
+    #[serde(default)]
+    pub single_branch: bool,
+    /// Replace Git LFS pointers with their contents, through the git-lfs CLI
+    #[serde(default)]
+    pub lfs: bool,
+}
+
+impl CloneOptions {
+    /// Whether fetches need the git CLI, libgit2 supports neither shallow nor partial
+    /// clones
+    fn needs_cli(&self) -> bool {
+        self.depth.is_some() || self.blobless
+    }
+}
+
+/// Oldest git supporting everything the CLI is used for, partial clones in particular
+const MIN_GIT_VERSION: (u32, u32) = (2, 22);
+
+/// Credential-helper answering with the username and password given in the environment
+const CREDENTIAL_HELPER: &str = "credential.helper=!f() { test \"$1\" = get && \
+                                 echo username=\"$RUPERT_GIT_USERNAME\" && \
+                                 echo password=\"$RUPERT_GIT_PASSWORD\"; }; f";
+
+/// Program for ssh to ask for passphrases, answering with the one in the environment
+const ASKPASS: &str = "#!/bin/sh\nprintf '%s\\n' \"$RUPERT_SSH_PASSPHRASE\"\n";
+
+/// Something in a repository that can be resolved into a commit
+#[derive(Clone, Debug)]
+pub enum GitRef {
+    Branch(String),
+    Tag(String),
+    Commit(String),
+}
+
+impl GitRef {
+    /// Refspec fetching only this ref, abbreviated commits can not be fetched by
+    /// themselves
+    fn refspec(&self) -> Option<String> {
+        match *self {
+            GitRef::Branch(ref name) => {
+                Some(format!("+refs/heads/{0}:refs/remotes/origin/{0}", name))
+            }
+            GitRef::Tag(ref name) => Some(format!("+refs/tags/{0}:refs/tags/{0}", name)),
+            GitRef::Commit(ref sha) if sha.len() == 40 => Some(sha.to_lowercase()),
+            GitRef::Commit(_) => None,
+        }
+    }
+
+    fn revspec(&self) -> Result<String> {
+        match *self {
+            GitRef::Branch(ref name) => Ok(format!("refs/remotes/origin/{}", name)),
+            GitRef::Tag(ref name) => Ok(format!("refs/tags/{}", name)),
+            GitRef::Commit(ref sha) => {
+                // Only accept hex so that e.g. "HEAD~1" is not silently resolved
+                if sha.len() < 4 || sha.len() > 40 ||
+                    !sha.chars().all(|c| c.is_ascii_hexdigit())
+                {
+                    bail!(ErrorKind::RefNotFound(
+                        format!("\"{}\" is not a (possibly abbreviated) commit-id", sha),
+                    ));
+                }
+                Ok(sha.to_lowercase())
+            }
+        }
+    }
+}
+
+/// Resolve `gitref` into the full id of a commit known to `repo`
+pub fn resolve_ref(repo: &Repository, gitref: &GitRef) -> Result<String> {
+    let spec = gitref.revspec()?;
+    let obj = repo.revparse_single(&spec).chain_err(|| {
+        ErrorKind::RefNotFound(format!("{:?}", gitref))
+    })?;
+    let commit = obj.peel(ObjectType::Commit).chain_err(|| {
+        ErrorKind::RefNotFound(format!("{:?} does not point to a commit", gitref))
+    })?;
+    Ok(commit.id().to_string())
+}
+
+/// Name and email of the author of commit `checksum`
+pub fn commit_author(repo: &Repository, checksum: &str) -> Result<(String, String)> {
+    let oid = Oid::from_str(checksum).chain_err(|| {
+        format!("Not a valid Oid: \"{}\"", checksum)
+    })?;
+    let commit = repo.find_commit(oid).chain_err(
+        || format!("Commit {} not found", checksum),
+    )?;
+    let author = commit.author();
+    Ok((
+        author.name().unwrap_or_default().to_owned(),
+        author.email().unwrap_or_default().to_owned(),
+    ))
+}
+
+/// Whether `checksum` is a full commit-id already present in `repo`
+pub fn has_commit(repo: &Repository, checksum: &str) -> bool {
+    Oid::from_str(checksum)
+        .and_then(|oid| repo.find_commit(oid))
+        .is_ok()
+}
+
+/// Create a commit merging `theirs` into `ours`, without updating any ref, returning
+/// its id
+pub fn merge_commit(repo: &Repository, ours: &str, theirs: &str) -> Result<String> {
+    let find = |checksum: &str| {
+        Oid::from_str(checksum)
+            .and_then(|oid| repo.find_commit(oid))
+            .chain_err(|| format!("Commit {} not found", checksum))
+    };
+    let (our_commit, their_commit) = (find(ours)?, find(theirs)?);
+    let mut index = repo.merge_commits(&our_commit, &their_commit, None)
+        .chain_err(|| format!("Failed merging {} into {}", theirs, ours))?;
+    if index.has_conflicts() {
+        bail!("{} can not be merged into {} without conflicts", theirs, ours);
+    }
+    let tree = index
+        .write_tree_to(repo)
+        .and_then(|oid| repo.find_tree(oid))
+        .chain_err(|| "Failed writing merged tree")?;
+    let sig = git2::Signature::now("Rupert", "rupert@localhost").chain_err(
+        || "Failed creating signature",
+    )?;
+    let message = format!("Merge {} into {}", theirs, ours);
+    repo.commit(None, &sig, &sig, &message, &tree, &[&our_commit, &their_commit])
+        .map(|oid| oid.to_string())
+        .chain_err(|| "Failed creating merge-commit")
+}
+
+/// Verify that HEAD of `repo` points to `checksum` and that the working tree matches it
+pub fn verify_head(repo: &Repository, checksum: &str) -> Result<()> {
+    let head = repo.head()
+        .and_then(|r| r.peel(ObjectType::Commit))
+        .chain_err(|| "Could not resolve HEAD")?
+        .id()
+        .to_string();
+    if head != checksum {
+        bail!(
+            "Checkout of {} failed, HEAD is at {} ({:?})",
+            checksum,
+            head,
+            repo.path()
+        );
+    }
+    let mut opts = git2::StatusOptions::new();
+    opts.include_untracked(false).exclude_submodules(true);
+    let statuses = repo.statuses(Some(&mut opts)).chain_err(
+        || "Failed reading status of working tree",
+    )?;
+    if let Some(entry) = statuses.iter().next() {
+        bail!(
+            "Checkout of {} failed, {:?} differs from the commit",
+            checksum,
+            String::from_utf8_lossy(entry.path_bytes())
+        );
+    }
     Ok(())
-    // TODO Switch to using libgit when implementing authentication
-    //let mut remote = repo.find_remote("origin").chain_err(
-    //    || "Failed remote-find",
-    //)?;
-    //let refspecs: Vec<String> = remote
-    //    .refspecs()
-    //    .filter_map(|v| v.str().map(|s| s.to_owned()))
-    //    .collect();
-    //let str_refs: Vec<&str> = refspecs.iter().map(|s| s.as_str()).collect();
-    //remote.fetch(str_refs.as_slice(), None, None).chain_err(
-    //    || "Failed fetching",
-    //)
 }
 
+/// Fetch from origin of `repo`, pruning deleted branches. Only `gitref` is fetched if
+/// `remote` is set up for single-branch fetches, else all branches and tags.
+///
+/// Shallow and partial fetches, and fetches of single commits, go through the git CLI.
+pub fn fetch(repo: &Repository, remote: &Remote, gitref: Option<&GitRef>) -> Result<()> {
+    let refspecs = match gitref.and_then(|r| r.refspec()) {
+        Some(refspec) if remote.options.single_branch => vec![refspec],
+        _ => {
+            vec![
+                "+refs/heads/*:refs/remotes/origin/*".to_owned(),
+                "+refs/tags/*:refs/tags/*".to_owned(),
+            ]
+        }
+    };
+    fetch_refspecs(repo, remote, true, &refspecs)
+}
+
+/// Fetch `branch` of the fork `name`, as `owner/reponame`, at `remote` into
+/// `refs/forks/<name>/<branch>`
+pub fn fetch_fork(repo: &Repository, remote: &Remote, name: &str, branch: &str) -> Result<()> {
+    let refspec = format!("+refs/heads/{1}:refs/forks/{0}/{1}", name, branch);
+    fetch_refspecs(repo, remote, false, &[refspec])
+}
+
+/// Fetch `refspecs` from the remote "origin" of `repo`, or else from the url of `remote`
+fn fetch_refspecs(
+    repo: &Repository,
+    remote: &Remote,
+    origin: bool,
+    refspecs: &[String],
+) -> Result<()> {
+    info!(
+        "Fetching {} from {}.",
+        refspecs.join(" "),
+        if origin { "origin" } else { &remote.url }
+    );
+    let commit = refspecs.iter().any(|r| !r.contains(':'));
+    if remote.options.needs_cli() || repo.is_shallow() || commit {
+        return fetch_cli(repo, remote, origin, refspecs);
+    }
+    let source = if origin {
+        repo.find_remote("origin").chain_err(
+            || "Repository has no remote \"origin\"",
+        )
+    } else {
+        repo.remote_anonymous(&remote.url).chain_err(|| {
+            format!("Bad remote url {}", remote.url)
+        })
+    };
+    let mut opts = fetch_options(&remote.credentials);
+    opts.prune(git2::FetchPrune::On);
+    let refspecs: Vec<&str> = refspecs.iter().map(|r| r.as_str()).collect();
+    source?.fetch(&refspecs, Some(&mut opts), None).chain_err(|| {
+        format!("Failed fetching from {}", remote.url)
+    })
+}
+
+fn fetch_cli(
+    repo: &Repository,
+    remote: &Remote,
+    origin: bool,
+    refspecs: &[String],
+) -> Result<()> {
+    let mut args = vec!["fetch".to_owned(), "--prune".to_owned()];
+    match remote.options.depth {
+        Some(depth) => args.push(format!("--depth={}", depth)),
+        // Configured without a depth since the mirror was cloned
+        None if repo.is_shallow() => args.push("--unshallow".to_owned()),
+        None => {}
+    }
+    // git only filters what it fetches from the remote the partial clone was made from
+    if remote.options.blobless && origin {
+        args.push("--filter=blob:none".to_owned());
+    }
+    args.push(if origin { "origin".to_owned() } else { remote.url.clone() });
+    args.extend(refspecs.iter().cloned());
+    git(repo.path(), repo.path(), remote, &args).chain_err(|| {
+        format!("Failed fetching from {}", remote.url)
+    })?;
+    reset_format_version(repo)
+}
+
+/// Let libgit2 open `repo` after git made it a partial clone.
+///
+/// This is a workaround: git marks partial clones as format-version 1, which libgit2
+/// refuses to open, and git still honours `extensions.partialClone` after the reset only
+/// for compatibility with repositories older than format-version 1. libgit2 does not know
+/// partial clones either way, so contents are fetched and checked out by the git CLI
+/// alone, libgit2 only reads the commits and refs of these mirrors.
+fn reset_format_version(repo: &Repository) -> Result<()> {
+    let mut config = repo.config().chain_err(|| "Failed reading config")?;
+    if config.get_i32("core.repositoryformatversion").unwrap_or(0) > 0 {
+        config.set_i32("core.repositoryformatversion", 0).chain_err(
+            || "Failed resetting format-version",
+        )?;
+    }
+    Ok(())
+}
+
+/// Fetch the full history of `mirror`, if it is shallow
+pub fn unshallow(mirror: &Repository, remote: &Remote) -> Result<()> {
+    if !mirror.is_shallow() {
+        return Ok(());
+    }
+    info!("Fetching full history of {:?}", mirror.path());
+    let mut args = vec!["fetch".to_owned(), "--unshallow".to_owned()];
+    if remote.options.blobless {
+        args.push("--filter=blob:none".to_owned());
+    }
+    args.push("origin".to_owned());
+    git(mirror.path(), mirror.path(), remote, &args).chain_err(|| {
+        format!("Failed fetching full history from {}", remote.url)
+    })?;
+    reset_format_version(mirror)
+}
+
+/// Run git with `args` in `dir`, of the repository mirrored in `mirror`, authenticating
+/// with the credentials of `remote`. Fails with the output of git if it does.
+fn git(mirror: &Path, dir: &Path, remote: &Remote, args: &[String]) -> Result<()> {
+    check_git_version()?;
+    let mut cmd = Command::new("git");
+    cmd.arg("-C").arg(dir).env("GIT_TERMINAL_PROMPT", "0");
+    match remote.credentials {
+        Credentials::Default => {}
+        Credentials::SshKey {
+            ref key,
+            ref passphrase,
+        } => {
+            let key = key.to_string_lossy().replace('\'', "'\\''");
+            cmd.env(
+                "GIT_SSH_COMMAND",
+                format!("ssh -i '{}' -o IdentitiesOnly=yes", key),
+            );
+            if let Some(ref passphrase) = *passphrase {
+                cmd.env("SSH_ASKPASS", askpass(mirror)?)
+                    .env("SSH_ASKPASS_REQUIRE", "force")
+                    .env("DISPLAY", ":0")
+                    .env("RUPERT_SSH_PASSPHRASE", passphrase);
+            }
+        }
+        Credentials::UserPass {
+            ref username,
+            ref password,
+        } => {
+            // Secrets are passed in the environment, never as arguments
+            cmd.args(["-c", "credential.helper=", "-c", CREDENTIAL_HELPER])
+                .env("RUPERT_GIT_USERNAME", username)
+                .env("RUPERT_GIT_PASSWORD", password);
+        }
+    }
+    debug!("Running git {}", args.join(" "));
+    let output = cmd.args(args).output().chain_err(|| "Failed running git")?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Path of the askpass-program in `mirror`, written on first use
+fn askpass(mirror: &Path) -> Result<PathBuf> {
+    let path = mirror.join("rupert-askpass");
+    if !path.exists() {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o700)
+            .open(&path)
+            .and_then(|mut file| file.write_all(ASKPASS.as_bytes()))
+            .chain_err(|| format!("Failed writing {:?}", path))?;
+    }
+    Ok(path)
+}
+
+/// Fail unless git is at least `MIN_GIT_VERSION`, checked once per process
+fn check_git_version() -> Result<()> {
+    static CHECKED: OnceLock<::std::result::Result<(), String>> = OnceLock::new();
+    CHECKED
+        .get_or_init(|| git_version().map_err(|e| e.to_string()))
+        .clone()
+        .map_err(Error::from)
+}
+
+fn git_version() -> Result<()> {
+    let output = Command::new("git").arg("--version").output().chain_err(
+        || "git is required for shallow and partial clones, but could not be run",
+    )?;
+    // "git version 2.39.5", possibly followed by the vendor's version
+    let text = String::from_utf8_lossy(&output.stdout);
+    let version: Vec<u32> = text.split_whitespace()
+        .nth(2)
+        .unwrap_or_default()
+        .split('.')
+        .take(2)
+        .filter_map(|v| v.parse().ok())
+        .collect();
+    match version.as_slice() {
+        &[major, minor] if (major, minor) >= MIN_GIT_VERSION => Ok(()),
+        _ => {
+            bail!(
+                "git {}.{} or later is required for shallow and partial clones, found \"{}\"",
+                MIN_GIT_VERSION.0,
+                MIN_GIT_VERSION.1,
+                text.trim()
+            )
+        }
+    }
+}
+
+/// Check out commit `checksum` in `repo`, discarding any changes to the working tree
 pub fn checkout(repo: &Repository, checksum: &str) -> Result<()> {
-    // TODO Simplify function calls by using asref to handle both String and &str
-    let path = repo.path().parent().unwrap();
-    info!("Checking out {} ({:?})", checksum, &path);
-    Command::new("git")
-        .current_dir(path)
-        .args(&["checkout", "--recurse-submodules", checksum])
-        .output()
-        .chain_err(|| "Subprocess \"git checkout\" failed")?;
+    info!("Checking out {} ({:?})", checksum, repo.path());
+    let oid = Oid::from_str(checksum).chain_err(|| {
+        format!("Not a valid Oid: \"{}\"", checksum)
+    })?;
+    let commit = repo.find_commit(oid).chain_err(|| {
+        ErrorKind::RefNotFound(format!("Commit {}", checksum))
+    })?;
+    let mut checkout = git2::build::CheckoutBuilder::new();
+    checkout.force().remove_untracked(true).remove_ignored(true);
+    repo.checkout_tree(commit.as_object(), Some(&mut checkout))
+        .chain_err(|| format!("Failed checkout of {}", checksum))?;
+    repo.set_head_detached(oid).chain_err(|| {
+        format!("Failed moving HEAD to {}", checksum)
+    })
+}
+
+/// Check out commit `checksum` of `mirror`, with its submodules, into a worktree at
+/// `path`.
+///
+/// A worktree of `mirror` already at `path` is reused, anything else there is replaced.
+/// Partial clones are checked out by the git CLI, which fetches the missing contents
+/// from `remote`. Submodules are checked out as configured in `remote`.
+pub fn checkout_worktree(
+    mirror: &Repository,
+    path: &Path,
+    checksum: &str,
+    remote: &Remote,
+) -> Result<Repository> {
+    let repo = match open_worktree(mirror, path) {
+        Some(repo) => repo,
+        None => add_worktree(mirror, path, checksum, remote)?,
+    };
+    if remote.options.blobless {
+        if !has_commit(mirror, checksum) {
+            bail!(ErrorKind::RefNotFound(format!("Commit {}", checksum)));
+        }
+        info!("Checking out {} ({:?})", checksum, repo.path());
+        let checkout = ["checkout", "--force", "--detach", checksum];
+        let clean = ["clean", "-ffdxq"];
+        for args in &[&checkout[..], &clean[..]] {
+            let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            git(mirror.path(), path, remote, &args).chain_err(|| {
+                format!("Failed checkout of {}", checksum)
+            })?;
+        }
+    } else {
+        checkout(&repo, checksum)?;
+    }
+    update_submodules(mirror, &repo, remote)?;
+    Ok(repo)
+}
+
+/// The repository at `path`, if it is a worktree of `mirror`
+fn open_worktree(mirror: &Repository, path: &Path) -> Option<Repository> {
+    let repo = match Repository::open(path) {
+        Ok(repo) => repo,
+        Err(_) => return None,
+    };
+    let worktrees = mirror.path().join("worktrees").canonicalize();
+    let gitdir = repo.path().canonicalize();
+    match (worktrees, gitdir) {
+        (Ok(ref worktrees), Ok(ref gitdir)) if gitdir.starts_with(worktrees) => Some(repo),
+        _ => None,
+    }
+}
+
+/// Replace whatever is at `path` with a new worktree of `mirror`, with HEAD at `checksum`.
+///
+/// libgit2 checks out a branch into new worktrees, so a temporary one is created at
+/// `checksum`. Partial clones are left to the git CLI, which adds the worktree without
+/// checking it out, rather than having libgit2 fail on the missing contents.
+fn add_worktree(
+    mirror: &Repository,
+    path: &Path,
+    checksum: &str,
+    remote: &Remote,
+) -> Result<Repository> {
+    if path.exists() {
+        remove_dir_all(path).chain_err(|| format!("Failed removing {:?}", path))?;
+    }
+    prune_worktrees(mirror)?;
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).chain_err(|| format!("Failed creating {:?}", parent))?;
+    }
+    info!("Adding worktree {:?} of {:?}", path, mirror.path());
+    if remote.options.blobless {
+        let args = ["worktree", "add", "--detach", "--no-checkout"];
+        let mut args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        args.push(path.to_string_lossy().into_owned());
+        args.push(checksum.to_owned());
+        git(mirror.path(), mirror.path(), remote, &args).chain_err(|| {
+            format!("Failed adding worktree {:?}", path)
+        })?;
+        return Repository::open(path).chain_err(|| format!("Failed opening worktree {:?}", path));
+    }
+
+    let oid = Oid::from_str(checksum).chain_err(|| {
+        ErrorKind::RefNotFound(format!("Commit {}", checksum))
+    })?;
+    let commit = mirror.find_commit(oid).chain_err(|| {
+        ErrorKind::RefNotFound(format!("Commit {}", checksum))
+    })?;
+    let name = worktree_name(path);
+    let mut branch = mirror
+        .branch(&format!("rupert-worktree/{}", name), &commit, true)
+        .chain_err(|| format!("Failed creating branch for worktree {:?}", path))?;
+    {
+        let mut opts = git2::WorktreeAddOptions::new();
+        opts.reference(Some(branch.get()));
+        mirror.worktree(&name, path, Some(&opts)).chain_err(|| {
+            format!("Failed adding worktree {:?}", path)
+        })?;
+    }
+    let repo = Repository::open(path).chain_err(|| format!("Failed opening worktree {:?}", path))?;
+    repo.set_head_detached(oid).chain_err(|| {
+        format!("Failed moving HEAD to {}", checksum)
+    })?;
+    branch.delete().chain_err(
+        || format!("Failed deleting branch of worktree {:?}", path),
+    )?;
+    Ok(repo)
+}
+
+/// Name of the worktree at `path`, its directory-name made unique by the full path
+fn worktree_name(path: &Path) -> String {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    let name = path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    format!("{}-{:08x}", name, hasher.finish() as u32)
+}
+
+/// Forget the worktrees of `mirror` whose directory is gone, like `git worktree prune`
+pub fn prune_worktrees(mirror: &Repository) -> Result<()> {
+    let names = mirror.worktrees().chain_err(|| "Failed reading worktrees")?;
+    for name in names.iter().flatten() {
+        let worktree = mirror.find_worktree(name).chain_err(|| {
+            format!("Failed opening worktree {}", name)
+        })?;
+        if worktree.is_prunable(None).unwrap_or(false) {
+            info!("Pruning stale worktree {}", name);
+            worktree.prune(None).chain_err(
+                || format!("Failed pruning worktree {}", name),
+            )?;
+        }
+    }
     Ok(())
+}
+
+/// Check out the submodules of `repo`, a worktree of `mirror`, at the commits recorded
+/// in HEAD, as configured in `remote`.
+///
+/// Each submodule is mirrored within `mirror` and checked out as a worktree of that.
+fn update_submodules(mirror: &Repository, repo: &Repository, remote: &Remote) -> Result<()> {
+    if remote.submodules.strategy == SubmoduleStrategy::None {
+        return Ok(());
+    }
+    let workdir = repo.workdir().ok_or("Repository has no working directory")?;
+    let submodules = repo.submodules().chain_err(|| "Failed reading submodules")?;
+    let mut pending = Vec::new();
+    for mut submodule in submodules {
+        let name = submodule.name().unwrap_or_default().to_owned();
+        let commit = submodule.head_id().ok_or(format!(
+            "Submodule {} is not in HEAD",
+            name
+        ))?;
+        // Resolves urls relative to the superproject into the config
+        submodule.init(false).chain_err(|| {
+            format!("Failed initializing submodule {}", name)
+        })?;
+        let url = repo.config()
+            .and_then(|c| c.get_string(&format!("submodule.{}.url", name)))
+            .chain_err(|| format!("Submodule {} has no url", name))?;
+        pending.push(PendingSubmodule {
+            name,
+            commit: commit.to_string(),
+            remote: remote.submodule(url),
+            path: workdir.join(submodule.path()),
+        });
+    }
 
-    // TODO Use git2 for this
-    //let oid = Oid::from_str(checksum).chain_err(|| {
-    //format!("Not a valid Oid: \"{}\"", checksum)
-    //})?;
-    //repo.reset(&oid, git2::ResetType::Hard, None).chain_err(|| "failed checkout")
+    let mirrors = mirror.path().join("modules");
+    let jobs = cmp::min(cmp::max(remote.submodules.jobs, 1), pending.len());
+    let pending = Arc::new(Mutex::new(pending));
+    let workers: Vec<_> = (0..jobs)
+        .map(|_| {
+            let pending = pending.clone();
+            let mirrors = mirrors.clone();
+            thread::spawn(move || -> Result<()> {
+                loop {
+                    let next = pending.lock().expect("Submodules poisoned").pop();
+                    match next {
+                        Some(submodule) => update_submodule(&mirrors, submodule)?,
+                        None => return Ok(()),
+                    }
+                }
+            })
+        })
+        .collect();
+    let mut res = Ok(());
+    for worker in workers {
+        let worker_res = worker.join().unwrap_or_else(|_| {
+            Err("Checkout of submodules panicked".into())
+        });
+        if res.is_ok() {
+            res = worker_res;
+        }
+    }
+    res
 }
 
-pub fn init_repo(path: &Path, url: &str) -> Result<Repository> {
+/// Fetch `submodule` into its mirror in `mirrors` and check it out
+fn update_submodule(mirrors: &Path, submodule: PendingSubmodule) -> Result<()> {
+    let res = init_mirror(&mirrors.join(&submodule.name), &submodule.remote).and_then(
+        |mirror| {
+            if !has_commit(&mirror, &submodule.commit) {
+                fetch(&mirror, &submodule.remote, None)?;
+            }
+            checkout_worktree(&mirror, &submodule.path, &submodule.commit, &submodule.remote)
+        },
+    );
+    res.map(|_| ()).chain_err(|| {
+        format!(
+            "Failed checkout of submodule {} from {}",
+            submodule.name,
+            submodule.remote.url
+        )
+    })
+}
+
+/// Replace the Git LFS pointers in `repo`, a worktree of `mirror`, with their contents.
+///
+/// Objects are kept in the mirror, shared by all builds of the repository.
+pub fn lfs_pull(mirror: &Repository, repo: &Repository, remote: &Remote) -> Result<()> {
+    let workdir = repo.workdir().ok_or("Repository has no working directory")?;
+    let installed = Command::new("git")
+        .args(["lfs", "version"])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false);
+    if !installed {
+        bail!("Git LFS is enabled for the repository, but `git lfs` could not be run");
+    }
+    let storage = mirror.path().join("lfs");
+    mirror
+        .config()
+        .and_then(|mut config| {
+            config.set_str("lfs.storage", &storage.to_string_lossy())
+        })
+        .chain_err(|| "Failed configuring storage of Git LFS")?;
+    info!("Fetching Git LFS objects of {:?}", workdir);
+    let args = ["lfs".to_owned(), "pull".to_owned(), "origin".to_owned()];
+    git(mirror.path(), workdir, remote, &args).chain_err(
+        || "Failed fetching Git LFS objects",
+    )
+}
+
+/// Options for fetching with `credentials`
+fn fetch_options<'a>(credentials: &Credentials) -> git2::FetchOptions<'a> {
+    let credentials = credentials.clone();
+    let mut tried = git2::CredentialType::empty();
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| {
+        // Called again after every rejection, each method is only tried once
+        if allowed.contains(git2::CredentialType::USERNAME) {
+            return git2::Cred::username(username.unwrap_or("git"));
+        }
+        let untried = |kind| allowed.contains(kind) && !tried.contains(kind);
+        let (kind, cred) = match credentials {
+            Credentials::Default if untried(git2::CredentialType::SSH_KEY) => {
+                (
+                    git2::CredentialType::SSH_KEY,
+                    git2::Cred::ssh_key_from_agent(username.unwrap_or("git")),
+                )
+            }
+            Credentials::Default if untried(git2::CredentialType::USER_PASS_PLAINTEXT) => {
+                let cred = git2::Config::open_default().and_then(|config| {
+                    git2::Cred::credential_helper(&config, url, username)
+                });
+                (git2::CredentialType::USER_PASS_PLAINTEXT, cred)
+            }
+            Credentials::SshKey {
+                ref key,
+                ref passphrase,
+            } if untried(git2::CredentialType::SSH_KEY) => {
+                let passphrase = passphrase.as_ref().map(|p| p.as_str());
+                (
+                    git2::CredentialType::SSH_KEY,
+                    git2::Cred::ssh_key(username.unwrap_or("git"), None, key, passphrase),
+                )
+            }
+            Credentials::UserPass {
+                ref username,
+                ref password,
+            } if untried(git2::CredentialType::USER_PASS_PLAINTEXT) => {
+                (
+                    git2::CredentialType::USER_PASS_PLAINTEXT,
+                    git2::Cred::userpass_plaintext(username, password),
+                )
+            }
+            _ => {
+                return Err(git2::Error::from_str(
+                    &format!("No working credentials for {}", url),
+                ))
+            }
+        };
+        tried.insert(kind);
+        cred
+    });
+    let mut opts = git2::FetchOptions::new();
+    opts.remote_callbacks(callbacks);
+    opts
+}
+
+/// Paths, relative to the working directory, of the files in the working tree of `repo`.
+///
+/// Tracked files are included with their uncommitted changes, untracked files only if
+/// `include_untracked` is set. Ignored files are never included.
+pub fn working_tree_files(repo: &Repository, include_untracked: bool) -> Result<Vec<PathBuf>> {
+    let workdir = repo.workdir().ok_or(
+        "Repository has no working directory",
+    )?;
+    let index = repo.index().chain_err(|| "Failed reading index")?;
+    let mut files: Vec<PathBuf> = index
+        .iter()
+        .map(|entry| PathBuf::from(OsStr::from_bytes(&entry.path)))
+        .collect();
+    if include_untracked {
+        let tracked: HashSet<PathBuf> = files.iter().cloned().collect();
+        let mut opts = git2::StatusOptions::new();
+        opts.include_untracked(true)
+            .recurse_untracked_dirs(true)
+            .include_ignored(false);
+        let statuses = repo.statuses(Some(&mut opts)).chain_err(
+            || "Failed reading status of working tree",
+        )?;
+        for entry in statuses.iter() {
+            let path = PathBuf::from(OsStr::from_bytes(entry.path_bytes()));
+            if !tracked.contains(&path) {
+                files.push(path);
+            }
+        }
+    }
+    // Conflicted files have several index-entries and tracked files may be deleted
+    files.sort();
+    files.dedup();
+    files.retain(|path| workdir.join(path).symlink_metadata().is_ok());
+    Ok(files)
+}
+
+/// Open the bare mirror at `path`, creating it with `remote` as origin if it does not
+/// exist. Nothing is fetched.
+///
+/// The url of origin is updated if the mirror was created for elsewhere.
+pub fn init_mirror(path: &Path, remote: &Remote) -> Result<Repository> {
     match Repository::open(path) {
         Ok(repo) => {
-            info!("Found local repo at {:?}.", path);
+            info!("Found mirror at {:?}.", path);
+            let url = repo.find_remote("origin")
+                .ok()
+                .and_then(|origin| origin.url().map(String::from));
+            if url.as_ref() != Some(&remote.url) {
+                info!("Changing url of origin to {}", remote.url);
+                repo.remote_set_url("origin", &remote.url).chain_err(|| {
+                    format!("Failed changing url of origin in {:?}", path)
+                })?;
+            }
             Ok(repo)
         }
         Err(e) => {
             warn!(
-                "Could not load local repository at {:?} due to {:?}, \
-                attempting cloning",
+                "Could not load mirror at {:?} due to {}, creating it",
                 path,
-                e.description()
+                e.message()
             );
-            Ok(clone_recurse(&url, &path).chain_err(
-                || "Failed clone_recurse",
-            )?)
+            let repo = Repository::init_bare(path).chain_err(|| {
+                format!("Failed creating mirror in {:?}", path)
+            })?;
+            repo.remote("origin", &remote.url).chain_err(|| {
+                format!("Failed adding origin {} to {:?}", remote.url, path)
+            })?;
+            Ok(repo)
         }
     }
 }
-
-fn clone_recurse(url: &str, path: &Path) -> Result<Repository> {
-    info!("Cloning {} into {:?}.", url, path);
-    // TODO Implement this with git2. Authentication is troublesome. Cargo' auth-code:
-    // https://github.com/rust-lang/cargo/blob/def249f9c18280d84f29fd96978389689fb61051/src/cargo/sources/git/utils.rs#L360
-    //Repository::clone_recurse(&url, &path)
-
-
-    // For now, defer to system-git
-    let output = Command::new("git")
-        .arg("clone")
-        .arg("--recursive")
-        .arg("--jobs")
-        .arg("8")
-        .arg(url)
-        .arg(path)
-        .output()
-        .chain_err(|| "Subprocess \"git clone\" failed")?;
-    let repo = Repository::open(path).chain_err(
-        || "Failed opening cloned repo",
-    )?;
-    Ok(repo)
-}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use curl::easy::{Easy, List};
+
+use errors::*;
+
+/// A response to a request
+pub struct Response {
+    pub code: u32,
+    pub body: Vec<u8>,
+}
+
+/// POST `body` to `url`, failing unless the response is a success (2xx)
+pub fn post(url: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<Response> {
+    let mut easy = Easy::new();
+    easy.url(url).chain_err(|| format!("Bad url {:?}", url))?;
+    easy.post(true).chain_err(|| "Failed setting up POST")?;
+    easy.post_fields_copy(body).chain_err(
+        || "Failed setting up POST",
+    )?;
+    perform(easy, url, headers)
+}
+
+/// GET `url`, failing unless the response is a success (2xx)
+pub fn get(url: &str, headers: &[(&str, &str)]) -> Result<Response> {
+    let mut easy = Easy::new();
+    easy.url(url).chain_err(|| format!("Bad url {:?}", url))?;
+    perform(easy, url, headers)
+}
+
+fn perform(mut easy: Easy, url: &str, headers: &[(&str, &str)]) -> Result<Response> {
+    let mut list = List::new();
+    for &(key, value) in headers {
+        list.append(&format!("{}: {}", key, value)).chain_err(
+            || "Bad header",
+        )?;
+    }
+    easy.http_headers(list).chain_err(|| "Bad headers")?;
+    easy.timeout(Duration::from_secs(30)).chain_err(
+        || "Failed setting timeout",
+    )?;
+
+    let mut body = Vec::new();
+    {
+        let mut transfer = easy.transfer();
+        transfer
+            .write_function(|data| {
+                body.extend_from_slice(data);
+                Ok(data.len())
+            })
+            .chain_err(|| "Failed setting up transfer")?;
+        transfer.perform().chain_err(
+            || format!("Request to {} failed", url),
+        )?;
+    }
+    let code = easy.response_code().chain_err(
+        || "Failed reading response-code",
+    )?;
+    if !(200..300).contains(&code) {
+        bail!(
+            "Request to {} failed with {}: {}",
+            url,
+            code,
+            String::from_utf8_lossy(&body)
+        );
+    }
+    Ok(Response { code, body })
+}
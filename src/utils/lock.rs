@@ -0,0 +1,155 @@
+//! Advisory locks on files, keeping concurrent runners off each other's mirror and
+//! build-dir
+//!
+//! Locks are `flock`s, released by the kernel when their holder exits. The holder's pid
+//! is written into the file, to report whom a runner waits for. A lock file removed
+//! while locked, as with unused build-dirs, is recreated by whoever locks it next.
+
+use std::fs::{File, OpenOptions, create_dir_all, metadata};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+use errors::*;
+
+/// How often a held lock is checked while waiting for it
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A held lock, released when dropped
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Lock `path`, waiting for as long as a running process holds it
+    pub fn acquire(path: &Path) -> Result<Lock> {
+        lock(path, true).map(|lock| lock.expect("Waited for lock"))
+    }
+
+    /// Lock `path`, unless a running process holds it
+    pub fn try_acquire(path: &Path) -> Result<Option<Lock>> {
+        lock(path, false)
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) } != 0 {
+            warn!(
+                "Failed releasing lock {:?}: {}",
+                self.path,
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+fn lock(path: &Path, wait: bool) -> Result<Option<Lock>> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).chain_err(|| {
+            format!("Failed creating directory of lock {:?}", path)
+        })?;
+    }
+    let mut waiting = false;
+    loop {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .chain_err(|| format!("Failed opening lock {:?}", path))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            // The lock may have been removed after it was opened
+            if !is_file_at(&file, path) {
+                continue;
+            }
+            file.set_len(0)
+                .and_then(|_| file.write_all(format!("{}\n", process::id()).as_bytes()))
+                .chain_err(|| format!("Failed writing pid into lock {:?}", path))?;
+            return Ok(Some(Lock {
+                file,
+                path: path.to_owned(),
+            }));
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(err).chain_err(|| format!("Failed locking {:?}", path));
+        }
+        if !wait {
+            return Ok(None);
+        }
+        if !waiting {
+            match holder(&mut file) {
+                Some(pid) => info!("Waiting for lock {:?} held by process {}", path, pid),
+                None => info!("Waiting for lock {:?}", path),
+            }
+            waiting = true;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Whether `file` is still the file at `path`
+fn is_file_at(file: &File, path: &Path) -> bool {
+    match (file.metadata(), metadata(path)) {
+        (Ok(opened), Ok(current)) => {
+            opened.dev() == current.dev() && opened.ino() == current.ino()
+        }
+        _ => false,
+    }
+}
+
+/// Pid written into the lock by its holder, if it got to write it yet
+fn holder(file: &mut File) -> Option<i32> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_to_string(&mut contents))
+        .ok()
+        .and_then(|_| contents.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{File, remove_file};
+    use std::io::Write;
+    use std::process::Command;
+
+    use utils::lock::Lock;
+    use utils::tests::TEST_DIR;
+
+    #[test]
+    fn test_lock() {
+        let path = TEST_DIR.join("test_lock").join("repo.lock");
+        let lock = Lock::acquire(&path).unwrap();
+        assert!(Lock::try_acquire(&path).unwrap().is_none());
+        drop(lock);
+        let lock = Lock::try_acquire(&path).unwrap().unwrap();
+
+        // Still held, whichever pid it names
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        File::create(&path)
+            .unwrap()
+            .write_all(format!("{}\n", child.id()).as_bytes())
+            .unwrap();
+        assert!(Lock::try_acquire(&path).unwrap().is_none());
+
+        // Removed while held, the lock is taken anew
+        remove_file(&path).unwrap();
+        let recreated = Lock::try_acquire(&path).unwrap().unwrap();
+        drop(lock);
+        assert!(Lock::try_acquire(&path).unwrap().is_none());
+        drop(recreated);
+        assert!(Lock::try_acquire(&path).unwrap().is_some());
+    }
+}
@@ -1,22 +1,32 @@
 
 use std::io::Read;
-use std::fs::{DirBuilder, File, create_dir, read_dir, copy};
-use std::error::Error;
+use std::fs::File;
 use std::fmt;
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use toml;
 use yansi::Paint;
 
+use executor;
+use limits;
+use users;
 use BuildInstruction;
-use BuildStep;
 use errors::*;
 use integrations::Integrations;
+use notify::BuildEvent;
 
+pub mod copy;
 pub mod git;
+pub mod http;
+pub mod lock;
+pub mod secrets;
 
-const FNAME_CONFIG: &'static str = "rupert-conf.toml";
+use self::secrets::Secrets;
+
+const FNAME_CONFIG: &str = "rupert-conf.toml";
 
 
 #[derive(Deserialize, Debug)]
@@ -28,12 +38,67 @@ struct RawConfig {
 #[derive(Clone, Deserialize, Debug)]
 pub struct MetaConfig {
     pub build_root: PathBuf,
+    /// Relay used for email-notifications, which are disabled without it
+    pub smtp: Option<SmtpConfig>,
+    /// Public address of rupert, used for linking to builds
+    pub public_url: Option<String>,
+    /// Bearer-tokens accepted by the HTTP-API, which is disabled without any
+    #[serde(default)]
+    pub api_tokens: Vec<String>,
+    /// Secrets-store referred to by the repositories, see `utils::secrets`
+    pub secrets: Option<PathBuf>,
+    /// Cgroup v2 delegated to rupert, for the `memory` and `cpus` limits of steps
+    pub cgroup: Option<PathBuf>,
+    /// Uids dynamic users of repositories are allocated from
+    #[serde(default)]
+    pub dynamic_users: users::UidRange,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    /// Sender-address of notifications
+    pub from: String,
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+/// An incoming webhook of a chat, such as Slack or Mattermost
+#[derive(Clone, Deserialize, Debug)]
+pub struct ChatConfig {
+    pub url: String,
+    /// Message-template, see `notify::chat::render` for placeholders
+    pub template: Option<String>,
+    /// Events posted about, all of them by default
+    #[serde(default = "default_chat_events")]
+    pub events: Vec<BuildEvent>,
+}
+
+/// Subscription to the build-events of a repository, see `notify::webhook`
+#[derive(Clone, Deserialize, Debug)]
+pub struct WebhookConfig {
+    /// Unique per repository, names the delivery-log
+    pub name: String,
+    pub url: String,
+    /// Key for signing events
+    pub secret: String,
+}
+
+fn default_chat_events() -> Vec<BuildEvent> {
+    vec![BuildEvent::Started, BuildEvent::Failed, BuildEvent::Fixed]
 }
 
 #[derive(Debug)]
 pub struct Config {
     pub meta: MetaConfig,
     pub repos: HashMap<(String, String), RepoConfig>,
+    pub secrets: Secrets,
+    /// The configuration-file and the secrets-store, hidden from sandboxed steps
+    pub private_files: Vec<PathBuf>,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -43,9 +108,177 @@ pub struct RepoConfig {
     pub reponame: String,
     pub api_token: String,
     pub build_instruction: BuildInstruction,
+    /// Notified, along with the commit-author, when a branch fails or is fixed
+    #[serde(default)]
+    pub notify_emails: Vec<String>,
+    /// Chat-webhooks posted to about builds
+    #[serde(default)]
+    pub chat: Vec<ChatConfig>,
+    /// Webhooks forwarded all build-events
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    /// Secret of the Bitbucket-webhook, whose events are rejected without it
+    pub webhook_secret: Option<String>,
+    /// Required as `?token=` for the status-badge, which is public without it
+    pub badge_token: Option<String>,
+    /// Clone-url, overriding the one of the integration
+    pub url: Option<String>,
+    /// How to authenticate against the remote, ssh-agent and git's credential-helpers
+    /// are tried without it
+    pub auth: Option<GitAuth>,
+    /// How much history is fetched
+    #[serde(default)]
+    pub clone: git::CloneOptions,
+    /// How local working trees are copied into the build-directory
+    #[serde(default)]
+    pub copy: copy::CopyOptions,
+    /// Which submodules are checked out, and how
+    #[serde(default)]
+    pub submodules: SubmoduleConfig,
+    /// Build pull-requests merged into their target-branch, rather than their source
+    #[serde(default)]
+    pub merge_pull_requests: bool,
+    /// Users whose pushes and pull-requests, and owners of forks whose pull-requests,
+    /// are built without approval, without it all but pull-requests from forks are
+    pub trusted: Option<Vec<String>>,
+    /// Environment-variables of the build-steps set to secrets, by name, withheld from
+    /// untrusted builds
+    #[serde(default)]
+    pub secret_env: BTreeMap<String, String>,
+    /// How build-steps are run, on the host by default
+    #[serde(default)]
+    pub executor: executor::ExecutorConfig,
+    /// Limits of every step
+    #[serde(default)]
+    pub limits: limits::Limits,
+    /// User the steps run as, rather than as rupert
+    pub user: Option<users::UserConfig>,
 }
 
-#[derive(Debug)]
+impl RepoConfig {
+    /// Credentials of `auth`, with the secrets it refers to looked up in `secrets`
+    pub fn credentials(&self, secrets: &Secrets) -> Result<git::Credentials> {
+        match self.auth {
+            Some(ref auth) => auth.credentials(secrets),
+            None => Ok(git::Credentials::Default),
+        }
+    }
+
+    /// `secret_env` with the secrets looked up in `secrets`
+    pub fn secret_env(&self, secrets: &Secrets) -> Result<BTreeMap<String, String>> {
+        let mut env = BTreeMap::new();
+        for (var, name) in &self.secret_env {
+            env.insert(var.clone(), secrets.get(name)?.to_owned());
+        }
+        Ok(env)
+    }
+
+    /// User the steps run as, allocated if dynamic
+    pub fn user(&self, meta: &MetaConfig) -> Result<Option<users::User>> {
+        match self.user {
+            Some(ref user) => {
+                let name = format!("{}/{}", self.owner, self.reponame);
+                user.user(&meta.build_root, &meta.dynamic_users, &name)
+                    .map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// How submodules are checked out, with the secrets of their `auth` looked up in
+    /// `secrets`
+    pub fn submodules(&self, secrets: &Secrets) -> Result<git::Submodules> {
+        let mut credentials = Vec::new();
+        for auth in &self.submodules.auth {
+            credentials.push((auth.url.clone(), auth.auth.credentials(secrets)?));
+        }
+        Ok(git::Submodules {
+            strategy: self.submodules.strategy,
+            jobs: self.submodules.jobs,
+            credentials,
+        })
+    }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct SubmoduleConfig {
+    /// `none`, `top-level` or `recursive`
+    #[serde(default)]
+    pub strategy: git::SubmoduleStrategy,
+    /// Submodules fetched and checked out at the same time
+    #[serde(default = "default_submodule_jobs")]
+    pub jobs: usize,
+    /// Authentication against submodule-remotes, instead of `auth` of the repository
+    #[serde(default)]
+    pub auth: Vec<SubmoduleAuth>,
+}
+
+fn default_submodule_jobs() -> usize {
+    1
+}
+
+impl Default for SubmoduleConfig {
+    fn default() -> SubmoduleConfig {
+        SubmoduleConfig {
+            strategy: git::SubmoduleStrategy::default(),
+            jobs: default_submodule_jobs(),
+            auth: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct SubmoduleAuth {
+    /// Used for the submodules whose url starts with this
+    pub url: String,
+    pub auth: GitAuth,
+}
+
+/// Authentication against the remote of a repository, secrets are given by their name
+/// in the secrets-store
+#[derive(Clone, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum GitAuth {
+    /// A private key, the remote is cloned over ssh
+    Ssh {
+        key: PathBuf,
+        passphrase: Option<String>,
+    },
+    /// A username with a password, app-password or token, the remote is cloned over https
+    Https { username: String, password: String },
+}
+
+impl GitAuth {
+    /// Credentials with the secrets referred to looked up in `secrets`
+    pub fn credentials(&self, secrets: &Secrets) -> Result<git::Credentials> {
+        Ok(match *self {
+            GitAuth::Ssh {
+                ref key,
+                ref passphrase,
+            } => {
+                git::Credentials::SshKey {
+                    key: key.clone(),
+                    passphrase: match *passphrase {
+                        Some(ref name) => Some(secrets.get(name)?.to_owned()),
+                        None => None,
+                    },
+                }
+            }
+            GitAuth::Https {
+                ref username,
+                ref password,
+            } => {
+                git::Credentials::UserPass {
+                    username: username.clone(),
+                    password: secrets.get(password)?.to_owned(),
+                }
+            }
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", content = "data")]
 pub enum TextOutput {
     Stdout(String),
     Stderr(String),
@@ -53,14 +286,15 @@ pub enum TextOutput {
 
 impl fmt::Display for TextOutput {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &TextOutput::Stdout(ref s) => write!(f, "{}", s),
-            &TextOutput::Stderr(ref s) => write!(f, "{}", Paint::red(s)),
+        match *self {
+            TextOutput::Stdout(ref s) => write!(f, "{}", s),
+            TextOutput::Stderr(ref s) => write!(f, "{}", Paint::red(s)),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", content = "data")]
 pub enum BuildUpdates {
     Started,
     StepStarted(String),
@@ -71,17 +305,17 @@ pub enum BuildUpdates {
 
 impl fmt::Display for BuildUpdates {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &BuildUpdates::Started => write!(f, "{}", Paint::yellow("Starting build")),
-            &BuildUpdates::StepStarted(ref s) => {
+        match *self {
+            BuildUpdates::Started => write!(f, "{}", Paint::yellow("Starting build")),
+            BuildUpdates::StepStarted(ref s) => {
                 write!(
                     f,
                     "{}",
                     Paint::yellow(format!("{}: {}", "Starting step", s))
                 )
             }
-            &BuildUpdates::StepNewOutput(ref s) => write!(f, "{}", s),
-            &BuildUpdates::StepFinished(ref res) => {
+            BuildUpdates::StepNewOutput(ref s) => write!(f, "{}", s),
+            BuildUpdates::StepFinished(ref res) => {
                 match res.status {
 
                     ::BuildStatus::Successful => {
@@ -90,96 +324,625 @@ impl fmt::Display for BuildUpdates {
                     ::BuildStatus::Failed => {
                         write!(f, "{}", Paint::red("Finished build-step with failure"))
                     }
-                    _ => unimplemented!(),
+                    ref status => {
+                        write!(
+                            f,
+                            "{}",
+                            Paint::red(format!("Finished build-step as {:?}", status))
+                        )
+                    }
+                }?;
+                match res.tests {
+                    Some(ref tests) => write!(f, " ({})", tests),
+                    None => Ok(()),
                 }
             }
-            &BuildUpdates::Finished => write!(f, "{}", Paint::yellow("Finished  build")),
+            BuildUpdates::Finished => write!(f, "{}", Paint::yellow("Finished  build")),
         }
 
     }
 }
 
+/// Call `f` until it succeeds, at most `attempts` times, doubling the delay after each
+/// failed attempt. The error of the last attempt is returned if all fail.
+pub fn retry<T, F>(attempts: u32, delay: Duration, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let mut delay = delay;
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(val) => return Ok(val),
+            Err(e) => {
+                if attempt >= attempts {
+                    return Err(e);
+                }
+                warn!("Attempt {} of {} failed, retrying in {:?}: {}", attempt, attempts, delay, e);
+                sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Part of terminal output, as split by `parse_ansi`
+#[derive(PartialEq, Debug)]
+pub enum Ansi<'a> {
+    /// Plain text
+    Text(&'a str),
+    /// The `;`-separated parameters of a color-sequence, `ESC [ .. m`
+    Colors(&'a str),
+}
+
+/// Split `text` into plain text and color-sequences, other escape-sequences are dropped
+pub fn parse_ansi(text: &str) -> Vec<Ansi<'_>> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('\u{1b}') {
+        if start > 0 {
+            parts.push(Ansi::Text(&rest[..start]));
+        }
+        rest = &rest[start + 1..];
+        if !rest.starts_with('[') {
+            // Two-character sequence, drop the character after ESC
+            let skip = rest.chars().next().map(|c| c.len_utf8()).unwrap_or(0);
+            rest = &rest[skip..];
+            continue;
+        }
+        rest = &rest[1..];
+        // Parameters until the final byte in range '@'..='~'
+        match rest.find(|c| ('@'..='~').contains(&c)) {
+            Some(end) => {
+                if rest[end..].starts_with('m') {
+                    parts.push(Ansi::Colors(&rest[..end]));
+                }
+                rest = &rest[end + 1..];
+            }
+            None => rest = "",
+        }
+    }
+    if !rest.is_empty() {
+        parts.push(Ansi::Text(rest));
+    }
+    parts
+}
+
+/// Remove terminal escape-sequences, such as colors, from `text`
+pub fn strip_ansi(text: &str) -> String {
+    parse_ansi(text)
+        .into_iter()
+        .filter_map(|part| match part {
+            Ansi::Text(text) => Some(text),
+            Ansi::Colors(_) => None,
+        })
+        .collect()
+}
+
 pub fn load_config(path: Option<PathBuf>) -> Result<Config> {
     let path = path.unwrap_or(Path::new(FNAME_CONFIG).into());
-    let mut file = File::open(path).chain_err(|| {
+    let mut file = File::open(&path).chain_err(|| {
         format!("Failed opening {}", FNAME_CONFIG)
     })?;
     let mut contents = String::new();
     file.read_to_string(&mut contents).chain_err(
         || "Could not read contents of file",
     )?;
-    let mut raw: RawConfig = toml::from_str(&contents).chain_err(
+    let raw: RawConfig = toml::from_str(&contents).chain_err(
         || "Bad format in config-file",
     )?;
     let meta = raw.meta;
+    let secrets = match meta.secrets {
+        Some(ref path) => Secrets::load(path)?,
+        None => Secrets::default(),
+    };
     let mut repos = HashMap::new();
     for repo in raw.repos.into_iter() {
+        // Missing secrets are reported now rather than at the first build
+        repo.credentials(&secrets).chain_err(|| {
+            format!("Bad auth of {}/{}", repo.owner, repo.reponame)
+        })?;
+        repo.submodules(&secrets).chain_err(|| {
+            format!("Bad submodule-auth of {}/{}", repo.owner, repo.reponame)
+        })?;
+        repo.secret_env(&secrets).chain_err(|| {
+            format!("Bad secret_env of {}/{}", repo.owner, repo.reponame)
+        })?;
+        let limits_processes = repo.limits.processes.is_some() ||
+            repo.build_instruction.steps.iter().any(
+                |step| step.limits.processes.is_some(),
+            );
+        let in_cgroup = meta.cgroup.is_some() || !repo.executor.executor().cgroup();
+        if limits_processes && repo.user.is_none() && !in_cgroup {
+            bail!(
+                "{}/{} limits processes, which needs a user or a cgroup of its steps",
+                repo.owner,
+                repo.reponame
+            );
+        }
+        if let Some(ref user) = repo.user {
+            for file in Some(&path).into_iter().chain(meta.secrets.as_ref()) {
+                user.check_unreadable(file, &meta.dynamic_users).chain_err(|| {
+                    format!("Steps of {}/{} could read secrets", repo.owner, repo.reponame)
+                })?;
+            }
+        }
         let key = (repo.owner.clone(), repo.reponame.clone());
         repos.insert(key, repo);
     }
-    Ok(Config { meta, repos })
-}
-
-pub fn copy_dir(src: &Path, dst: &Path) -> Result<()> {
-    for entry in read_dir(src).chain_err(
-        || format!("Failed read_dir of {:?}", src),
-    )?
-    {
-        let entry = entry.chain_err(|| "Failed reading entry")?;
-        let path = entry.path();
-        let mut subdst = dst.to_owned();
-        subdst.push(path.file_name().ok_or(format!(
-            "Could not get filename of {:?}",
-            path
-        ))?);
-        if path.is_dir() {
-            create_dir(&subdst).chain_err(|| {
-                format!("Failed to create {:?}", subdst)
-            })?;
-            copy_dir(&path, &subdst).chain_err(
-                || "Failed recursive copy",
-            )?;
-        } else {
-            copy(&path, &subdst).chain_err(|| {
-                format!("Failed copy of {:?} to {:?}", path, subdst)
-            })?;
-        }
-    }
-    Ok(())
+    let private_files = Some(&path)
+        .into_iter()
+        .chain(meta.secrets.as_ref())
+        .map(|file| {
+            file.canonicalize().chain_err(
+                || format!("Failed resolving {:?}", file),
+            )
+        })
+        .collect::<Result<_>>()?;
+    Ok(Config {
+        meta,
+        repos,
+        secrets,
+        private_files,
+    })
 }
 
-
 #[cfg(test)]
-mod tests {
+pub mod tests {
 
     use std::clone::Clone;
-    use std::env::temp_dir;
     use std::fs::File;
-    use std::fs::DirBuilder;
-    use std::io::Write;
+    use std::fs::create_dir_all;
+    use std::io::{Read, Write};
     use std::path::PathBuf;
     use std::env;
+    use std::fs::remove_dir_all;
+    use std::process::{Command, Stdio};
+    use std::thread;
+
+    use git2;
+    use serde_json;
+    use tiny_http;
 
     use utils;
+    use utils::git::{CloneOptions, Credentials, GitRef, Remote, SubmoduleStrategy, Submodules};
 
     lazy_static!{
         pub static ref TEST_DIR: PathBuf = {
             let mut path = env::temp_dir();
             path.push("rupert-tests");
-            DirBuilder::new().create(&path);
+            create_dir_all(&path).unwrap();
             path
         };
     }
 
+    /// Init a repo with a single commit, returning the repo and commit-id
+    fn init_test_repo(name: &str) -> (git2::Repository, git2::Oid) {
+        let mut path = TEST_DIR.clone();
+        path.push(name);
+        let _ = remove_dir_all(&path);
+        let repo = git2::Repository::init(&path).unwrap();
+        let oid = {
+            let sig = git2::Signature::now("Rupert", "rupert@example.com").unwrap();
+            let tree_id = repo.index().unwrap().write_tree().unwrap();
+            let tree = repo.find_tree(tree_id).unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "Initial", &tree, &[])
+                .unwrap()
+        };
+        (repo, oid)
+    }
+
+    #[test]
+    fn test_resolve_ref() {
+        let (repo, oid) = init_test_repo("test_resolve_ref");
+        let full = oid.to_string();
+        repo.reference("refs/remotes/origin/Main", oid, false, "test")
+            .unwrap();
+        repo.reference("refs/tags/v1.0", oid, false, "test").unwrap();
+
+        let resolve = |r: GitRef| utils::git::resolve_ref(&repo, &r);
+        assert_eq!(resolve(GitRef::Commit(full[..7].into())).unwrap(), full);
+        assert_eq!(resolve(GitRef::Commit(full.to_uppercase())).unwrap(), full);
+        assert_eq!(resolve(GitRef::Branch("Main".into())).unwrap(), full);
+        assert_eq!(resolve(GitRef::Tag("v1.0".into())).unwrap(), full);
+        assert!(resolve(GitRef::Branch("main".into())).is_err());
+        assert!(resolve(GitRef::Tag("v2.0".into())).is_err());
+        assert!(resolve(GitRef::Commit("HEAD".into())).is_err());
+        assert!(resolve(GitRef::Commit("deadbeef".into())).is_err());
+    }
+
+    /// Commit `name` with `contents` on top of HEAD of `repo`
+    fn commit_file(repo: &git2::Repository, name: &str, contents: &str) -> git2::Oid {
+        let workdir = repo.workdir().unwrap().to_owned();
+        File::create(workdir.join(name))
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(name.as_ref()).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let parent = repo.find_commit(repo.head().unwrap().target().unwrap())
+            .unwrap();
+        let sig = git2::Signature::now("Rupert", "rupert@example.com").unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, name, &tree, &[&parent])
+            .unwrap()
+    }
+
+    #[test]
+    fn test_fetch_and_checkout() {
+        let (origin, _) = init_test_repo("test_fetch_and_checkout_origin");
+        let first = commit_file(&origin, "file", "first").to_string();
+        let branch = origin.head().unwrap().shorthand().unwrap().to_owned();
+
+        let path = TEST_DIR.join("test_fetch_and_checkout");
+        let worktree = TEST_DIR.join("test_fetch_and_checkout_worktree");
+        let _ = remove_dir_all(&path);
+        let _ = remove_dir_all(&worktree);
+        let remote = Remote {
+            url: origin.workdir().unwrap().to_str().unwrap().to_owned(),
+            credentials: Credentials::Default,
+            options: CloneOptions::default(),
+            submodules: Submodules::default(),
+        };
+        let mirror = utils::git::init_mirror(&path, &remote).unwrap();
+        assert!(mirror.is_bare());
+        utils::git::fetch(&mirror, &remote, None).unwrap();
+        let second = commit_file(&origin, "file", "second").to_string();
+        assert!(!utils::git::has_commit(&mirror, &second));
+
+        utils::git::fetch(&mirror, &remote, None).unwrap();
+        let head = utils::git::resolve_ref(&mirror, &GitRef::Branch(branch)).unwrap();
+        assert_eq!(head, second);
+        for commit in &[&second, &first] {
+            let repo = utils::git::checkout_worktree(&mirror, &worktree, commit, &remote)
+                .unwrap();
+            utils::git::verify_head(&repo, commit).unwrap();
+        }
+        let mut contents = String::new();
+        File::open(worktree.join("file"))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "first");
+
+        // Local changes are discarded, and detected if not
+        let repo = git2::Repository::open(&worktree).unwrap();
+        File::create(worktree.join("file")).unwrap().write_all(b"dirty").unwrap();
+        File::create(worktree.join("untracked")).unwrap();
+        assert!(utils::git::verify_head(&repo, &first).is_err());
+        utils::git::checkout_worktree(&mirror, &worktree, &first, &remote).unwrap();
+        utils::git::verify_head(&repo, &first).unwrap();
+        assert!(!worktree.join("untracked").exists());
+
+        // Worktrees are forgotten once their directory is gone
+        let worktrees = || ::std::fs::read_dir(path.join("worktrees")).unwrap().count();
+        assert_eq!(worktrees(), 1);
+        remove_dir_all(&worktree).unwrap();
+        utils::git::prune_worktrees(&mirror).unwrap();
+        assert_eq!(worktrees(), 0);
+        // Nor is the branch checked out into the new worktree left behind
+        assert!(mirror.branches(None).unwrap().all(|branch| {
+            !branch.unwrap().0.name().unwrap().unwrap().starts_with("rupert-worktree/")
+        }));
+
+        let missing = "0123456789abcdef0123456789abcdef01234567";
+        assert!(utils::git::checkout_worktree(&mirror, &worktree, missing, &remote).is_err());
+        mirror.remote_set_url("origin", "/nonexistent").unwrap();
+        assert!(utils::git::fetch(&mirror, &remote, None).is_err());
+    }
+
+    #[test]
+    fn test_shallow_fetch() {
+        let (origin, _) = init_test_repo("test_shallow_fetch_origin");
+        let first = commit_file(&origin, "file", "first").to_string();
+        let second = commit_file(&origin, "file", "second").to_string();
+        let branch = origin.head().unwrap().shorthand().unwrap().to_owned();
+        origin.branch("other", &origin.find_commit(first.parse().unwrap()).unwrap(), false)
+            .unwrap();
+        origin.config().unwrap().set_bool("uploadpack.allowFilter", true).unwrap();
+
+        let path = TEST_DIR.join("test_shallow_fetch");
+        let worktree = TEST_DIR.join("test_shallow_fetch_worktree");
+        let _ = remove_dir_all(&path);
+        let _ = remove_dir_all(&worktree);
+        let remote = Remote {
+            url: format!("file://{}", origin.workdir().unwrap().display()),
+            credentials: Credentials::Default,
+            options: CloneOptions {
+                depth: Some(1),
+                blobless: true,
+                single_branch: true,
+                lfs: false,
+            },
+            submodules: Submodules::default(),
+        };
+        let mirror = utils::git::init_mirror(&path, &remote).unwrap();
+        let gitref = GitRef::Branch(branch);
+        utils::git::fetch(&mirror, &remote, Some(&gitref)).unwrap();
+        assert!(mirror.is_shallow());
+        assert_eq!(utils::git::resolve_ref(&mirror, &gitref).unwrap(), second);
+        assert!(!utils::git::has_commit(&mirror, &first));
+        assert!(utils::git::resolve_ref(&mirror, &GitRef::Branch("other".into())).is_err());
+
+        let repo = utils::git::checkout_worktree(&mirror, &worktree, &second, &remote).unwrap();
+        utils::git::verify_head(&repo, &second).unwrap();
+        let mut contents = String::new();
+        File::open(worktree.join("file"))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "second");
+
+        utils::git::unshallow(&mirror, &remote).unwrap();
+        let mirror = git2::Repository::open(&path).unwrap();
+        assert!(!mirror.is_shallow());
+        assert!(utils::git::has_commit(&mirror, &first));
+    }
+
     #[test]
-    fn test_checkout() {
-        // TODO Init new repo in /dev/shm, add two commits and verify `checkout` works
+    fn test_fork_merge() {
+        let (origin, _) = init_test_repo("test_fork_merge_origin");
+        let fork_path = TEST_DIR.join("test_fork_merge_fork");
+        let _ = remove_dir_all(&fork_path);
+        let fork = git2::Repository::clone(origin.path().to_str().unwrap(), &fork_path).unwrap();
+        let target = commit_file(&origin, "target", "target").to_string();
+        let source = commit_file(&fork, "source", "source").to_string();
+
+        let path = TEST_DIR.join("test_fork_merge");
+        let _ = remove_dir_all(&path);
+        let remote = Remote {
+            url: origin.workdir().unwrap().to_str().unwrap().to_owned(),
+            credentials: Credentials::Default,
+            options: CloneOptions::default(),
+            submodules: Submodules::default(),
+        };
+        let mirror = utils::git::init_mirror(&path, &remote).unwrap();
+        utils::git::fetch(&mirror, &remote, None).unwrap();
+        let fork_remote = Remote {
+            url: fork_path.to_str().unwrap().to_owned(),
+            ..remote.clone()
+        };
+        utils::git::fetch_fork(&mirror, &fork_remote, "lex/fork", "master").unwrap();
+        let fetched = mirror.refname_to_id("refs/forks/lex/fork/master").unwrap();
+        assert_eq!(fetched.to_string(), source);
+        assert!(mirror.find_reference("refs/remotes/origin/master").is_ok());
+
+        let merge = utils::git::merge_commit(&mirror, &target, &source).unwrap();
+        let merge = mirror.find_commit(git2::Oid::from_str(&merge).unwrap()).unwrap();
+        let parents: Vec<String> = merge.parent_ids().map(|id| id.to_string()).collect();
+        assert_eq!(parents, vec![target.clone(), source.clone()]);
+        assert!(merge.tree().unwrap().get_name("source").is_some());
+        assert!(merge.tree().unwrap().get_name("target").is_some());
+    }
+
+    /// Add `sub` as submodule `name` of `repo` and commit it
+    fn add_submodule(repo: &git2::Repository, sub: &git2::Repository, name: &str) -> String {
+        let workdir = repo.workdir().unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(workdir)
+                .args(["-c", "protocol.file.allow=always", "-c", "user.name=Rupert"])
+                .args(["-c", "user.email=rupert@example.com"])
+                .args(args)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success());
+        };
+        git(&["submodule", "add", sub.workdir().unwrap().to_str().unwrap(), name]);
+        git(&["commit", "-m", name]);
+        repo.head().unwrap().target().unwrap().to_string()
     }
 
-    #[ignore]
     #[test]
-    fn test_init_repo() {
-        // TODO Make sure cloning a repo works
+    fn test_submodule_strategy() {
+        let (inner, _) = init_test_repo("test_submodule_strategy_inner");
+        commit_file(&inner, "inner.txt", "inner");
+        let (middle, _) = init_test_repo("test_submodule_strategy_middle");
+        add_submodule(&middle, &inner, "inner");
+        let (origin, _) = init_test_repo("test_submodule_strategy_origin");
+        let commit = add_submodule(&origin, &middle, "middle");
+
+        let checkout = |strategy, jobs| {
+            let path = TEST_DIR.join("test_submodule_strategy");
+            let worktree = TEST_DIR.join("test_submodule_strategy_worktree");
+            let _ = remove_dir_all(&path);
+            let remote = Remote {
+                url: origin.workdir().unwrap().to_str().unwrap().to_owned(),
+                credentials: Credentials::Default,
+                options: CloneOptions::default(),
+                submodules: Submodules {
+                    strategy,
+                    jobs,
+                    credentials: Vec::new(),
+                },
+            };
+            let mirror = utils::git::init_mirror(&path, &remote).unwrap();
+            utils::git::fetch(&mirror, &remote, None).unwrap();
+            utils::git::checkout_worktree(&mirror, &worktree, &commit, &remote).unwrap();
+            (
+                worktree.join("middle/.git").exists(),
+                worktree.join("middle/inner/inner.txt").exists(),
+            )
+        };
+        assert_eq!(checkout(SubmoduleStrategy::None, 1), (false, false));
+        assert_eq!(checkout(SubmoduleStrategy::TopLevel, 1), (true, false));
+        assert_eq!(checkout(SubmoduleStrategy::Recursive, 4), (true, true));
+    }
+
+    /// Serve the repositories in `TEST_DIR` through `git http-backend`, to those
+    /// authenticating as rupert:secret. Returns the base-url.
+    fn serve_http_backend() -> String {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr());
+        thread::spawn(move || for mut request in server.incoming_requests() {
+            // "rupert:secret" in base64
+            let authorized = request.headers().iter().any(|h| {
+                h.field.as_str().as_str().eq_ignore_ascii_case("Authorization") &&
+                    h.value.as_str() == "Basic cnVwZXJ0OnNlY3JldA=="
+            });
+            if !authorized {
+                let header = "WWW-Authenticate: Basic realm=\"git\"".parse().unwrap();
+                let response = tiny_http::Response::from_data("").with_status_code(401);
+                let _ = request.respond(response.with_header::<tiny_http::Header>(header));
+                continue;
+            }
+            let mut body = Vec::new();
+            request.as_reader().read_to_end(&mut body).unwrap();
+            let (path, query) = match request.url().find('?') {
+                Some(i) => (request.url()[..i].to_owned(), request.url()[i + 1..].to_owned()),
+                None => (request.url().to_owned(), String::new()),
+            };
+            let content_type = request
+                .headers()
+                .iter()
+                .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case("Content-Type"))
+                .map(|h| h.value.as_str().to_owned())
+                .unwrap_or_default();
+            let mut child = Command::new("git")
+                .arg("http-backend")
+                .env("GIT_PROJECT_ROOT", &*TEST_DIR)
+                .env("GIT_HTTP_EXPORT_ALL", "1")
+                .env("REQUEST_METHOD", request.method().as_str())
+                .env("PATH_INFO", path)
+                .env("QUERY_STRING", query)
+                .env("CONTENT_TYPE", content_type)
+                .env("CONTENT_LENGTH", body.len().to_string())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(&body).unwrap();
+            let output = child.wait_with_output().unwrap().stdout;
+            // CGI-headers, with the status given as a header of its own
+            let split = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+            let mut response = tiny_http::Response::from_data(output[split + 4..].to_vec());
+            for line in String::from_utf8_lossy(&output[..split]).lines() {
+                if line.starts_with("Status: ") {
+                    response = response.with_status_code(line[8..11].parse::<u16>().unwrap());
+                } else {
+                    response = response.with_header::<tiny_http::Header>(line.parse().unwrap());
+                }
+            }
+            let _ = request.respond(response);
+        });
+        url
+    }
+
+    #[test]
+    fn test_https_auth() {
+        let (origin, _) = init_test_repo("test_https_auth_origin");
+        let commit = commit_file(&origin, "file", "contents").to_string();
+        let url = format!("{}/test_https_auth_origin", serve_http_backend());
+        let path = TEST_DIR.join("test_https_auth");
+        let remote = |password: &str, depth| {
+            Remote {
+                url: url.clone(),
+                credentials: Credentials::UserPass {
+                    username: "rupert".into(),
+                    password: password.into(),
+                },
+                options: CloneOptions {
+                    depth,
+                    ..Default::default()
+                },
+                submodules: Submodules::default(),
+            }
+        };
+
+        let _ = remove_dir_all(&path);
+        let mirror = utils::git::init_mirror(&path, &remote("secret", None)).unwrap();
+        assert!(utils::git::fetch(&mirror, &remote("wrong", None), None).is_err());
+        utils::git::fetch(&mirror, &remote("secret", None), None).unwrap();
+        assert!(utils::git::has_commit(&mirror, &commit));
+
+        // Through the git CLI, as libgit2 can not fetch shallowly
+        let commit = commit_file(&origin, "file", "changed").to_string();
+        assert!(utils::git::fetch(&mirror, &remote("wrong", Some(1)), None).is_err());
+        utils::git::fetch(&mirror, &remote("secret", Some(1)), None).unwrap();
+        assert!(utils::git::has_commit(&mirror, &commit));
+    }
+
+    #[test]
+    fn test_working_tree_files() {
+        let (repo, _) = init_test_repo("test_working_tree_files");
+        let workdir = repo.workdir().unwrap().to_owned();
+        let write = |name: &str| {
+            File::create(workdir.join(name))
+                .unwrap()
+                .write_all(name.as_bytes())
+                .unwrap()
+        };
+        write("tracked");
+        write("deleted");
+        write("untracked");
+        write("ignored");
+        File::create(workdir.join(".gitignore"))
+            .unwrap()
+            .write_all(b"ignored")
+            .unwrap();
+        {
+            let mut index = repo.index().unwrap();
+            for name in &[".gitignore", "tracked", "deleted"] {
+                index.add_path(name.as_ref()).unwrap();
+            }
+            index.write().unwrap();
+        }
+        ::std::fs::remove_file(workdir.join("deleted")).unwrap();
+
+        let files = utils::git::working_tree_files(&repo, false).unwrap();
+        assert_eq!(files, vec![PathBuf::from(".gitignore"), "tracked".into()]);
+        let files = utils::git::working_tree_files(&repo, true).unwrap();
+        assert_eq!(
+            files,
+            vec![PathBuf::from(".gitignore"), "tracked".into(), "untracked".into()]
+        );
+    }
+
+    #[test]
+    fn test_serialize_updates() {
+        let update = utils::BuildUpdates::StepNewOutput(
+            utils::TextOutput::Stderr("oops".into()),
+        );
+        let json = serde_json::to_string(&update).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"StepNewOutput","data":{"type":"Stderr","data":"oops"}}"#
+        );
+        let update: utils::BuildUpdates = serde_json::from_str(&json).unwrap();
+        match update {
+            utils::BuildUpdates::StepNewOutput(utils::TextOutput::Stderr(ref s)) => {
+                assert_eq!(s, "oops")
+            }
+            _ => panic!("Unexpected {:?}", update),
+        }
+    }
+
+    #[test]
+    fn test_parse_ansi() {
+        use utils::Ansi;
+        assert_eq!(
+            utils::parse_ansi("\u{1b}[1;31merror\u{1b}[0m: \u{1b}[Kdone\u{1b}("),
+            vec![
+                Ansi::Colors("1;31"),
+                Ansi::Text("error"),
+                Ansi::Colors("0"),
+                Ansi::Text(": "),
+                Ansi::Text("done"),
+            ]
+        );
+        assert_eq!(utils::strip_ansi("\u{1b}[33mStarting\u{1b}[0m ünïcode"), "Starting ünïcode");
     }
 
     #[test]
@@ -207,4 +970,31 @@ build_instruction = { steps = [
         utils::load_config(Some(path)).unwrap();
 
     }
+
+    #[test]
+    fn test_load_conf_notify_emails() {
+        let mut path = TEST_DIR.clone();
+        path.push("test_load_conf_notify_emails");
+        let mut file = File::create(path.clone()).unwrap();
+        file.write_all(
+            b"
+[meta]
+build_root = \"/opt/rupert/build_root\"
+
+[[repos]]
+integration = \"bitbucket\"
+owner = \"purew\"
+reponame = \"foobar\"
+api_token = \"biggaboo\"
+notify_emails = [\"team@example.com\"]
+build_instruction = { steps = [
+      {cmd = \"make\"},
+    ]}
+",
+        ).unwrap();
+        let conf = utils::load_config(Some(path)).unwrap();
+        assert!(conf.meta.smtp.is_none());
+        let repo = &conf.repos[&("purew".into(), "foobar".into())];
+        assert_eq!(repo.notify_emails, vec!["team@example.com".to_owned()]);
+    }
 }
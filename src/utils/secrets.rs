@@ -0,0 +1,58 @@
+//! Secrets, such as passphrases and passwords, kept apart from the configuration
+//!
+//! The store is a TOML-file of `name = "value"` pairs, referred to by name from
+//! `rupert-conf.toml` so the configuration itself can be shared.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use toml;
+
+use errors::*;
+
+#[derive(Default)]
+pub struct Secrets {
+    values: HashMap<String, String>,
+}
+
+impl Secrets {
+    /// Read the store at `path`, warning if others than its owner may read it
+    pub fn load(path: &Path) -> Result<Secrets> {
+        let mut file = File::open(path).chain_err(|| {
+            format!("Failed opening secrets-store {:?}", path)
+        })?;
+        let mode = file.metadata()
+            .chain_err(|| format!("Failed reading metadata of {:?}", path))?
+            .permissions()
+            .mode();
+        if mode & 0o077 != 0 {
+            warn!("Secrets-store {:?} is accessible by others than its owner", path);
+        }
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).chain_err(|| {
+            format!("Could not read contents of {:?}", path)
+        })?;
+        let values = toml::from_str(&contents).chain_err(|| {
+            format!("Bad format in secrets-store {:?}", path)
+        })?;
+        Ok(Secrets { values })
+    }
+
+    /// Value of secret `name`
+    pub fn get(&self, name: &str) -> Result<&str> {
+        self.values.get(name).map(|v| v.as_str()).ok_or_else(|| {
+            format!("Secret \"{}\" not found in secrets-store", name).into()
+        })
+    }
+}
+
+/// Only names are shown, so configurations can be logged
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.values.keys()).finish()
+    }
+}